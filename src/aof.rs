@@ -0,0 +1,457 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::commands;
+use crate::helper;
+use crate::memory;
+use crate::rdb;
+use crate::types;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::EverySec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+            AppendFsync::No => "no",
+        }
+    }
+}
+
+/// append only file: an optional rdb preamble followed by every write command as resp
+pub struct Aof {
+    file: Option<File>, // None when appendonly is off
+    pub path: PathBuf,
+    pub fsync: AppendFsync,
+    needs_fsync: bool, // written since the last fsync, used by everysec
+
+    // writes made while a rewrite runs, appended to the new file before the swap
+    rewrite_buffer: Option<Vec<u8>>,
+    pub base_size: u64, // size after the last rewrite (or at startup)
+    pub current_size: u64,
+    pub auto_rewrite_percentage: u64, // 0 disables automatic rewrites
+    pub auto_rewrite_min_size: u64,
+
+    // db of the last write sent to the file and the replicas, None to send a
+    // SELECT before the next one whatever its db
+    pub selected_db: Option<usize>,
+
+    // writes that didn't make it to the file, retried by the next write and the
+    // cron, clients can't write while there are any
+    unwritten: Vec<u8>,
+    last_write_error: Option<String>,
+}
+
+pub type SharedAof = Arc<Mutex<Aof>>;
+
+impl Aof {
+    pub fn new(path: PathBuf, fsync: AppendFsync) -> Self {
+        Self {
+            file: None,
+            path,
+            fsync,
+            needs_fsync: false,
+            rewrite_buffer: None,
+            base_size: 0,
+            current_size: 0,
+            auto_rewrite_percentage: 100,
+            auto_rewrite_min_size: 64 * 1024 * 1024,
+            selected_db: None,
+            unwritten: Vec::new(),
+            last_write_error: None,
+        }
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.rewrite_buffer.is_some()
+    }
+
+    /// bytes of writes buffered while a rewrite runs or until the file can be
+    /// written again
+    pub fn buffered_bytes(&self) -> usize {
+        self.rewrite_buffer.as_ref().map_or(0, Vec::capacity) + self.unwritten.capacity()
+    }
+
+    /// the error to refuse writes with while the file can't be written
+    pub fn misconf_reply(&self) -> Option<Vec<u8>> {
+        self.last_write_error
+            .as_ref()
+            .map(|e| format!("-MISCONF Errors writing to the AOF file: {e}\r\n").into_bytes())
+    }
+
+    pub fn last_write_ok(&self) -> bool {
+        self.last_write_error.is_none()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// opens the file for appending
+    /// a new file starts with an rdb preamble of the current dataset, so keys loaded
+    /// from an rdb are not lost the next time the server starts from the aof
+    pub fn enable(&mut self, dbs: &types::SharedDatabases) -> std::io::Result<()> {
+        let is_new = !self.path.exists();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        if is_new {
+            let snapshot = helper::snapshot_store(dbs);
+            if !snapshot.keys.is_empty() {
+                let data = rdb::serialize(&snapshot.keys, true);
+                let _data_memory = memory::NotCounted::new(data.capacity());
+                file.write_all(&data)?;
+                file.sync_all()?;
+            }
+        }
+
+        self.current_size = file.metadata()?.len();
+        self.base_size = self.current_size;
+        self.file = Some(file);
+        // nothing in the file says which db the next write is for
+        self.selected_db = None;
+        Ok(())
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        if self.file.is_none() {
+            return;
+        }
+
+        // the rewritten file gets it whatever happens to this one
+        if let Some(buffer) = self.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(data);
+        }
+
+        self.unwritten.extend_from_slice(data);
+        self.write_unwritten();
+    }
+
+    /// writes what is pending to the file
+    /// a failed write is cut off again, so the file doesn't end in half a
+    /// command, and the whole of it is tried again later
+    fn write_unwritten(&mut self) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        if self.unwritten.is_empty() {
+            return;
+        }
+
+        let mut written = 0;
+        let result = loop {
+            match file.write(&self.unwritten[written..]) {
+                Ok(0) => break Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+            if written == self.unwritten.len() {
+                break Ok(());
+            }
+        };
+
+        if let Err(e) = result {
+            if self.last_write_error.is_none() {
+                println!("[error] writing to the append only file failed: {e}");
+            }
+            self.last_write_error = Some(e.to_string());
+
+            if written == 0 {
+                return;
+            }
+            if let Err(e) = file.set_len(self.current_size) {
+                // what did get written stays, only the rest is tried again
+                println!("[error] truncating the append only file failed: {e}");
+                self.current_size += written as u64;
+                self.unwritten.drain(..written);
+            }
+            return;
+        }
+
+        self.current_size += written as u64;
+        self.unwritten.clear();
+        if self.last_write_error.take().is_some() {
+            println!("[info] writing to the append only file works again");
+        }
+
+        match self.fsync {
+            AppendFsync::Always => {
+                if let Err(e) = file.sync_data() {
+                    println!("[error] fsync of the append only file failed: {e}");
+                }
+            }
+            AppendFsync::EverySec => self.needs_fsync = true,
+            AppendFsync::No => {}
+        }
+    }
+
+    /// logs an executed write, in the same form it is propagated to replicas
+    pub fn feed(&mut self, data: &[u8]) {
+        self.append(data);
+    }
+}
+
+/// starts rewriting the file in the background
+/// the new file is an rdb preamble of the current keyspace, writes that happen
+/// meanwhile are buffered and appended to it right before it replaces the old one
+pub fn start_rewrite(aof: &SharedAof, dbs: &types::SharedDatabases) -> Result<(), &'static str> {
+    let mut guard = aof.lock().unwrap();
+    if guard.is_rewriting() {
+        return Err("Background append only file rewriting already in progress");
+    }
+
+    // writers hold the aof lock, so the snapshot and the buffer start at the same point
+    let snapshot = helper::snapshot_store(dbs);
+    guard.rewrite_buffer = Some(Vec::new());
+    guard.selected_db = None;
+    let path = guard.path.clone();
+    drop(guard);
+
+    let aof = Arc::clone(aof);
+    std::thread::spawn(move || {
+        let tmp_path =
+            path.with_file_name(format!("temp-rewriteaof-bg-{}.aof", std::process::id()));
+
+        let result = write_rewrite_base(&tmp_path, &snapshot.keys)
+            .and_then(|file| finish_rewrite(&aof, file, &tmp_path));
+
+        match result {
+            Ok(()) => println!("[info] background aof rewrite terminated with success"),
+            Err(e) => {
+                aof.lock().unwrap().rewrite_buffer = None;
+                let _ = std::fs::remove_file(&tmp_path);
+                println!("[error] background aof rewrite failed: {e}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn write_rewrite_base(tmp_path: &Path, keys: &[rdb::RdbKey]) -> std::io::Result<File> {
+    let mut file = File::create(tmp_path)?;
+    let data = rdb::serialize(keys, true);
+    let _data_memory = memory::NotCounted::new(data.capacity());
+    file.write_all(&data)?;
+    file.sync_data()?;
+    Ok(file)
+}
+
+/// appends the buffered writes and swaps the new file in, all under the lock
+/// so no write lands in the old file after its contents were copied
+fn finish_rewrite(aof: &SharedAof, mut file: File, tmp_path: &Path) -> std::io::Result<()> {
+    let mut guard = aof.lock().unwrap();
+
+    let buffer = guard.rewrite_buffer.take().unwrap_or_default();
+    file.write_all(&buffer)?;
+    file.sync_data()?;
+    std::fs::rename(tmp_path, &guard.path)?;
+
+    let size = file.metadata()?.len();
+    guard.base_size = size;
+    guard.current_size = size;
+    // the writes the old file missed are in the buffer as well
+    guard.unwritten.clear();
+    guard.last_write_error = None;
+
+    if guard.is_enabled() {
+        guard.file = Some(OpenOptions::new().append(true).open(&guard.path)?);
+        guard.needs_fsync = false;
+    }
+
+    Ok(())
+}
+
+/// runs once a second: flushes the file to disk when appendfsync is everysec and
+/// starts a rewrite once the file grew past the auto-aof-rewrite thresholds
+/// the fsync runs on a cloned handle, so writers are not blocked while it happens
+pub fn run_aof_cron(aof: SharedAof, dbs: types::SharedDatabases) {
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+
+        let (file, should_rewrite) = {
+            let mut aof = aof.lock().unwrap();
+            // writes that failed are tried again
+            aof.write_unwritten();
+
+            let file = match aof.file.as_ref() {
+                Some(file) if aof.fsync == AppendFsync::EverySec && aof.needs_fsync => {
+                    Some(file.try_clone())
+                }
+                _ => None,
+            };
+            if file.is_some() {
+                aof.needs_fsync = false;
+            }
+
+            let growth =
+                aof.current_size.saturating_sub(aof.base_size) * 100 / aof.base_size.max(1);
+            let should_rewrite = aof.is_enabled()
+                && !aof.is_rewriting()
+                && aof.auto_rewrite_percentage > 0
+                && aof.current_size >= aof.auto_rewrite_min_size
+                && growth >= aof.auto_rewrite_percentage;
+
+            (file, should_rewrite)
+        };
+
+        if let Some(Err(e)) = file.map(|file| file.and_then(|file| file.sync_data())) {
+            println!("[error] fsync of the append only file failed: {e}");
+        }
+
+        if should_rewrite {
+            println!("[info] starting automatic rewriting of the append only file");
+            let _ = start_rewrite(&aof, &dbs);
+        }
+    }
+}
+
+/// rebuilds the keyspace from the file at startup
+/// a record cut off by a crash at the end of the file (including an unfinished
+/// MULTI) is truncated away, anything unparsable before that is an error
+pub fn load(
+    path: &Path,
+    dbs: &types::SharedDatabases,
+    users: &types::SharedUsers,
+) -> Result<usize, String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+
+    let mut consumed = 0;
+    let mut loaded_keys = 0;
+    if data.starts_with(b"REDIS") {
+        let contents = rdb::parse(&data).map_err(|e| format!("bad rdb preamble: {e}"))?;
+        consumed = contents.size;
+        loaded_keys = helper::insert_rdb_keys(contents.keys, dbs);
+    }
+
+    let replay_user = Arc::new(Mutex::new(types::UserInfo::default()));
+    let mut commands = 0;
+    let mut multi_start: Option<usize> = None;
+    let mut queued: Vec<Vec<Vec<u8>>> = Vec::new();
+    // the db the following writes apply to, changed by SELECT records
+    let mut db = 0;
+
+    loop {
+        // records are always multibulk, inline commands never end up in the file
+        if data.get(consumed).is_some_and(|b| *b != b'*') {
+            return Err(format!("bad file format at offset {consumed}"));
+        }
+
+        let (elems, len) = match helper::parse_frame(&data[consumed..]) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => return Err(format!("bad file format at offset {consumed}: {e}")),
+        };
+
+        if !elems.is_empty() {
+            match helper::cmd_name(&elems[0]).as_str() {
+                "multi" => {
+                    multi_start = Some(consumed);
+                    queued.clear();
+                }
+                "exec" => {
+                    for command in queued.drain(..) {
+                        if helper::cmd_name(&command[0]) == "select" {
+                            commands::handle_select(&command, dbs, &mut db);
+                        } else {
+                            helper::replay_command(command, dbs, db, users, &replay_user);
+                            commands += 1;
+                        }
+                    }
+                    multi_start = None;
+                }
+                _ if multi_start.is_some() => queued.push(elems),
+                "select" => {
+                    commands::handle_select(&elems, dbs, &mut db);
+                }
+                _ => {
+                    helper::replay_command(elems, dbs, db, users, &replay_user);
+                    commands += 1;
+                }
+            }
+        }
+
+        consumed += len;
+    }
+
+    let valid_len = multi_start.unwrap_or(consumed);
+    if valid_len < data.len() {
+        println!(
+            "[info] truncating {} bytes of incomplete data at the end of {}",
+            data.len() - valid_len,
+            path.display()
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(valid_len as u64))
+            .map_err(|e| format!("truncating failed: {e}"))?;
+    }
+
+    println!(
+        "[info] loaded {loaded_keys} keys and replayed {commands} commands from {}",
+        path.display()
+    );
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_writes_are_refused_and_retried() {
+        let dir = std::env::temp_dir().join(format!("aof-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("appendonly.aof");
+        let _ = std::fs::remove_file(&path);
+
+        // every write to /dev/full fails with no space left
+        let mut aof = Aof::new(path.clone(), AppendFsync::No);
+        aof.file = Some(OpenOptions::new().append(true).open("/dev/full").unwrap());
+        aof.feed(b"*1\r\n$4\r\nPING\r\n");
+        assert!(!aof.last_write_ok());
+        assert!(aof.misconf_reply().unwrap().starts_with(b"-MISCONF "));
+        assert_eq!(aof.current_size, 0);
+
+        // once the file can be written nothing is lost or written twice
+        aof.file = Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap(),
+        );
+        aof.feed(b"*1\r\n$4\r\nINCR\r\n");
+        assert!(aof.last_write_ok());
+        assert_eq!(aof.misconf_reply(), None);
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            b"*1\r\n$4\r\nPING\r\n*1\r\n$4\r\nINCR\r\n"
+        );
+        assert_eq!(aof.current_size, 28);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,277 @@
+use crate::helper;
+
+// command flags, named after the ones redis reports in COMMAND INFO
+
+/// changes the dataset: refused on replicas, counted by the save points and,
+/// when it succeeds, propagated to the aof and the replicas
+pub const WRITE: u32 = 1 << 0;
+/// only reads the dataset
+pub const READONLY: u32 = 1 << 1;
+/// server and replication administration
+pub const ADMIN: u32 = 1 << 2;
+/// may block the client until data arrives
+pub const BLOCKING: u32 = 1 << 3;
+/// publish/subscribe, doesn't touch the dataset
+pub const PUBSUB: u32 = 1 << 4;
+/// may use more memory, refused with -OOM when over maxmemory
+pub const DENYOOM: u32 = 1 << 5;
+
+pub struct CommandSpec {
+    pub name: &'static str,
+    // number of arguments including the command name like redis counts them:
+    // exactly that many when positive, at least minus that many when negative
+    pub arity: i32,
+    pub flags: u32,
+    pub subcommands: &'static [CommandSpec], // when the flags depend on the subcommand
+}
+
+const fn command(name: &'static str, arity: i32, flags: u32) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        subcommands: &[],
+    }
+}
+
+pub static COMMAND_TABLE: &[CommandSpec] = &[
+    command("ping", -1, 0),
+    command("echo", 2, 0),
+    command("info", -1, 0),
+    command("auth", -2, 0),
+    CommandSpec {
+        name: "acl",
+        arity: -2,
+        flags: ADMIN,
+        subcommands: &[
+            command("whoami", 2, 0),
+            command("getuser", 3, ADMIN),
+            command("setuser", -3, WRITE | ADMIN),
+        ],
+    },
+    // transactions
+    command("multi", 1, 0),
+    command("exec", 1, 0),
+    command("discard", 1, 0),
+    // strings
+    command("set", -3, WRITE | DENYOOM),
+    command("get", 2, READONLY),
+    command("mget", -2, READONLY),
+    command("mset", -3, WRITE | DENYOOM),
+    command("msetnx", -3, WRITE | DENYOOM),
+    command("setnx", 3, WRITE | DENYOOM),
+    command("setex", 4, WRITE | DENYOOM),
+    command("psetex", 4, WRITE | DENYOOM),
+    command("getset", 3, WRITE | DENYOOM),
+    command("getdel", 2, WRITE),
+    command("getex", -2, WRITE),
+    command("append", 3, WRITE | DENYOOM),
+    command("strlen", 2, READONLY),
+    command("getrange", 4, READONLY),
+    command("setrange", 4, WRITE | DENYOOM),
+    command("lcs", -3, READONLY),
+    // bitmaps
+    command("setbit", 4, WRITE | DENYOOM),
+    command("getbit", 3, READONLY),
+    command("bitcount", -2, READONLY),
+    command("bitpos", -3, READONLY),
+    command("bitop", -4, WRITE | DENYOOM),
+    command("incr", 2, WRITE | DENYOOM),
+    command("decr", 2, WRITE | DENYOOM),
+    command("incrby", 3, WRITE | DENYOOM),
+    command("decrby", 3, WRITE | DENYOOM),
+    command("incrbyfloat", 3, WRITE | DENYOOM),
+    // keyspace
+    command("type", 2, READONLY),
+    command("keys", 2, READONLY),
+    command("scan", -2, READONLY),
+    command("hscan", -3, READONLY),
+    command("sscan", -3, READONLY),
+    command("zscan", -3, READONLY),
+    command("exists", -2, READONLY),
+    command("randomkey", 1, READONLY),
+    command("del", -2, WRITE),
+    command("unlink", -2, WRITE),
+    command("rename", 3, WRITE),
+    command("renamenx", 3, WRITE),
+    command("copy", -3, WRITE | DENYOOM),
+    command("expire", -3, WRITE),
+    command("pexpire", -3, WRITE),
+    command("expireat", -3, WRITE),
+    command("pexpireat", -3, WRITE),
+    command("persist", 2, WRITE),
+    command("ttl", 2, READONLY),
+    command("pttl", 2, READONLY),
+    command("expiretime", 2, READONLY),
+    command("pexpiretime", 2, READONLY),
+    // databases
+    command("select", 2, 0),
+    command("dbsize", 1, READONLY),
+    command("move", 3, WRITE),
+    command("swapdb", 3, WRITE),
+    command("flushdb", -1, WRITE),
+    command("flushall", -1, WRITE),
+    // lists
+    command("rpush", -3, WRITE | DENYOOM),
+    command("lpush", -3, WRITE | DENYOOM),
+    command("lrange", 4, READONLY),
+    command("llen", 2, READONLY),
+    command("lpop", -2, WRITE),
+    command("blpop", -3, WRITE | BLOCKING),
+    // streams
+    command("xadd", -5, WRITE | DENYOOM),
+    command("xrange", -4, READONLY),
+    command("xread", -4, READONLY | BLOCKING),
+    // sorted sets
+    command("zadd", -4, WRITE | DENYOOM),
+    command("zrank", -3, READONLY),
+    command("zrange", -4, READONLY),
+    command("zcard", 2, READONLY),
+    command("zscore", 3, READONLY),
+    command("zrem", -3, WRITE),
+    // geospatial
+    command("geoadd", -5, WRITE | DENYOOM),
+    command("geopos", -2, READONLY),
+    command("geodist", -4, READONLY),
+    command("geosearch", -7, READONLY),
+    // pub/sub
+    command("subscribe", -2, PUBSUB),
+    command("unsubscribe", -1, PUBSUB),
+    command("publish", 3, PUBSUB),
+    // persistence
+    command("save", 1, ADMIN),
+    command("bgsave", -1, ADMIN),
+    command("lastsave", 1, 0),
+    command("bgrewriteaof", 1, ADMIN),
+    command("config", -2, ADMIN),
+    // replication
+    command("replconf", -1, ADMIN),
+    command("psync", -3, ADMIN),
+    command("replicaof", 3, ADMIN),
+    command("slaveof", 3, ADMIN),
+    command("wait", 3, 0),
+];
+
+/// finds the entry for a command, or for its subcommand when it has them
+pub fn lookup(elems: &[Vec<u8>]) -> Option<&'static CommandSpec> {
+    let name = helper::cmd_name(elems.first()?);
+    let spec = COMMAND_TABLE.iter().find(|spec| spec.name == name)?;
+
+    if spec.subcommands.is_empty() {
+        return Some(spec);
+    }
+
+    let subcommand = helper::cmd_name(elems.get(1)?);
+    spec.subcommands.iter().find(|sub| sub.name == subcommand)
+}
+
+fn arity_matches(arity: i32, len: usize) -> bool {
+    if arity >= 0 {
+        len == arity as usize
+    } else {
+        len >= arity.unsigned_abs() as usize
+    }
+}
+
+/// checks the number of arguments of a known command, and of its subcommand if
+/// it has them, before anything runs it
+/// returns the error reply when it's wrong
+pub fn check_arity(elems: &[Vec<u8>]) -> Result<(), Vec<u8>> {
+    let Some(first) = elems.first() else {
+        return Ok(());
+    };
+    let name = helper::cmd_name(first);
+    let Some(spec) = COMMAND_TABLE.iter().find(|spec| spec.name == name) else {
+        return Ok(());
+    };
+
+    if !arity_matches(spec.arity, elems.len()) {
+        return Err(
+            format!("-ERR wrong number of arguments for '{name}' command\r\n").into_bytes(),
+        );
+    }
+
+    let Some(sub) = elems
+        .get(1)
+        .map(|sub| helper::cmd_name(sub))
+        .and_then(|sub| spec.subcommands.iter().find(|spec| spec.name == sub))
+    else {
+        return Ok(());
+    };
+    if !arity_matches(sub.arity, elems.len()) {
+        return Err(format!(
+            "-ERR wrong number of arguments for '{name}|{}' command\r\n",
+            sub.name
+        )
+        .into_bytes());
+    }
+
+    Ok(())
+}
+
+pub fn has_flag(elems: &[Vec<u8>], flag: u32) -> bool {
+    lookup(elems).is_some_and(|spec| spec.flags & flag != 0)
+}
+
+pub fn is_write(elems: &[Vec<u8>]) -> bool {
+    has_flag(elems, WRITE)
+}
+
+/// name a command is reported under in INFO commandstats, `acl|setuser` for
+/// subcommands, None for commands the server doesn't know
+pub fn stat_name(elems: &[Vec<u8>]) -> Option<String> {
+    let spec = lookup(elems)?;
+    let name = helper::cmd_name(&elems[0]);
+
+    if spec.name == name {
+        Some(name)
+    } else {
+        Some(format!("{name}|{}", spec.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<Vec<u8>> {
+        line.split(' ').map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn every_command_has_an_arity() {
+        for spec in COMMAND_TABLE {
+            assert_ne!(spec.arity, 0, "{}", spec.name);
+            for sub in spec.subcommands {
+                assert!(sub.arity.unsigned_abs() >= 2, "{}|{}", spec.name, sub.name);
+            }
+        }
+    }
+
+    #[test]
+    fn check_arity_counts_the_arguments() {
+        assert!(check_arity(&args("get foo")).is_ok());
+        assert_eq!(
+            check_arity(&args("GET")),
+            Err(b"-ERR wrong number of arguments for 'get' command\r\n".to_vec())
+        );
+        assert!(check_arity(&args("get foo bar")).is_err());
+
+        // a minimum
+        assert!(check_arity(&args("del")).is_err());
+        assert!(check_arity(&args("del a b c")).is_ok());
+        assert!(check_arity(&args("randomkey x")).is_err());
+
+        // subcommands have their own
+        assert!(check_arity(&args("acl")).is_err());
+        assert!(check_arity(&args("acl whoami")).is_ok());
+        assert_eq!(
+            check_arity(&args("acl getuser")),
+            Err(b"-ERR wrong number of arguments for 'acl|getuser' command\r\n".to_vec())
+        );
+
+        // unknown commands are left to the dispatcher
+        assert!(check_arity(&args("nosuchcommand")).is_ok());
+        assert!(check_arity(&[]).is_ok());
+    }
+}
@@ -1,774 +1,2723 @@
 use ordered_float::OrderedFloat;
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use std::fs;
-use std::io::{Read, Write};
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
-use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::aof;
 use crate::helper;
+use crate::memory;
 use crate::types::{self};
 
 // handle echo cmd
-pub fn handle_echo(elems: Vec<String>) -> String {
+pub fn handle_echo(elems: Vec<Vec<u8>>) -> Vec<u8> {
     let value = &elems[1];
 
-    return format!("${}\r\n{}\r\n", value.len(), value);
+    return helper::bulk_string(value);
 }
 
 // handle set cmd
-pub fn handle_set(elems: Vec<String>, store: &types::SharedStore) -> String {
+pub fn handle_set(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 3 {
+        return b"-ERR wrong number of arguments for 'set' command\r\n".to_vec();
+    }
+
+    let set_args = match helper::parse_set_args(&elems[3..]) {
+        Ok(set_args) => set_args,
+        Err(e) => return e,
+    };
+
     let (s, _) = &**store;
     let mut map = s.lock().unwrap();
 
     let key = &elems[1];
     let value = &elems[2];
 
-    let expires_at = elems
-        .get(3)
-        .and_then(|time_setter_args| helper::handle_expiry(time_setter_args, elems.clone()));
+    let existing = helper::get_live_entry(&mut map, key);
+    let exists = existing.is_some();
+    let old_ttl = existing.as_ref().and_then(|entry| entry.expires_at);
+
+    // GET only works on strings, and nothing is set when it fails
+    let reply = if set_args.get {
+        match existing.map(|entry| &entry.value) {
+            Some(types::StoredValue::String(old)) => helper::bulk_string(old),
+            Some(_) => return helper::WRONGTYPE.to_vec(),
+            None => b"$-1\r\n".to_vec(),
+        }
+    } else {
+        b"+OK\r\n".to_vec()
+    };
+
+    if (set_args.nx && exists) || (set_args.xx && !exists) {
+        return if set_args.get {
+            reply
+        } else {
+            b"$-1\r\n".to_vec()
+        };
+    }
 
-    let value_entry = types::ValueEntry {
-        value: types::StoredValue::String(value.clone()),
-        expires_at,
+    let expires_at = if set_args.keep_ttl {
+        old_ttl
+    } else {
+        set_args.expires_at
     };
 
-    map.insert(key.clone(), value_entry);
+    // a time already in the past deletes the key, a replay keeps it for the
+    // commands after it like it does for PEXPIREAT
+    if let Some(target) = set_args.expires_at {
+        helper::record_stored_expiry(target);
+    }
+    if expires_at.is_some_and(|target| target <= helper::now_unix_ms()) && !helper::is_replaying() {
+        map.remove(key);
+    } else {
+        let value_entry =
+            types::ValueEntry::with_expiry(types::StoredValue::String(value.clone()), expires_at);
+        map.insert(key.clone(), value_entry);
+    }
 
-    return "+OK\r\n".to_string();
+    reply
 }
 
-// handle get cmd but without rdb file
-pub fn handle_get_old(elems: &Vec<String>, store: &types::SharedStore) -> String {
+// handle get cmd
+pub fn handle_get(elems: &Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
     let (s, _) = &**store;
     let mut map = s.lock().unwrap();
     let key = &elems[1];
 
-    if let Some(val) = map.get(key) {
-        if let Some(expire_time) = val.expires_at {
-            if Instant::now() >= expire_time {
-                map.remove(key);
-                return "$-1\r\n".to_string();
-            }
-        }
-
-        match &val.value {
-            types::StoredValue::String(string_var) => {
-                return format!("${}\r\n{}\r\n", string_var.as_bytes().len(), string_var);
-            }
-            _ => {
-                return "-ERR stored_value is not a string\r\n".to_string();
-            }
-        }
-    } else {
-        return "$-1\r\n".to_string();
+    match helper::get_live_entry(&mut map, key).map(|val| &val.value) {
+        Some(types::StoredValue::String(bytes)) => helper::bulk_string(bytes),
+        Some(_) => helper::WRONGTYPE.to_vec(),
+        None => b"$-1\r\n".to_vec(),
     }
 }
 
-// handle get cmd with/without rdb file
-pub fn handle_get(
-    dir_clone: &Arc<Mutex<Option<String>>>,
-    dbfilename_clone: &Arc<Mutex<Option<String>>>,
-    elems: &Vec<String>,
-    store: &types::SharedStore,
-) -> String {
-    let dir = &*dir_clone.lock().unwrap();
-    let dbfilename = &*dbfilename_clone.lock().unwrap();
-    let key = elems[1].clone();
-
-    // early return if dir & dbfielname doesn't exists
-    let (Some(dir), Some(dbfilename)) = (dir, dbfilename) else {
-        return handle_get_old(elems, store);
-    };
-
-    let path = format!("{}/{}", dir, dbfilename);
-    let data = fs::read(path).unwrap();
-
-    let (kv, kv_fc, kv_fd) = helper::parse_db(&data);
-
-    // kv pair
-    let resp = if let Some(kv_pair) = kv.iter().find(|p| p[0] == key) {
-        let key = &kv_pair[1];
-        let resp = format!("${}\r\n{}\r\n", key.len(), key);
-        resp
-        // kv pair with milliseconds timeout
-    } else if let Some(kv_pair_fc) = kv_fc.iter().find(|p| p[0] == key) {
-        let expiry = kv_pair_fc[2].parse::<u64>().unwrap();
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-
-        if now as u64 > expiry {
-            "$-1\r\n".to_string()
-        } else {
-            format!("${}\r\n{}\r\n", kv_pair_fc[1].len(), kv_pair_fc[1])
-        }
-    // kv pair with seconds timeout
-    } else if let Some(kv_pair_fd) = kv_fd.iter().find(|p| p[0] == key) {
-        let expiry = kv_pair_fd[2].parse::<u32>().unwrap();
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-
-        if now as u32 > expiry {
-            "$-1\r\n".to_string()
-        } else {
-            format!("${}\r\n{}\r\n", kv_pair_fd[1].len(), kv_pair_fd[1])
+/// largest string SETRANGE and APPEND can make, the proto-max-bulk-len default
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+// handle mget cmd
+pub fn handle_mget(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 2 {
+        return b"-ERR wrong number of arguments for 'mget' command\r\n".to_vec();
+    }
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    // keys holding another type are nil, like missing ones
+    let mut resp = format!("*{}\r\n", elems.len() - 1).into_bytes();
+    for key in &elems[1..] {
+        match helper::get_live_entry(&mut map, key).map(|entry| &entry.value) {
+            Some(types::StoredValue::String(value)) => resp.extend(helper::bulk_string(value)),
+            _ => resp.extend_from_slice(b"$-1\r\n"),
         }
-    } else {
-        "-ERR key doesn't exists in RDB file".to_string()
-    };
+    }
 
     resp
 }
 
-// handle rpush
-pub fn handle_rpush(elems: Vec<String>, main_list_store: &types::SharedMainList) -> String {
-    let (main_list_guard, cvar) = &**main_list_store;
+// handle mset and msetnx cmds
+pub fn handle_mset(elems: Vec<Vec<u8>>, store: &types::SharedStore, nx: bool) -> Vec<u8> {
+    if elems.len() < 3 || elems.len().is_multiple_of(2) {
+        let cmd = if nx { "msetnx" } else { "mset" };
+        return format!("-ERR wrong number of arguments for '{}' command\r\n", cmd).into_bytes();
+    }
 
-    let mut main_list = main_list_guard.lock().unwrap();
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    let list_name = &elems[1];
-    let list_elements = &elems[2..];
+    // msetnx sets nothing at all if any of the keys exists
+    if nx
+        && elems[1..]
+            .chunks(2)
+            .any(|pair| helper::get_live_entry(&mut map, &pair[0]).is_some())
+    {
+        return b":0\r\n".to_vec();
+    }
+
+    for pair in elems[1..].chunks(2) {
+        map.insert(
+            pair[0].clone(),
+            types::ValueEntry::new(types::StoredValue::String(pair[1].clone())),
+        );
+    }
 
-    let s = main_list.iter_mut().find(|l| &l.name == list_name);
+    if nx {
+        b":1\r\n".to_vec()
+    } else {
+        b"+OK\r\n".to_vec()
+    }
+}
 
-    let list = match s {
-        Some(list) => list,
-        None => {
-            main_list.push(types::List::new(&list_name));
-            main_list.last_mut().unwrap()
-        }
-    };
+// handle setnx cmd
+pub fn handle_setnx(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 3 {
+        return b"-ERR wrong number of arguments for 'setnx' command\r\n".to_vec();
+    }
 
-    list.vec.extend(list_elements.iter().cloned());
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    cvar.notify_one();
-    return format!(":{}\r\n", list.vec.len());
+    if helper::get_live_entry(&mut map, &elems[1]).is_some() {
+        return b":0\r\n".to_vec();
+    }
+
+    map.insert(
+        elems[1].clone(),
+        types::ValueEntry::new(types::StoredValue::String(elems[2].clone())),
+    );
+    b":1\r\n".to_vec()
 }
 
-// handle lpush
-pub fn handle_lpush(elems: Vec<String>, main_list_store: &types::SharedMainList) -> String {
-    let (main_list_guard, _) = &**main_list_store;
+// handle setex and psetex cmds
+pub fn handle_setex(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let cmd = helper::cmd_name(&elems[0]);
+    if elems.len() != 4 {
+        return format!("-ERR wrong number of arguments for '{}' command\r\n", cmd).into_bytes();
+    }
 
-    let mut main_list = main_list_guard.lock().unwrap();
+    let option = if cmd == "setex" { "ex" } else { "px" };
+    let expires_at = match helper::option_expire_target_ms(option, &elems[2], &cmd) {
+        Ok(expires_at) => expires_at,
+        Err(e) => return e,
+    };
 
-    let list_name = &elems[1];
-    let list_elements = &elems[2..];
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    let s = main_list.iter_mut().find(|l| &l.name == list_name);
+    map.insert(
+        elems[1].clone(),
+        types::ValueEntry::with_expiry(
+            types::StoredValue::String(elems[3].clone()),
+            Some(expires_at),
+        ),
+    );
+    helper::record_stored_expiry(expires_at);
+    b"+OK\r\n".to_vec()
+}
 
-    let list = match s {
-        Some(list) => list,
-        None => {
-            main_list.push(types::List::new(&list_name));
-            main_list.last_mut().unwrap()
-        }
+// handle getset cmd
+pub fn handle_getset(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 3 {
+        return b"-ERR wrong number of arguments for 'getset' command\r\n".to_vec();
+    }
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let reply = match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(old)) => helper::bulk_string(old),
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => b"$-1\r\n".to_vec(),
     };
 
-    list.vec.splice(0..0, list_elements.iter().rev().cloned());
+    // the ttl is dropped like a plain SET does
+    map.insert(
+        elems[1].clone(),
+        types::ValueEntry::new(types::StoredValue::String(elems[2].clone())),
+    );
+    reply
+}
+
+// handle getdel cmd
+pub fn handle_getdel(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 2 {
+        return b"-ERR wrong number of arguments for 'getdel' command\r\n".to_vec();
+    }
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let reply = match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(value)) => helper::bulk_string(value),
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => return b"$-1\r\n".to_vec(),
+    };
 
-    return format!(":{}\r\n", list.vec.len());
+    map.remove(&elems[1]);
+    reply
 }
 
-// handle lrange
-pub fn handle_lrange(elems: Vec<String>, main_list_store: &types::SharedMainList) -> String {
-    let (guard, _) = &**main_list_store;
-    let main_list = guard.lock().unwrap();
+// handle getex cmd
+pub fn handle_getex(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 2 {
+        return b"-ERR wrong number of arguments for 'getex' command\r\n".to_vec();
+    }
 
-    let list = match main_list.iter().find(|l| l.name == elems[1]) {
-        Some(l) => l,
-        None => {
-            return "*0\r\n".to_string();
-        }
+    let ttl = match helper::parse_getex_ttl(&elems[2..]) {
+        Ok(ttl) => ttl,
+        Err(e) => return e,
     };
 
-    let mut start = elems[2].parse::<i32>().unwrap();
-    let mut end = elems[3].parse::<i32>().unwrap();
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    let list_len = list.vec.len() as i32;
+    let Some(entry) = helper::get_live_entry(&mut map, &elems[1]) else {
+        return b"$-1\r\n".to_vec();
+    };
+    let types::StoredValue::String(value) = &entry.value else {
+        return helper::WRONGTYPE.to_vec();
+    };
+    let reply = helper::bulk_string(value);
 
-    if start < 0 {
-        if start < -1 * list_len {
-            start = 0;
-        } else {
-            start = list_len + -1 * ((-1 * start) % list_len);
+    match ttl {
+        types::GetExTtl::Keep => {}
+        types::GetExTtl::Persist => {
+            map.set_expiry(&elems[1], None);
+        }
+        // a time already in the past deletes the key, except while replaying
+        types::GetExTtl::ExpireAt(target)
+            if target <= helper::now_unix_ms() && !helper::is_replaying() =>
+        {
+            helper::record_stored_expiry(target);
+            map.remove(&elems[1]);
+        }
+        types::GetExTtl::ExpireAt(target) => {
+            helper::record_stored_expiry(target);
+            map.set_expiry(&elems[1], Some(target));
         }
     }
 
-    if end < 0 {
-        if end < -1 * list_len {
-            end = 0;
-        } else {
-            end = list_len + -1 * ((-1 * end) % list_len);
-        }
+    reply
+}
+
+// handle append cmd
+pub fn handle_append(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 3 {
+        return b"-ERR wrong number of arguments for 'append' command\r\n".to_vec();
     }
 
-    println!("[info] starting index {}, ending index  {}", start, end);
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    if start >= list_len || start > end {
-        "*0\r\n".to_string()
-    } else {
-        if end >= list_len {
-            end = list_len - 1;
+    let len = match helper::get_live_entry(&mut map, &elems[1]) {
+        // the ttl is kept
+        Some(entry) => {
+            let types::StoredValue::String(value) = &mut entry.value else {
+                return helper::WRONGTYPE.to_vec();
+            };
+            if value.len() + elems[2].len() > MAX_STRING_LEN {
+                return b"-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n"
+                    .to_vec();
+            }
+            value.extend_from_slice(&elems[2]);
+            value.len()
+        }
+        None => {
+            map.insert(
+                elems[1].clone(),
+                types::ValueEntry::new(types::StoredValue::String(elems[2].clone())),
+            );
+            elems[2].len()
         }
+    };
 
-        let slice = &list.vec[start as usize..(end + 1) as usize];
+    format!(":{}\r\n", len).into_bytes()
+}
 
-        let mut resp = format!("*{}\r\n", slice.len());
+// handle strlen cmd
+pub fn handle_strlen(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 2 {
+        return b"-ERR wrong number of arguments for 'strlen' command\r\n".to_vec();
+    }
 
-        for item in slice {
-            resp.push_str(&format!("${}\r\n{}\r\n", item.len(), item));
-        }
-        resp
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(value)) => format!(":{}\r\n", value.len()).into_bytes(),
+        Some(_) => helper::WRONGTYPE.to_vec(),
+        None => b":0\r\n".to_vec(),
     }
 }
 
-// handle llen
-pub fn handle_llen(elems: Vec<String>, main_list_store: &types::SharedMainList) -> String {
-    let (guard, _) = &**main_list_store;
-    let mut main_list = guard.lock().unwrap();
+// handle getrange cmd
+pub fn handle_getrange(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 4 {
+        return b"-ERR wrong number of arguments for 'getrange' command\r\n".to_vec();
+    }
 
-    let list = match main_list.iter_mut().find(|l| l.name == elems[1]) {
-        Some(l) => l,
-        None => {
-            return ":0\r\n".to_string();
-        }
+    let (Some(start), Some(end)) = (
+        helper::parse_arg::<i64>(&elems[2]),
+        helper::parse_arg::<i64>(&elems[3]),
+    ) else {
+        return b"-ERR value is not an integer or out of range\r\n".to_vec();
+    };
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let value = match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(value)) => value,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => return b"$0\r\n\r\n".to_vec(),
     };
 
-    return format!(":{}\r\n", list.vec.len());
+    match clamp_range(start, end, value.len() as i64) {
+        Some((start, end)) => helper::bulk_string(&value[start..=end]),
+        None => b"$0\r\n\r\n".to_vec(),
+    }
 }
 
-// handle lpop
-pub fn handle_lpop(elems: Vec<String>, main_list_store: &types::SharedMainList) -> String {
-    let (main_list_guard, _) = &**main_list_store;
-    let mut main_list = main_list_guard.lock().unwrap();
+// handle setrange cmd
+pub fn handle_setrange(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 4 {
+        return b"-ERR wrong number of arguments for 'setrange' command\r\n".to_vec();
+    }
 
-    let list = match main_list.iter_mut().find(|l| l.name == elems[1]) {
-        Some(l) => l,
-        None => {
-            return "$-1\r\n".to_string();
-        }
+    let offset = match helper::parse_arg::<i64>(&elems[2]) {
+        Some(offset) if offset >= 0 => offset as usize,
+        Some(_) => return b"-ERR offset is out of range\r\n".to_vec(),
+        None => return b"-ERR value is not an integer or out of range\r\n".to_vec(),
     };
+    let patch = &elems[3];
 
-    let count = elems
-        .get(2)
-        .and_then(|v| v.parse::<usize>().ok())
-        .unwrap_or(1)
-        .min(list.vec.len());
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    if count == 1 {}
+    let current_len = match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(value)) => Some(value.len()),
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => None,
+    };
 
-    let resp = if count == 1 {
-        let element = list.vec.remove(0);
-        format!("${}\r\n{}\r\n", element.len(), element)
-    } else {
-        let mut out = format!("*{}\r\n", count);
+    // an empty patch changes nothing and doesn't create the key
+    if patch.is_empty() {
+        return format!(":{}\r\n", current_len.unwrap_or(0)).into_bytes();
+    }
+    if offset + patch.len() > MAX_STRING_LEN {
+        return b"-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n".to_vec();
+    }
 
-        for _ in 0..count {
-            let element = list.vec.remove(0);
-            out.push_str(&format!("${}\r\n{}\r\n", element.len(), element));
-        }
-        out
+    if !map.contains_key(&elems[1]) {
+        map.insert(
+            elems[1].clone(),
+            types::ValueEntry::new(types::StoredValue::String(Vec::new())),
+        );
+    }
+    let entry = map.get_mut(&elems[1]).unwrap();
+    let types::StoredValue::String(value) = &mut entry.value else {
+        unreachable!("checked to be a string above");
     };
 
-    resp
+    // the gap up to the offset is zero padded
+    if value.len() < offset + patch.len() {
+        value.resize(offset + patch.len(), 0);
+    }
+    value[offset..offset + patch.len()].copy_from_slice(patch);
+
+    format!(":{}\r\n", value.len()).into_bytes()
 }
 
-// handle blpop
-pub fn handle_blpop(elems: Vec<String>, main_list_store: &types::SharedMainList) -> String {
-    let (guard, cvar) = &**main_list_store;
-    let mut main_list = guard.lock().unwrap();
+// handle lcs cmd
+pub fn handle_lcs(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 3 {
+        return b"-ERR wrong number of arguments for 'lcs' command\r\n".to_vec();
+    }
 
-    let timeout_sec = elems[2].parse::<f32>().unwrap();
-    let timeout = if timeout_sec == 0.0 {
-        Duration::from_secs_f32(u16::MAX as f32)
-    } else {
-        Duration::from_secs_f32(timeout_sec)
+    let mut get_len = false;
+    let mut get_idx = false;
+    let mut with_match_len = false;
+    let mut min_match_len = 0;
+    let mut i = 3;
+    while i < elems.len() {
+        match helper::cmd_name(&elems[i]).as_str() {
+            "len" => get_len = true,
+            "idx" => get_idx = true,
+            "withmatchlen" => with_match_len = true,
+            "minmatchlen" if i + 1 < elems.len() => {
+                let Some(len) = helper::parse_arg::<i64>(&elems[i + 1]) else {
+                    return b"-ERR value is not an integer or out of range\r\n".to_vec();
+                };
+                min_match_len = len.max(0) as usize;
+                i += 1;
+            }
+            _ => return b"-ERR syntax error\r\n".to_vec(),
+        }
+        i += 1;
+    }
+
+    if get_len && get_idx {
+        return b"-ERR If you want both the length and indexes, please just use IDX.\r\n".to_vec();
+    }
+
+    // missing keys are empty strings
+    let (a, b) = {
+        let (s, _) = &**store;
+        let mut map = s.lock().unwrap();
+
+        let mut string_at =
+            |key: &[u8]| match helper::get_live_entry(&mut map, key).map(|entry| &entry.value) {
+                Some(types::StoredValue::String(value)) => Some(value.clone()),
+                Some(_) => None,
+                None => Some(Vec::new()),
+            };
+        let (Some(a), Some(b)) = (string_at(&elems[1]), string_at(&elems[2])) else {
+            return b"-ERR The specified keys must contain string values\r\n".to_vec();
+        };
+        (a, b)
     };
 
-    let resp = loop {
-        match main_list.iter_mut().find(|l| l.name == elems[1]) {
-            Some(list) if !list.vec.is_empty() => {
-                let element = list.vec.remove(0);
+    // lcs[i][j] is the length of the lcs of a[..i] and b[..j]
+    let width = b.len() + 1;
+    let mut lcs = vec![0u32; (a.len() + 1) * width];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            lcs[i * width + j] = if a[i - 1] == b[j - 1] {
+                lcs[(i - 1) * width + j - 1] + 1
+            } else {
+                lcs[(i - 1) * width + j].max(lcs[i * width + j - 1])
+            };
+        }
+    }
+    let lcs_len = lcs[a.len() * width + b.len()] as usize;
 
-                break format!(
-                    "*2\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
-                    list.name.len(),
-                    list.name,
-                    element.len(),
-                    element
-                );
-            }
-            _ => {
-                let (mainlist_res, timeout_res) = cvar.wait_timeout(main_list, timeout).unwrap();
+    if get_len {
+        return format!(":{}\r\n", lcs_len).into_bytes();
+    }
 
-                main_list = mainlist_res;
+    // walks back from the end collecting the common bytes, and with IDX the
+    // ranges of contiguous matches, from the last one to the first like redis
+    let mut common = vec![0; lcs_len];
+    let mut matches: Vec<Vec<u8>> = Vec::new();
+    let mut range: Option<(usize, usize, usize, usize)> = None; // a start/end, b start/end
+    let (mut i, mut j, mut k) = (a.len(), b.len(), lcs_len);
+
+    while i > 0 && j > 0 {
+        let emit = if a[i - 1] == b[j - 1] {
+            common[k - 1] = a[i - 1];
+            range = match range {
+                // extends the current range backwards
+                Some((a_start, a_end, b_start, b_end)) if a_start == i && b_start == j => {
+                    Some((a_start - 1, a_end, b_start - 1, b_end))
+                }
+                _ => Some((i - 1, i - 1, j - 1, j - 1)),
+            };
+            // nothing can match before the first byte of either string
+            let at_start = i == 1 || j == 1;
+            i -= 1;
+            j -= 1;
+            k -= 1;
+            at_start
+        } else {
+            if lcs[(i - 1) * width + j] > lcs[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            // a gap ends the current range
+            range.is_some()
+        };
 
-                if timeout_res.timed_out() {
-                    break "*-1\r\n".to_string();
+        if let (true, Some((a_start, a_end, b_start, b_end))) = (emit, range) {
+            let match_len = a_end - a_start + 1;
+            if match_len >= min_match_len {
+                let mut entry = format!(
+                    "*{}\r\n*2\r\n:{a_start}\r\n:{a_end}\r\n*2\r\n:{b_start}\r\n:{b_end}\r\n",
+                    if with_match_len { 3 } else { 2 }
+                )
+                .into_bytes();
+                if with_match_len {
+                    entry.extend(format!(":{match_len}\r\n").into_bytes());
                 }
+                matches.push(entry);
             }
+            range = None;
         }
-    };
+    }
+
+    if !get_idx {
+        return helper::bulk_string(&common);
+    }
 
+    let mut resp = b"*4\r\n".to_vec();
+    resp.extend(helper::bulk_string(b"matches"));
+    resp.extend(format!("*{}\r\n", matches.len()).into_bytes());
+    for entry in matches {
+        resp.extend(entry);
+    }
+    resp.extend(helper::bulk_string(b"len"));
+    resp.extend(format!(":{}\r\n", lcs_len).into_bytes());
     resp
 }
 
-// handle type
-pub fn handle_type(elems: Vec<String>, store: &types::SharedStore) -> String {
-    let (s, _) = &**store;
-    let map = s.lock().unwrap();
-
-    if let Some(entry) = map.get(&elems[1]) {
-        match entry.value {
-            types::StoredValue::String(_) => "+string\r\n".to_string(),
-            types::StoredValue::Stream(_) => "+stream\r\n".to_string(),
+/// bit offset of SETBIT and GETBIT, within the largest string there can be
+fn parse_bit_offset(arg: &[u8]) -> Result<usize, Vec<u8>> {
+    match helper::parse_arg::<i64>(arg) {
+        Some(offset) if offset >= 0 && ((offset >> 3) as usize) < MAX_STRING_LEN => {
+            Ok(offset as usize)
         }
-    } else {
-        "+none\r\n".to_string()
+        _ => Err(b"-ERR bit offset is not an integer or out of range\r\n".to_vec()),
     }
 }
 
-// handle xadd
-pub fn handle_xadd(elems: &mut Vec<String>, store: &types::SharedStore) -> String {
-    let (guard, cvar) = &**store;
-    let mut map = guard.lock().unwrap();
-
-    let incoming_id = elems[2].clone();
-    let key = &elems[1].clone();
-    let last_id = helper::get_last_stream_id(key, &map);
-
-    // generate the whole sequence
-    if incoming_id == "*" {
-        elems[2] = helper::generate_full_id(last_id);
+/// true when the range of BITCOUNT or BITPOS is given in bits instead of bytes
+fn parse_bit_unit(arg: Option<&Vec<u8>>) -> Result<bool, Vec<u8>> {
+    match arg.map(|unit| helper::cmd_name(unit)).as_deref() {
+        None | Some("byte") => Ok(false),
+        Some("bit") => Ok(true),
+        Some(_) => Err(b"-ERR syntax error\r\n".to_vec()),
+    }
+}
 
-        // generate only the sequence number
-    } else if incoming_id.splitn(2, '-').nth(1).unwrap() == "*" {
-        elems[2] = helper::generate_seq_id(&incoming_id, last_id);
+/// inclusive range out of start and end of a string of len units, negative
+/// indexes counting from the end, None when nothing is in it
+fn clamp_range(start: i64, end: i64, len: i64) -> Option<(usize, usize)> {
+    if start < 0 && end < 0 && start > end {
+        return None;
+    }
 
-        // if ids are not generated, then must be validated
+    let start = if start < 0 {
+        (len + start).max(0)
     } else {
-        if let Some(error) = helper::validate_entry_id(&elems, &map) {
-            return error.to_string();
-        }
+        start
+    };
+    let end = if end < 0 {
+        (len + end).max(0)
+    } else {
+        end.min(len - 1)
+    };
+
+    if len == 0 || start > end {
+        return None;
     }
+    Some((start as usize, end as usize))
+}
 
-    // if stream exists, pushing the entry to the stream
-    let resp = if let Some(value_entry) = map.get_mut(key) {
-        let (id, new_map) = helper::get_stream_related_data(&elems);
+/// value of the bit at offset, the first one being the highest of the first byte
+fn bit_at(bytes: &[u8], offset: usize) -> u8 {
+    bytes
+        .get(offset / 8)
+        .map_or(0, |byte| (byte >> (7 - offset % 8)) & 1)
+}
 
-        if let types::StoredValue::Stream(entry_vector) = &mut value_entry.value {
-            entry_vector.push(types::Entry {
-                id: id.clone(),
-                map: new_map,
-            });
-        }
+// handle setbit cmd
+pub fn handle_setbit(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 4 {
+        return b"-ERR wrong number of arguments for 'setbit' command\r\n".to_vec();
+    }
 
-        format!("${}\r\n{}\r\n", id.len(), id)
-    } else {
-        let (id, new_map) = helper::get_stream_related_data(&elems);
-        let mut vec: Vec<types::Entry> = Vec::new();
+    let offset = match parse_bit_offset(&elems[2]) {
+        Ok(offset) => offset,
+        Err(e) => return e,
+    };
+    let bit = match elems[3].as_slice() {
+        b"0" => 0,
+        b"1" => 1,
+        _ => return b"-ERR bit is not an integer or out of range\r\n".to_vec(),
+    };
 
-        vec.push(types::Entry {
-            id: id.clone(),
-            map: new_map,
-        });
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
+    if helper::get_live_entry(&mut map, &elems[1]).is_none() {
         map.insert(
-            key.to_string(),
-            types::ValueEntry {
-                value: types::StoredValue::Stream(vec),
-                expires_at: None,
-            },
+            elems[1].clone(),
+            types::ValueEntry::new(types::StoredValue::String(Vec::new())),
         );
-
-        format!("${}\r\n{}\r\n", id.len(), id)
+    }
+    // the ttl of an existing key is kept
+    let entry = map.get_mut(&elems[1]).unwrap();
+    let types::StoredValue::String(bytes) = &mut entry.value else {
+        return helper::WRONGTYPE.to_vec();
     };
 
-    cvar.notify_one();
-    return resp;
+    // the string grows with zero bytes to hold the offset
+    if bytes.len() <= offset / 8 {
+        bytes.resize(offset / 8 + 1, 0);
+    }
+    let old = bit_at(bytes, offset);
+    let mask = 1 << (7 - offset % 8);
+    if bit == 1 {
+        bytes[offset / 8] |= mask;
+    } else {
+        bytes[offset / 8] &= !mask;
+    }
+
+    format!(":{}\r\n", old).into_bytes()
 }
 
-// handle xrange
-pub fn handle_xrange(elems: &mut Vec<String>, store: &types::SharedStore) -> String {
-    let (guard, _) = &**store;
-    let map = guard.lock().unwrap();
+// handle getbit cmd
+pub fn handle_getbit(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 3 {
+        return b"-ERR wrong number of arguments for 'getbit' command\r\n".to_vec();
+    }
 
-    let (start_time, start_seq, end_time, end_seq) = helper::get_start_and_end_indexes(&elems);
+    let offset = match parse_bit_offset(&elems[2]) {
+        Ok(offset) => offset,
+        Err(e) => return e,
+    };
 
-    // getting the entries array
-    // stream exists
-    if let Some(entry) = map.get(&elems[1]) {
-        if let types::StoredValue::Stream(entry_vec) = &entry.value {
-            let filtered_data: Vec<&types::Entry> = entry_vec
-                .iter()
-                .filter(|e| {
-                    let mut s = e.id.splitn(2, "-");
-                    let (item_time, item_seq) = (
-                        s.next().unwrap().parse::<u128>().unwrap(),
-                        s.next().unwrap().parse::<u128>().unwrap(),
-                    );
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-                    item_time <= end_time
-                        && item_time >= start_time
-                        && item_seq >= start_seq
-                        && item_seq <= end_seq
-                })
+    // bits past the end of the string, or of a missing key, are 0
+    match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(bytes)) => {
+            format!(":{}\r\n", bit_at(bytes, offset)).into_bytes()
+        }
+        Some(_) => helper::WRONGTYPE.to_vec(),
+        None => b":0\r\n".to_vec(),
+    }
+}
+
+// handle bitcount cmd
+pub fn handle_bitcount(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 2 {
+        return b"-ERR wrong number of arguments for 'bitcount' command\r\n".to_vec();
+    }
+
+    // BITCOUNT key [start end [BYTE|BIT]]
+    let range = match elems.len() {
+        2 => None,
+        4 | 5 => {
+            let (Some(start), Some(end)) = (
+                helper::parse_arg::<i64>(&elems[2]),
+                helper::parse_arg::<i64>(&elems[3]),
+            ) else {
+                return b"-ERR value is not an integer or out of range\r\n".to_vec();
+            };
+            match parse_bit_unit(elems.get(4)) {
+                Ok(in_bits) => Some((start, end, in_bits)),
+                Err(e) => return e,
+            }
+        }
+        _ => return b"-ERR syntax error\r\n".to_vec(),
+    };
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let bytes = match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(bytes)) => bytes,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => return b":0\r\n".to_vec(),
+    };
+
+    let count = match range {
+        None => bytes.iter().map(|byte| byte.count_ones() as usize).sum(),
+        Some((start, end, false)) => match clamp_range(start, end, bytes.len() as i64) {
+            Some((start, end)) => bytes[start..=end]
+                .iter()
+                .map(|byte| byte.count_ones() as usize)
+                .sum(),
+            None => 0,
+        },
+        Some((start, end, true)) => match clamp_range(start, end, bytes.len() as i64 * 8) {
+            Some((start, end)) => (start..=end)
+                .filter(|offset| bit_at(bytes, *offset) == 1)
+                .count(),
+            None => 0,
+        },
+    };
+
+    format!(":{}\r\n", count).into_bytes()
+}
+
+// handle bitpos cmd
+pub fn handle_bitpos(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 3 {
+        return b"-ERR wrong number of arguments for 'bitpos' command\r\n".to_vec();
+    }
+
+    let bit = match helper::parse_arg::<i64>(&elems[2]) {
+        Some(bit @ (0 | 1)) => bit as u8,
+        Some(_) => return b"-ERR The bit argument must be 1 or 0.\r\n".to_vec(),
+        None => return b"-ERR value is not an integer or out of range\r\n".to_vec(),
+    };
+
+    // BITPOS key bit [start [end [BYTE|BIT]]]
+    if elems.len() > 6 {
+        return b"-ERR syntax error\r\n".to_vec();
+    }
+    let mut bounds = Vec::new();
+    for arg in elems.iter().skip(3).take(2) {
+        match helper::parse_arg::<i64>(arg) {
+            Some(bound) => bounds.push(bound),
+            None => return b"-ERR value is not an integer or out of range\r\n".to_vec(),
+        }
+    }
+    let in_bits = match parse_bit_unit(elems.get(5)) {
+        Ok(in_bits) => in_bits,
+        Err(e) => return e,
+    };
+    let end_given = bounds.len() == 2;
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    // a missing key is an endless run of 0 bits
+    let bytes = match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(bytes)) => bytes,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => return format!(":{}\r\n", if bit == 1 { -1 } else { 0 }).into_bytes(),
+    };
+
+    let len = if in_bits {
+        bytes.len() as i64 * 8
+    } else {
+        bytes.len() as i64
+    };
+    let start = bounds.first().copied().unwrap_or(0);
+    let end = bounds.get(1).copied().unwrap_or(-1);
+    let Some((start, end)) = clamp_range(start, end, len) else {
+        return b":-1\r\n".to_vec();
+    };
+    let (first_bit, last_bit) = if in_bits {
+        (start, end)
+    } else {
+        (start * 8, end * 8 + 7)
+    };
+
+    // whole bytes holding none of the bit looked for are skipped at once
+    let skip = if bit == 1 { 0x00 } else { 0xff };
+    let mut offset = first_bit;
+    while offset <= last_bit {
+        if offset % 8 == 0 && offset + 7 <= last_bit && bytes[offset / 8] == skip {
+            offset += 8;
+            continue;
+        }
+        if bit_at(bytes, offset) == bit {
+            return format!(":{}\r\n", offset).into_bytes();
+        }
+        offset += 1;
+    }
+
+    // without an end the string counts as followed by 0 bits
+    if bit == 0 && !end_given {
+        return format!(":{}\r\n", bytes.len() * 8).into_bytes();
+    }
+    b":-1\r\n".to_vec()
+}
+
+// handle bitop cmd
+pub fn handle_bitop(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 4 {
+        return b"-ERR wrong number of arguments for 'bitop' command\r\n".to_vec();
+    }
+
+    let op = helper::cmd_name(&elems[1]);
+    if !matches!(op.as_str(), "and" | "or" | "xor" | "not") {
+        return b"-ERR syntax error\r\n".to_vec();
+    }
+    if op == "not" && elems.len() != 4 {
+        return b"-ERR BITOP NOT must be called with a single source key.\r\n".to_vec();
+    }
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    // missing keys are empty strings
+    let mut sources = Vec::with_capacity(elems.len() - 3);
+    for key in &elems[3..] {
+        match helper::get_live_entry(&mut map, key).map(|entry| &entry.value) {
+            Some(types::StoredValue::String(bytes)) => sources.push(bytes.clone()),
+            Some(_) => return helper::WRONGTYPE.to_vec(),
+            None => sources.push(Vec::new()),
+        }
+    }
+
+    // shorter strings are padded with zero bytes up to the longest one
+    let len = sources.iter().map(Vec::len).max().unwrap_or(0);
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let mut bytes = sources
+                .iter()
+                .map(|source| source.get(i).copied().unwrap_or(0));
+            let first = bytes.next().unwrap_or(0);
+            match op.as_str() {
+                "and" => bytes.fold(first, |acc, byte| acc & byte),
+                "or" => bytes.fold(first, |acc, byte| acc | byte),
+                "xor" => bytes.fold(first, |acc, byte| acc ^ byte),
+                _ => !first,
+            }
+        })
+        .collect();
+
+    // an empty result deletes the destination
+    if result.is_empty() {
+        map.remove(&elems[2]);
+    } else {
+        map.insert(
+            elems[2].clone(),
+            types::ValueEntry::new(types::StoredValue::String(result)),
+        );
+    }
+
+    format!(":{}\r\n", len).into_bytes()
+}
+
+// handle rpush
+pub fn handle_rpush(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    handle_push(elems, store, false)
+}
+
+// handle lpush
+pub fn handle_lpush(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    handle_push(elems, store, true)
+}
+
+/// shared by rpush and lpush, creates the list if it doesn't exist
+fn handle_push(elems: Vec<Vec<u8>>, store: &types::SharedStore, at_head: bool) -> Vec<u8> {
+    let (s, cvar) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let list_name = &elems[1];
+    let list_elements = &elems[2..];
+
+    if helper::get_live_entry(&mut map, list_name).is_none() {
+        map.insert(
+            list_name.clone(),
+            types::ValueEntry::new(types::StoredValue::List(VecDeque::new())),
+        );
+    }
+
+    let Some(types::StoredValue::List(list)) = map.get_mut(list_name).map(|e| &mut e.value) else {
+        return helper::WRONGTYPE.to_vec();
+    };
+
+    for element in list_elements {
+        if at_head {
+            list.push_front(element.clone());
+        } else {
+            list.push_back(element.clone());
+        }
+    }
+
+    let len = list.len();
+
+    // wake up blocked blpop clients
+    cvar.notify_all();
+    format!(":{}\r\n", len).into_bytes()
+}
+
+// handle lrange
+pub fn handle_lrange(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let list = match helper::get_live_entry(&mut map, &elems[1]).map(|e| &e.value) {
+        Some(types::StoredValue::List(l)) => l,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => {
+            return b"*0\r\n".to_vec();
+        }
+    };
+
+    let (Some(mut start), Some(mut end)) = (
+        helper::parse_arg::<i64>(&elems[2]),
+        helper::parse_arg::<i64>(&elems[3]),
+    ) else {
+        return b"-ERR value is not an integer or out of range\r\n".to_vec();
+    };
+
+    let list_len = list.len() as i64;
+
+    if start < 0 {
+        start = (list_len + start).max(0);
+    }
+
+    if end < 0 {
+        end += list_len;
+    }
+
+    if start >= list_len || start > end {
+        b"*0\r\n".to_vec()
+    } else {
+        if end >= list_len {
+            end = list_len - 1;
+        }
+
+        let slice: Vec<Vec<u8>> = list
+            .range(start as usize..(end + 1) as usize)
+            .cloned()
+            .collect();
+
+        helper::elements_arr_to_resp_arr(&slice)
+    }
+}
+
+// handle llen
+pub fn handle_llen(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    match helper::get_live_entry(&mut map, &elems[1]).map(|e| &e.value) {
+        Some(types::StoredValue::List(list)) => format!(":{}\r\n", list.len()).into_bytes(),
+        Some(_) => helper::WRONGTYPE.to_vec(),
+        None => b":0\r\n".to_vec(),
+    }
+}
+
+// handle lpop
+pub fn handle_lpop(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let list = match helper::get_live_entry(&mut map, &elems[1]).map(|e| &mut e.value) {
+        Some(types::StoredValue::List(l)) => l,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => {
+            return b"$-1\r\n".to_vec();
+        }
+    };
+
+    let count = match elems.get(2) {
+        Some(count) => match helper::parse_arg::<usize>(count) {
+            Some(count) => count.min(list.len()),
+            None => return b"-ERR value is out of range, must be positive\r\n".to_vec(),
+        },
+        None => 1,
+    };
+
+    let resp = if elems.len() == 2 {
+        let element = list.pop_front().unwrap_or_default();
+        helper::bulk_string(&element)
+    } else {
+        let popped: Vec<Vec<u8>> = list.drain(..count).collect();
+        helper::elements_arr_to_resp_arr(&popped)
+    };
+
+    // empty lists don't exist
+    if list.is_empty() {
+        map.remove(&elems[1]);
+    }
+
+    resp
+}
+
+// handle blpop
+pub fn handle_blpop(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, cvar) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let Some(timeout_sec) = helper::parse_arg::<f32>(&elems[2]) else {
+        return b"-ERR timeout is not a float or out of range\r\n".to_vec();
+    };
+    let timeout = if timeout_sec == 0.0 {
+        Duration::from_secs_f32(u16::MAX as f32)
+    } else {
+        Duration::from_secs_f32(timeout_sec)
+    };
+    let deadline = Instant::now() + timeout;
+
+    let resp = loop {
+        match helper::get_live_entry(&mut map, &elems[1]).map(|e| &mut e.value) {
+            Some(types::StoredValue::List(list)) if !list.is_empty() => {
+                let element = list.pop_front().unwrap();
+
+                if list.is_empty() {
+                    map.remove(&elems[1]);
+                }
+
+                break helper::elements_arr_to_resp_arr(&[elems[1].clone(), element]);
+            }
+            Some(types::StoredValue::List(_)) | None => {
+                let now = Instant::now();
+                if now >= deadline {
+                    break b"*-1\r\n".to_vec();
+                }
+
+                (map, _) = cvar.wait_timeout(map, deadline - now).unwrap();
+            }
+            Some(_) => break helper::WRONGTYPE.to_vec(),
+        }
+    };
+
+    resp
+}
+
+// handle type
+pub fn handle_type(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    if let Some(entry) = helper::get_live_entry(&mut map, &elems[1]) {
+        format!("+{}\r\n", entry.value.type_name()).into_bytes()
+    } else {
+        b"+none\r\n".to_vec()
+    }
+}
+
+// handle xadd
+pub fn handle_xadd(elems: &mut [Vec<u8>], store: &types::SharedStore) -> Vec<u8> {
+    let (guard, cvar) = &**store;
+    let mut map = guard.lock().unwrap();
+
+    let incoming_id = String::from_utf8_lossy(&elems[2]).to_string();
+    let key = &elems[1].clone();
+
+    if let Some(entry) = helper::get_live_entry(&mut map, key)
+        && !matches!(entry.value, types::StoredValue::Stream(_))
+    {
+        return helper::WRONGTYPE.to_vec();
+    }
+
+    if elems.len() < 5 || elems.len().is_multiple_of(2) {
+        return b"-ERR wrong number of arguments for 'xadd' command\r\n".to_vec();
+    }
+
+    let last_id = helper::get_last_stream_id(key, &map);
+
+    // generate the whole sequence
+    if incoming_id == "*" {
+        elems[2] = helper::generate_full_id(last_id).into_bytes();
+
+        // generate only the sequence number
+    } else if incoming_id.splitn(2, '-').nth(1) == Some("*") {
+        elems[2] = helper::generate_seq_id(&incoming_id, last_id).into_bytes();
+
+        // if ids are not generated, then must be validated
+    } else {
+        if let Some(error) = helper::validate_entry_id(elems, &map) {
+            return error.as_bytes().to_vec();
+        }
+    }
+
+    // if stream exists, pushing the entry to the stream
+    let resp = if let Some(value_entry) = map.get_mut(key) {
+        let (id, new_map) = helper::get_stream_related_data(elems);
+
+        if let types::StoredValue::Stream(entry_vector) = &mut value_entry.value {
+            entry_vector.push(types::Entry {
+                id: id.clone(),
+                map: new_map,
+            });
+        }
+
+        helper::bulk_string(id.as_bytes())
+    } else {
+        let (id, new_map) = helper::get_stream_related_data(elems);
+        let mut vec: Vec<types::Entry> = Vec::new();
+
+        vec.push(types::Entry {
+            id: id.clone(),
+            map: new_map,
+        });
+
+        map.insert(
+            key.clone(),
+            types::ValueEntry::new(types::StoredValue::Stream(vec)),
+        );
+
+        helper::bulk_string(id.as_bytes())
+    };
+
+    cvar.notify_one();
+    return resp;
+}
+
+// handle xrange
+pub fn handle_xrange(elems: &[Vec<u8>], store: &types::SharedStore) -> Vec<u8> {
+    let (guard, _) = &**store;
+    let mut map = guard.lock().unwrap();
+
+    let Some((start_time, start_seq, end_time, end_seq)) = helper::get_start_and_end_indexes(elems)
+    else {
+        return b"-ERR Invalid stream ID specified as stream command argument\r\n".to_vec();
+    };
+
+    // getting the entries array
+    // stream exists
+    if let Some(entry) = helper::get_live_entry(&mut map, &elems[1]) {
+        if let types::StoredValue::Stream(entry_vec) = &entry.value {
+            let filtered_data: Vec<&types::Entry> = entry_vec
+                .iter()
+                .filter(|e| {
+                    let mut s = e.id.splitn(2, "-");
+                    let (item_time, item_seq) = (
+                        s.next().unwrap().parse::<u128>().unwrap(),
+                        s.next().unwrap().parse::<u128>().unwrap(),
+                    );
+
+                    item_time <= end_time
+                        && item_time >= start_time
+                        && item_seq >= start_seq
+                        && item_seq <= end_seq
+                })
+                .collect();
+
+            let mut final_arr = format!("*{}\r\n", filtered_data.len()).into_bytes();
+
+            for entry in filtered_data {
+                final_arr.extend(helper::stream_entry_to_resp(entry));
+            }
+
+            final_arr
+        } else {
+            helper::WRONGTYPE.to_vec()
+        }
+
+        // stream doesn't exists
+    } else {
+        b"*0\r\n".to_vec()
+    }
+}
+
+// handle xread
+pub fn handle_xread(elems: &mut Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    // $ will pass nonetheless, anything will pass in place of entry ids
+    // blocking will always happen irrespective of whatever data is already present or not
+    // blocking if stream is not available or entries in it are not available
+    let (s, cvar) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let keys_start = if elems[1].eq_ignore_ascii_case(b"block") {
+        4
+    } else {
+        2
+    };
+    let no_of_streams = elems.len().saturating_sub(keys_start) / 2;
+
+    for key in elems.iter().skip(keys_start).take(no_of_streams) {
+        if let Some(entry) = helper::get_live_entry(&mut map, key)
+            && !matches!(entry.value, types::StoredValue::Stream(_))
+        {
+            return helper::WRONGTYPE.to_vec();
+        }
+    }
+
+    if elems[1].eq_ignore_ascii_case(b"block") {
+        let _ = elems.remove(1);
+
+        // since above element was removed, following is basically elems[2]
+        let block_time = match helper::parse_arg::<u64>(&elems.remove(1)) {
+            Some(0) => u64::MAX,
+            Some(t) => t,
+            None => return b"-ERR timeout is not an integer or out of range\r\n".to_vec(),
+        };
+
+        // this adjusts the start ids by mutating them in elements array
+        helper::adjust_xread_start_ids(&map, elems);
+
+        (map, _) = cvar
+            .wait_timeout(map, Duration::from_millis(block_time))
+            .unwrap();
+    }
+
+    let (final_array_data_of_streams, no_of_valid_streams) = helper::get_streams_array(&map, elems);
+
+    if no_of_valid_streams != 0 {
+        let mut data_to_send = format!("*{}\r\n", no_of_valid_streams).into_bytes();
+        data_to_send.extend(final_array_data_of_streams);
+
+        return data_to_send;
+    } else {
+        return b"*-1\r\n".to_vec();
+    }
+}
+
+// handle incr cmd
+pub fn handle_incr(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let cmd = helper::cmd_name(&elems[0]);
+    let arity = if cmd == "incr" || cmd == "decr" { 2 } else { 3 };
+    if elems.len() != arity {
+        return format!("-ERR wrong number of arguments for '{}' command\r\n", cmd).into_bytes();
+    }
+
+    let increment = match cmd.as_str() {
+        "incr" => 1,
+        "decr" => -1,
+        _ => match helper::parse_int(&elems[2]) {
+            // the negation can't be represented
+            Some(i64::MIN) if cmd == "decrby" => {
+                return b"-ERR decrement would overflow\r\n".to_vec();
+            }
+            Some(n) if cmd == "decrby" => -n,
+            Some(n) => n,
+            None => return b"-ERR value is not an integer or out of range\r\n".to_vec(),
+        },
+    };
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    // a missing key counts as 0
+    let current = match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(bytes)) => match helper::parse_int(bytes) {
+            Some(current) => current,
+            None => return b"-ERR value is not an integer or out of range\r\n".to_vec(),
+        },
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => 0,
+    };
+    let Some(updated) = current.checked_add(increment) else {
+        return b"-ERR increment or decrement would overflow\r\n".to_vec();
+    };
+
+    store_counter(&mut map, &elems[1], updated.to_string().into_bytes());
+    format!(":{}\r\n", updated).into_bytes()
+}
+
+/// writes the new value of a counter, an existing key keeps its ttl
+fn store_counter(map: &mut types::Keyspace, key: &[u8], value: Vec<u8>) {
+    match map.get_mut(key) {
+        Some(entry) => entry.value = types::StoredValue::String(value),
+        None => {
+            map.insert(
+                key.to_vec(),
+                types::ValueEntry::new(types::StoredValue::String(value)),
+            );
+        }
+    }
+}
+
+// handle incrbyfloat cmd
+pub fn handle_incrbyfloat(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 3 {
+        return b"-ERR wrong number of arguments for 'incrbyfloat' command\r\n".to_vec();
+    }
+
+    if helper::parse_float(&elems[2]).is_none() {
+        return b"-ERR value is not a valid float\r\n".to_vec();
+    }
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let current = match helper::get_live_entry(&mut map, &elems[1]).map(|entry| &entry.value) {
+        Some(types::StoredValue::String(bytes)) if helper::parse_float(bytes).is_some() => {
+            bytes.clone()
+        }
+        Some(types::StoredValue::String(_)) => {
+            return b"-ERR value is not a valid float\r\n".to_vec();
+        }
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => b"0".to_vec(),
+    };
+    let Some(value) = float_sum(&current, &elems[2]) else {
+        return b"-ERR increment would produce NaN or Infinity\r\n".to_vec();
+    };
+
+    let value = value.into_bytes();
+    let reply = helper::bulk_string(&value);
+    store_counter(&mut map, &elems[1], value);
+    reply
+}
+
+/// digits after the point redis prints the long double result of INCRBYFLOAT
+/// with (%.17Lf), before trimming the trailing zeros
+const FLOAT_FRACTION_DIGITS: u32 = 17;
+
+/// current + increment as INCRBYFLOAT stores it, never with an exponent and
+/// without trailing zeros, None when it isn't finite
+/// redis adds them as long doubles, which is more precise than a f64, so
+/// plain decimals are added exactly and 0.1 + 0.2 is 0.3 like there, anything
+/// too long or too far apart goes through f64
+fn float_sum(current: &[u8], increment: &[u8]) -> Option<String> {
+    let sum = helper::parse_float(current)? + helper::parse_float(increment)?;
+    if !sum.is_finite() {
+        return None;
+    }
+
+    let text = match parse_decimal(current)
+        .zip(parse_decimal(increment))
+        .and_then(|(a, b)| add_decimals(a, b))
+    {
+        Some((mantissa, exp)) => format_decimal(mantissa, exp),
+        None => format!("{:.*}", FLOAT_FRACTION_DIGITS as usize, sum),
+    };
+
+    Some(trim_fraction(text))
+}
+
+/// a plain decimal number like 12.5, -3 or 1.5e3 as mantissa * 10^exp, None
+/// for anything else or when it has too many digits to be exact
+fn parse_decimal(arg: &[u8]) -> Option<(i128, i32)> {
+    let text = std::str::from_utf8(arg).ok()?;
+    let (number, exp) = match text.find(['e', 'E']) {
+        Some(at) => (&text[..at], text[at + 1..].parse::<i32>().ok()?),
+        None => (text, 0),
+    };
+    let (negative, number) = match number.strip_prefix('-') {
+        Some(number) => (true, number),
+        None => (false, number.strip_prefix('+').unwrap_or(number)),
+    };
+    let (int_part, frac_part) = number.split_once('.').unwrap_or((number, ""));
+    if int_part.is_empty() && frac_part.is_empty() {
+        return None;
+    }
+
+    let mut mantissa: i128 = 0;
+    for c in int_part.bytes().chain(frac_part.bytes()) {
+        if !c.is_ascii_digit() {
+            return None;
+        }
+        mantissa = mantissa.checked_mul(10)?.checked_add((c - b'0') as i128)?;
+    }
+    let exp = exp.checked_sub(frac_part.len() as i32)?;
+
+    Some((if negative { -mantissa } else { mantissa }, exp))
+}
+
+/// exact sum of two decimals, None if it doesn't fit
+fn add_decimals(a: (i128, i32), b: (i128, i32)) -> Option<(i128, i32)> {
+    let ((high, high_exp), (low, low_exp)) = if a.1 >= b.1 { (a, b) } else { (b, a) };
+    let scale = 10i128.checked_pow(high_exp.checked_sub(low_exp)? as u32)?;
+    Some((high.checked_mul(scale)?.checked_add(low)?, low_exp))
+}
+
+/// mantissa * 10^exp rounded to FLOAT_FRACTION_DIGITS decimal places, in
+/// fixed notation
+fn format_decimal(mantissa: i128, mut exp: i32) -> String {
+    let mut digits = mantissa.unsigned_abs();
+
+    let min_exp = -(FLOAT_FRACTION_DIGITS as i32);
+    if exp < min_exp {
+        let excess = (min_exp - exp) as u32;
+        // more digits to drop than the mantissa has rounds it to 0
+        digits = match 10u128.checked_pow(excess) {
+            Some(divisor) => digits / divisor + u128::from(digits % divisor * 2 >= divisor),
+            None => 0,
+        };
+        exp = min_exp;
+    }
+
+    let digits = digits.to_string();
+    let text = if exp >= 0 {
+        format!("{}{}", digits, "0".repeat(exp as usize))
+    } else {
+        let point = digits.len() as i32 + exp;
+        if point > 0 {
+            format!(
+                "{}.{}",
+                &digits[..point as usize],
+                &digits[point as usize..]
+            )
+        } else {
+            format!("0.{}{}", "0".repeat(-point as usize), digits)
+        }
+    };
+
+    if mantissa < 0 {
+        format!("-{text}")
+    } else {
+        text
+    }
+}
+
+/// drops the trailing zeros of the fraction and the point, -0 becomes 0
+fn trim_fraction(mut text: String) -> String {
+    if text.contains('.') {
+        text.truncate(text.trim_end_matches('0').trim_end_matches('.').len());
+    }
+    if text == "-0" {
+        text.remove(0);
+    }
+    text
+}
+
+/// handle info command
+/// INFO [section ...], with no section the default ones, `all` or `everything`
+/// for all of them
+pub fn handle_info(elems: &[Vec<u8>], ctx: &types::ServerContext) -> Vec<u8> {
+    let types::ServerContext {
+        dbs,
+        save_state,
+        aof,
+        repl_state,
+        replicas,
+        stats,
+        maxmemory,
+        ..
+    } = ctx;
+
+    const ALL_SECTIONS: [(&str, &str); 10] = [
+        ("server", "Server"),
+        ("clients", "Clients"),
+        ("memory", "Memory"),
+        ("persistence", "Persistence"),
+        ("stats", "Stats"),
+        ("replication", "Replication"),
+        ("cpu", "CPU"),
+        ("commandstats", "Commandstats"),
+        ("errorstats", "Errorstats"),
+        ("keyspace", "Keyspace"),
+    ];
+
+    let requested: Vec<String> = elems[1..].iter().map(|s| helper::cmd_name(s)).collect();
+    // commandstats is the only section left out by default
+    let wanted = |section: &str| {
+        if requested.is_empty() {
+            return section != "commandstats";
+        }
+        requested.iter().any(|r| {
+            r == section
+                || r == "all"
+                || r == "everything"
+                || (r == "default" && section != "commandstats")
+        })
+    };
+
+    let sections: Vec<String> = ALL_SECTIONS
+        .iter()
+        .filter(|(section, _)| wanted(section))
+        .map(|(section, title)| {
+            let body = match *section {
+                "server" => info_server(repl_state, stats),
+                "clients" => format!(
+                    "connected_clients:{}\r\n",
+                    stats.lock().unwrap().connected_clients
+                ),
+                "memory" => info_memory(dbs, maxmemory),
+                "persistence" => info_persistence(save_state, aof),
+                "stats" => info_stats(stats),
+                "replication" => helper::replication_info(repl_state, replicas),
+                "cpu" => info_cpu(),
+                "commandstats" => info_commandstats(stats),
+                "errorstats" => info_errorstats(stats),
+                _ => info_keyspace(dbs),
+            };
+
+            format!("# {title}\r\n{body}")
+        })
+        .collect();
+
+    helper::bulk_string(sections.join("\r\n").as_bytes())
+}
+
+fn info_server(
+    repl_state: &types::SharedReplicationState,
+    stats: &types::SharedServerStats,
+) -> String {
+    let tcp_port = repl_state.lock().unwrap().listening_port.clone();
+    let stats = stats.lock().unwrap();
+    let uptime = stats.started_at.elapsed().as_secs();
+
+    format!(
+        "redis_version:{}\r\nredis_mode:standalone\r\nos:{} {}\r\narch_bits:{}\r\n\
+         process_id:{}\r\nrun_id:{}\r\ntcp_port:{}\r\nserver_time_usec:{}\r\n\
+         uptime_in_seconds:{}\r\nuptime_in_days:{}\r\n",
+        helper::REDIS_VERSION,
+        std::env::consts::OS,
+        std::env::consts::ARCH,
+        usize::BITS,
+        std::process::id(),
+        stats.run_id,
+        tcp_port,
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros(),
+        uptime,
+        uptime / 86400
+    )
+}
+
+fn info_memory(dbs: &types::SharedDatabases, maxmemory: &memory::SharedMaxMemory) -> String {
+    let used_memory = memory::used_memory();
+
+    // keys, values and a fixed per key overhead for the table entry and expiry
+    let used_memory_dataset: usize = dbs
+        .iter()
+        .map(|store| {
+            let map = store.0.lock().unwrap();
+            map.iter()
+                .map(|(key, entry)| key.len() + entry.value.approx_size() + 64)
+                .sum::<usize>()
+        })
+        .sum();
+
+    let maxmemory = maxmemory.lock().unwrap();
+    format!(
+        "used_memory:{}\r\nused_memory_human:{}\r\nused_memory_dataset:{}\r\n\
+         maxmemory:{}\r\nmaxmemory_human:{}\r\nmaxmemory_policy:{}\r\n",
+        used_memory,
+        helper::human_bytes(used_memory as u64),
+        used_memory_dataset,
+        maxmemory.limit,
+        helper::human_bytes(maxmemory.limit),
+        maxmemory.policy.as_str()
+    )
+}
+
+fn info_persistence(save_state: &types::SharedSaveState, aof: &aof::SharedAof) -> String {
+    let mut info = {
+        let save_state = save_state.lock().unwrap();
+        format!(
+            "loading:0\r\nrdb_changes_since_last_save:{}\r\nrdb_bgsave_in_progress:{}\r\n\
+             rdb_last_save_time:{}\r\nrdb_last_bgsave_status:{}\r\n",
+            save_state.dirty,
+            save_state.bgsave_in_progress as u8,
+            save_state.last_save,
+            if save_state.last_bgsave_ok {
+                "ok"
+            } else {
+                "err"
+            }
+        )
+    };
+
+    let aof = aof.lock().unwrap();
+    info.push_str(&format!(
+        "aof_enabled:{}\r\naof_rewrite_in_progress:{}\r\n",
+        aof.is_enabled() as u8,
+        aof.is_rewriting() as u8
+    ));
+    if aof.is_enabled() {
+        info.push_str(&format!(
+            "aof_current_size:{}\r\naof_base_size:{}\r\naof_last_write_status:{}\r\n",
+            aof.current_size,
+            aof.base_size,
+            if aof.last_write_ok() { "ok" } else { "err" }
+        ));
+    }
+
+    info
+}
+
+fn info_stats(stats: &types::SharedServerStats) -> String {
+    let stats = stats.lock().unwrap();
+    format!(
+        "total_connections_received:{}\r\ntotal_commands_processed:{}\r\n\
+         total_error_replies:{}\r\nexpired_keys:{}\r\nevicted_keys:{}\r\n",
+        stats.total_connections_received,
+        stats.total_commands_processed,
+        stats.total_error_replies,
+        stats.expired_keys,
+        stats.evicted_keys
+    )
+}
+
+/// user and system cpu seconds of the process, from /proc where it exists
+fn info_cpu() -> String {
+    // utime and stime are the 12th and 13th fields after the parenthesized name,
+    // in clock ticks of 1/100th of a second
+    let (user, sys) = std::fs::read_to_string("/proc/self/stat")
+        .ok()
+        .and_then(|stat| {
+            let fields: Vec<u64> = stat
+                .rsplit_once(") ")?
+                .1
+                .split(' ')
+                .skip(11)
+                .take(2)
+                .filter_map(|f| f.parse().ok())
                 .collect();
+            Some((*fields.first()?, *fields.get(1)?))
+        })
+        .unwrap_or((0, 0));
 
-            let mut final_arr = format!("*{}\r\n", filtered_data.len());
+    format!(
+        "used_cpu_sys:{:.6}\r\nused_cpu_user:{:.6}\r\n",
+        sys as f64 / 100.0,
+        user as f64 / 100.0
+    )
+}
 
-            for entry in filtered_data {
-                let entry_id = &entry.id;
-                let hashmap = &entry.map;
-
-                let mut hmap_arr = format!("*{}\r\n", hashmap.len() * 2);
-
-                for (key, value) in hashmap {
-                    let data = format!(
-                        "${}\r\n{}\r\n${}\r\n{}\r\n",
-                        key.len(),
-                        key,
-                        value.len(),
-                        value
-                    );
-                    hmap_arr.push_str(&data);
-                }
+fn info_commandstats(stats: &types::SharedServerStats) -> String {
+    let stats = stats.lock().unwrap();
+    let mut names: Vec<&String> = stats.command_stats.keys().collect();
+    names.sort();
+
+    names
+        .into_iter()
+        .map(|name| {
+            let cmd = &stats.command_stats[name];
+            format!(
+                "cmdstat_{name}:calls={},usec={},usec_per_call={:.2},rejected_calls={},failed_calls={}\r\n",
+                cmd.calls,
+                cmd.usec,
+                cmd.usec as f64 / cmd.calls.max(1) as f64,
+                cmd.rejected_calls,
+                cmd.failed_calls
+            )
+        })
+        .collect()
+}
+
+fn info_errorstats(stats: &types::SharedServerStats) -> String {
+    let stats = stats.lock().unwrap();
+    let mut errors: Vec<(&String, &u64)> = stats.error_stats.iter().collect();
+    errors.sort();
+
+    errors
+        .into_iter()
+        .map(|(error, count)| format!("errorstat_{error}:count={count}\r\n"))
+        .collect()
+}
+
+fn info_keyspace(dbs: &types::SharedDatabases) -> String {
+    let now = helper::now_unix_ms();
+    let mut body = String::new();
+
+    for (db, store) in dbs.iter().enumerate() {
+        let map = store.0.lock().unwrap();
+
+        let mut keys = 0;
+        let mut expires = 0;
+        let mut total_ttl_ms = 0;
+        for entry in map.values().filter(|entry| !entry.is_expired()) {
+            keys += 1;
+            if let Some(expires_at) = entry.expires_at {
+                expires += 1;
+                total_ttl_ms += expires_at.saturating_sub(now);
+            }
+        }
+
+        // empty databases are left out
+        if keys > 0 {
+            body += &format!(
+                "db{db}:keys={keys},expires={expires},avg_ttl={}\r\n",
+                total_ttl_ms / expires.max(1)
+            );
+        }
+    }
+
+    body
+}
+
+/// handle replconf command
+pub fn handle_replconf(
+    elems: &Vec<Vec<u8>>,
+    stream: &TcpStream,
+    replicas: &types::SharedReplicas,
+    listening_port: &mut Option<u16>,
+) -> Option<Vec<u8>> {
+    let option = elems
+        .get(1)
+        .map(|o| helper::cmd_name(o))
+        .unwrap_or_default();
+
+    match option.as_str() {
+        "ack" => {
+            let (Some(offset), Ok(addr)) = (
+                elems.get(2).and_then(|o| helper::parse_arg::<usize>(o)),
+                stream.peer_addr(),
+            ) else {
+                return None;
+            };
+
+            let mut replicas = replicas.lock().unwrap();
+            if let Some(replica) = replicas.iter_mut().find(|r| r.addr == addr) {
+                replica.ack_offset = replica.ack_offset.max(offset);
+                replica.last_ack = Instant::now();
+            }
+
+            None
+        }
+        "listening-port" => match elems.get(2).and_then(|p| helper::parse_arg::<u16>(p)) {
+            Some(port) => {
+                *listening_port = Some(port);
+                Some(b"+OK\r\n".to_vec())
+            }
+            None => Some(b"-ERR value is not an integer or out of range\r\n".to_vec()),
+        },
+        _ => Some(b"+OK\r\n".to_vec()),
+    }
+}
+
+// handle psync cmd
+pub fn handle_psync(
+    stream: &mut TcpStream,
+    elems: &[Vec<u8>],
+    replicas: &types::SharedReplicas,
+    dbs: &types::SharedDatabases,
+    aof: &aof::SharedAof,
+    repl_state: &types::SharedReplicationState,
+    listening_port: Option<u16>,
+) {
+    // writes are executed and propagated under the aof lock, so while it is held the
+    // snapshot and the offset describe the same point
+    let mut writes_guard = aof.lock().unwrap();
+    let repl = repl_state.lock().unwrap();
+
+    // PSYNC <replid> <offset>, the offset being the first byte the replica is missing
+    let requested_replid = elems.get(1).map(|id| id.as_slice()).unwrap_or(b"?");
+    // the id this server had before a promotion is still good up to the promotion
+    let known_replid = |offset: usize| {
+        requested_replid == repl.replid.as_bytes()
+            || repl.replid2.as_ref().is_some_and(|(replid2, valid_until)| {
+                requested_replid == replid2.as_bytes() && offset <= *valid_until
+            })
+    };
+    let missing = elems
+        .get(2)
+        .and_then(|offset| helper::parse_arg::<usize>(offset))
+        .filter(|offset| *offset > 0 && known_replid(offset - 1))
+        .and_then(|offset| repl.backlog_since(offset - 1));
+
+    let replid = repl.replid.clone();
+    let offset = repl.master_repl_offset;
+    let snapshot = if missing.is_none() {
+        // the stream after the snapshot has to say which db its first write is for
+        writes_guard.selected_db = None;
+        Some(helper::snapshot_store(dbs))
+    } else {
+        None
+    };
+    drop(repl);
+    drop(writes_guard);
+
+    // the locks aren't held while the replica is sent the data, writes made
+    // meanwhile reach it from the backlog below
+    if let Some(missing) = missing {
+        println!(
+            "[info] partial resync accepted, sending {} bytes of backlog",
+            missing.len()
+        );
+        let _ = stream.write_all(format!("+CONTINUE {replid}\r\n").as_bytes());
+        let _ = stream.write_all(&missing);
+    } else if let Some(snapshot) = snapshot {
+        let rdb_file_bytes = crate::rdb::serialize(&snapshot.keys, false);
+        let _rdb_memory = memory::NotCounted::new(rdb_file_bytes.capacity());
+        drop(snapshot);
+
+        let _ = stream.write_all(format!("+FULLRESYNC {replid} {offset}\r\n").as_bytes());
+
+        let header = format!("${}\r\n", rdb_file_bytes.len());
+
+        let _ = stream.write_all(header.as_bytes());
+        let _ = stream.write_all(&rdb_file_bytes);
+    }
+
+    let (Ok(replica_stream), Ok(addr)) = (stream.try_clone(), stream.peer_addr()) else {
+        return;
+    };
+
+    // catching up and joining the replicas under the locks, so no write is sent
+    // twice or missed
+    let _writes_guard = aof.lock().unwrap();
+    let repl = repl_state.lock().unwrap();
+    let since = match repl.backlog_since(offset) {
+        Some(since) if repl.replid == replid => since,
+        // the backlog moved past the offset or the role changed, the replica
+        // has to sync again
+        _ => {
+            println!("[info] replica fell behind while syncing, closing its link");
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+            return;
+        }
+    };
+    if stream.write_all(&since).is_err() {
+        return;
+    }
+
+    replicas.lock().unwrap().push(types::Replica {
+        stream: replica_stream,
+        addr,
+        listening_port,
+        ack_offset: 0,
+        last_ack: Instant::now(),
+    });
+}
+
+// handle replicaof cmd
+pub fn handle_replicaof(
+    elems: &Vec<Vec<u8>>,
+    dbs: &types::SharedDatabases,
+    users: &types::SharedUsers,
+    aof: &aof::SharedAof,
+    replicas: &types::SharedReplicas,
+    repl_state: &types::SharedReplicationState,
+) -> Vec<u8> {
+    if elems.len() != 3 {
+        return b"-ERR wrong number of arguments for 'replicaof' command\r\n".to_vec();
+    }
+
+    // no writes are executed or propagated while the role changes
+    let mut writes_guard = aof.lock().unwrap();
+
+    if elems[1].eq_ignore_ascii_case(b"no") && elems[2].eq_ignore_ascii_case(b"one") {
+        if repl_state.lock().unwrap().master_addr.is_none() {
+            return b"+OK\r\n".to_vec();
+        }
+
+        helper::set_master(None, dbs, users, repl_state, false);
+        // the stream received so far may have selected any db
+        writes_guard.selected_db = None;
+
+        // replicas of the old master can continue from the stream received so far
+        let mut repl = repl_state.lock().unwrap();
+        let old_replid = std::mem::replace(&mut repl.replid, helper::random_replid());
+        repl.replid2 = Some((old_replid, repl.master_repl_offset));
+        println!("[info] promoted to master, keeping the current data");
+
+        return b"+OK\r\n".to_vec();
+    }
+
+    let (host, Some(port)) = (
+        String::from_utf8_lossy(&elems[1]).to_string(),
+        helper::parse_arg::<u16>(&elems[2]),
+    ) else {
+        return b"-ERR Invalid master port\r\n".to_vec();
+    };
+    let master_addr = format!("{host}:{port}");
+
+    if repl_state.lock().unwrap().master_addr.as_deref() == Some(master_addr.as_str()) {
+        return b"+OK Already connected to specified master\r\n".to_vec();
+    }
+
+    // our replicas have to resync, from now on they get what the new master sends
+    for replica in replicas.lock().unwrap().drain(..) {
+        let _ = replica.stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    // the current data is kept until the new master sends a snapshot, and not
+    // replaced at all if it accepts continuing from our stream
+    helper::set_master(Some(master_addr.clone()), dbs, users, repl_state, true);
+    println!("[info] replicating from {master_addr}");
 
-                let mut entry_arr = format!("*2\r\n${}\r\n{}\r\n", entry_id.len(), entry_id);
-                entry_arr.push_str(&hmap_arr);
-                final_arr.push_str(&entry_arr);
+    b"+OK\r\n".to_vec()
+}
+
+// handle wait cmd
+pub fn handle_wait(
+    elems: &Vec<Vec<u8>>,
+    replicas: &types::SharedReplicas,
+    aof: &aof::SharedAof,
+    repl_state: &types::SharedReplicationState,
+) -> Vec<u8> {
+    let (Some(replica_needed), Some(timeout_ms)) = (
+        helper::parse_arg::<usize>(&elems[1]),
+        helper::parse_arg::<u64>(&elems[2]),
+    ) else {
+        return b"-ERR value is not an integer or out of range\r\n".to_vec();
+    };
+
+    if repl_state.lock().unwrap().master_addr.is_some() {
+        return b"-ERR WAIT cannot be used with replica instances.\r\n".to_vec();
+    }
+
+    // every write made so far has to be acknowledged, each WAIT has its own target
+    // so overlapping calls don't interfere
+    let acked = |target: usize| {
+        let replicas = replicas.lock().unwrap();
+        replicas.iter().filter(|r| r.ack_offset >= target).count()
+    };
+
+    let target = {
+        // the getack goes through the replication stream like any write, so it
+        // is counted in the offsets and kept in the backlog
+        let _writes_guard = aof.lock().unwrap();
+        let mut repl = repl_state.lock().unwrap();
+        let target = repl.master_repl_offset;
+
+        if acked(target) >= replica_needed {
+            return format!(":{}\r\n", acked(target)).into_bytes();
+        }
+
+        let getack_cmd = b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n";
+        println!("[info] sent getack to replicas, waiting for offset {target}");
+        helper::handle_slaves(replicas, getack_cmd);
+        repl.feed(getack_cmd);
+
+        target
+    };
+
+    // a timeout of 0 blocks until enough replicas acknowledged
+    let deadline = (timeout_ms > 0).then(|| Instant::now() + Duration::from_millis(timeout_ms));
+    loop {
+        let replica_count = acked(target);
+
+        if replica_count >= replica_needed || deadline.is_some_and(|d| Instant::now() > d) {
+            return format!(":{}\r\n", replica_count).into_bytes();
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+// handle config cmd
+pub fn handle_config(
+    dir_clone: &Arc<Mutex<Option<String>>>,
+    dbfilename_clone: &Arc<Mutex<Option<String>>>,
+    save_state: &types::SharedSaveState,
+    aof: &aof::SharedAof,
+    maxmemory: &memory::SharedMaxMemory,
+    elems: Vec<Vec<u8>>,
+) -> Vec<u8> {
+    if elems.len() < 3 {
+        return b"-ERR wrong number of arguments for 'config' command\r\n".to_vec();
+    }
+
+    let param = helper::cmd_name(&elems[2]);
+
+    match helper::cmd_name(&elems[1]).as_str() {
+        "get" => {
+            let value = match param.as_str() {
+                "dir" => dir_clone.lock().unwrap().clone(),
+                "dbfilename" => dbfilename_clone.lock().unwrap().clone(),
+                "save" => {
+                    let state = save_state.lock().unwrap();
+                    let points: Vec<String> = state
+                        .save_points
+                        .iter()
+                        .map(|(seconds, changes)| format!("{seconds} {changes}"))
+                        .collect();
+                    Some(points.join(" "))
+                }
+                "appendonly" => {
+                    let enabled = aof.lock().unwrap().is_enabled();
+                    Some(if enabled { "yes" } else { "no" }.to_string())
+                }
+                "appendfsync" => Some(aof.lock().unwrap().fsync.as_str().to_string()),
+                "auto-aof-rewrite-percentage" => {
+                    Some(aof.lock().unwrap().auto_rewrite_percentage.to_string())
+                }
+                "auto-aof-rewrite-min-size" => {
+                    Some(aof.lock().unwrap().auto_rewrite_min_size.to_string())
+                }
+                "maxmemory" => Some(maxmemory.lock().unwrap().limit.to_string()),
+                "maxmemory-policy" => Some(maxmemory.lock().unwrap().policy.as_str().to_string()),
+                "maxmemory-samples" => Some(maxmemory.lock().unwrap().samples.to_string()),
+                _ => return b"*0\r\n".to_vec(),
+            };
+
+            match value {
+                Some(value) => {
+                    let arr = vec![param.into_bytes(), value.into_bytes()];
+                    helper::elements_arr_to_resp_arr(&arr)
+                }
+                None => format!("-ERR error getting {param}\r\n").into_bytes(),
+            }
+        }
+        "set" => {
+            let Some(value) = elems.get(3) else {
+                return b"-ERR wrong number of arguments for 'config|set' command\r\n".to_vec();
+            };
+            let value = String::from_utf8_lossy(value).to_string();
+
+            match param.as_str() {
+                "dir" => *dir_clone.lock().unwrap() = Some(value),
+                "dbfilename" => *dbfilename_clone.lock().unwrap() = Some(value),
+                "save" => match helper::parse_save_points(&value) {
+                    Some(points) => save_state.lock().unwrap().save_points = points,
+                    None => {
+                        return b"-ERR Invalid argument 'save' for CONFIG SET\r\n".to_vec();
+                    }
+                },
+                "auto-aof-rewrite-percentage" => match value.parse::<u64>() {
+                    Ok(percentage) => aof.lock().unwrap().auto_rewrite_percentage = percentage,
+                    Err(_) => {
+                        return b"-ERR Invalid argument 'auto-aof-rewrite-percentage' for CONFIG SET\r\n"
+                            .to_vec();
+                    }
+                },
+                "auto-aof-rewrite-min-size" => match helper::parse_memory(&value) {
+                    Some(min_size) => aof.lock().unwrap().auto_rewrite_min_size = min_size,
+                    None => {
+                        return b"-ERR Invalid argument 'auto-aof-rewrite-min-size' for CONFIG SET\r\n"
+                            .to_vec();
+                    }
+                },
+                "appendfsync" => match aof::AppendFsync::parse(&value) {
+                    Some(policy) => aof.lock().unwrap().fsync = policy,
+                    None => {
+                        return b"-ERR Invalid argument 'appendfsync' for CONFIG SET\r\n".to_vec();
+                    }
+                },
+                // a lower limit is enforced from the next write on
+                "maxmemory" => match helper::parse_memory(&value) {
+                    Some(limit) => maxmemory.lock().unwrap().limit = limit,
+                    None => {
+                        return b"-ERR Invalid argument 'maxmemory' for CONFIG SET\r\n".to_vec();
+                    }
+                },
+                "maxmemory-policy" => match memory::EvictionPolicy::parse(&value) {
+                    Some(policy) => maxmemory.lock().unwrap().policy = policy,
+                    None => {
+                        return b"-ERR Invalid argument 'maxmemory-policy' for CONFIG SET\r\n"
+                            .to_vec();
+                    }
+                },
+                "maxmemory-samples" => match value.parse::<usize>() {
+                    Ok(samples) if samples > 0 => maxmemory.lock().unwrap().samples = samples,
+                    _ => {
+                        return b"-ERR Invalid argument 'maxmemory-samples' for CONFIG SET\r\n"
+                            .to_vec();
+                    }
+                },
+                _ => return format!("-ERR Unknown option '{param}'\r\n").into_bytes(),
             }
 
-            return final_arr;
-        }
+            b"+OK\r\n".to_vec()
+        }
+        _ => b"-ERR unknown CONFIG subcommand\r\n".to_vec(),
+    }
+}
+
+// handle save cmd
+pub fn handle_save(
+    dbs: &types::SharedDatabases,
+    dir: &Arc<Mutex<Option<String>>>,
+    dbfilename: &Arc<Mutex<Option<String>>>,
+    save_state: &types::SharedSaveState,
+) -> Vec<u8> {
+    // unlike a real SAVE reads go on while the file is written, only the copy of
+    // the keyspace holds the db locks (see snapshot_store)
+    // the state stays locked until the file is written though, and a write waits
+    // on it to count itself as dirty, still holding the aof lock, so every write
+    // after it waits as well
+    let mut state = save_state.lock().unwrap();
+    if state.bgsave_in_progress {
+        return b"-ERR Background save already in progress\r\n".to_vec();
+    }
+
+    let snapshot = helper::snapshot_store(dbs);
+    let path = helper::rdb_path(dir, dbfilename);
+
+    match crate::rdb::write_file(&path, &snapshot.keys) {
+        Ok(()) => {
+            state.dirty = 0;
+            state.last_save = helper::now_unix_ms() / 1000;
+            println!("[info] db saved on disk");
+            b"+OK\r\n".to_vec()
+        }
+        Err(e) => {
+            println!("[error] saving to {} failed: {e}", path.display());
+            format!("-ERR saving to {} failed: {e}\r\n", path.display()).into_bytes()
+        }
+    }
+}
+
+// handle bgsave cmd
+pub fn handle_bgsave(
+    dbs: &types::SharedDatabases,
+    dir: &Arc<Mutex<Option<String>>>,
+    dbfilename: &Arc<Mutex<Option<String>>>,
+    save_state: &types::SharedSaveState,
+) -> Vec<u8> {
+    match helper::start_bgsave(dbs, dir, dbfilename, save_state) {
+        Ok(()) => b"+Background saving started\r\n".to_vec(),
+        Err(e) => format!("-ERR {e}\r\n").into_bytes(),
+    }
+}
+
+// handle bgrewriteaof cmd
+pub fn handle_bgrewriteaof(aof: &aof::SharedAof, dbs: &types::SharedDatabases) -> Vec<u8> {
+    match aof::start_rewrite(aof, dbs) {
+        Ok(()) => b"+Background append only file rewriting started\r\n".to_vec(),
+        Err(e) => format!("-ERR {e}\r\n").into_bytes(),
+    }
+}
+
+// handle lastsave cmd
+pub fn handle_lastsave(save_state: &types::SharedSaveState) -> Vec<u8> {
+    format!(":{}\r\n", save_state.lock().unwrap().last_save).into_bytes()
+}
+
+// handle keys cmd
+pub fn handle_keys(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 2 {
+        return b"-ERR wrong number of arguments for 'keys' command\r\n".to_vec();
+    }
+
+    let (s, _) = &**store;
+    let map = s.lock().unwrap();
+
+    let pattern = &elems[1];
+    let k_arr: Vec<Vec<u8>> = map
+        .iter()
+        .filter(|(key, entry)| !entry.is_expired() && helper::glob_match(pattern, key))
+        .map(|(key, _)| key.to_vec())
+        .collect();
+
+    return helper::elements_arr_to_resp_arr(&k_arr);
+}
+
+// handle scan cmd
+pub fn handle_scan(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let args = match helper::parse_scan_args(&elems[1..], "scan") {
+        Ok(args) => args,
+        Err(resp) => return resp,
+    };
+
+    let (s, _) = &**store;
+    let map = s.lock().unwrap();
+
+    let (cursor, page) = map.scan_order().page(args.cursor, args.count);
+
+    // filters apply to the page, so it may come back with fewer keys or none
+    let keys: Vec<Vec<u8>> = page
+        .into_iter()
+        .map(|key| (key, &map[key]))
+        .filter(|(_, entry)| !entry.is_expired())
+        .filter(|(key, _)| {
+            args.pattern
+                .as_ref()
+                .is_none_or(|p| helper::glob_match(p, key))
+        })
+        .filter(|(_, entry)| {
+            args.type_name
+                .as_ref()
+                .is_none_or(|t| t.eq_ignore_ascii_case(entry.value.type_name()))
+        })
+        .map(|(key, _)| key.to_vec())
+        .collect();
+
+    helper::scan_reply(cursor, &keys)
+}
+
+// handle hscan, sscan and zscan cmds
+pub fn handle_collection_scan(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let cmd = helper::cmd_name(&elems[0]);
+    if elems.len() < 3 {
+        return format!("-ERR wrong number of arguments for '{}' command\r\n", cmd).into_bytes();
+    }
+
+    let args = match helper::parse_scan_args(&elems[2..], &cmd) {
+        Ok(args) => args,
+        Err(resp) => return resp,
+    };
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let Some(entry) = helper::get_live_entry(&mut map, &elems[1]) else {
+        return helper::scan_reply(0, &[]);
+    };
+
+    let matches = |element: &[u8]| {
+        args.pattern
+            .as_ref()
+            .is_none_or(|p| helper::glob_match(p, element))
+    };
+
+    // (element, its value for hashes and sorted sets) in the order they are sent
+    let (cursor, page) = match (cmd.as_str(), &entry.value) {
+        ("hscan", types::StoredValue::Hash(hash)) => {
+            let (cursor, fields) = hash.scan_order().page(args.cursor, args.count);
+            let page: Vec<_> = fields
+                .into_iter()
+                .map(|field| (field, Some(hash[field].clone())))
+                .collect();
+            (cursor, page)
+        }
+        ("sscan", types::StoredValue::Set(set)) => {
+            let (cursor, members) = set.scan_order().page(args.cursor, args.count);
+            (
+                cursor,
+                members.into_iter().map(|member| (member, None)).collect(),
+            )
+        }
+        ("zscan", types::StoredValue::ZSet(zset)) => {
+            let (cursor, members) = zset.scan_order.page(args.cursor, args.count);
+            let page = members
+                .into_iter()
+                .map(|member| (member, Some(zset.scores[member].to_string().into_bytes())))
+                .collect();
+            (cursor, page)
+        }
+        _ => return helper::WRONGTYPE.to_vec(),
+    };
+
+    let mut items = Vec::new();
+    for (element, value) in page.into_iter().filter(|(element, _)| matches(element)) {
+        items.push(element.clone());
+        if let Some(value) = value.filter(|_| !args.novalues) {
+            items.push(value);
+        }
+    }
+
+    helper::scan_reply(cursor, &items)
+}
+
+// handle del cmd
+pub fn handle_del(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 2 {
+        return b"-ERR wrong number of arguments for 'del' command\r\n".to_vec();
+    }
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let mut deleted = 0;
+    for key in &elems[1..] {
+        if helper::get_live_entry(&mut map, key).is_some() {
+            map.remove(key);
+            deleted += 1;
+        }
+    }
+
+    format!(":{}\r\n", deleted).into_bytes()
+}
+
+// handle unlink cmd
+pub fn handle_unlink(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 2 {
+        return b"-ERR wrong number of arguments for 'unlink' command\r\n".to_vec();
+    }
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let mut unlinked = Vec::new();
+    for key in &elems[1..] {
+        if helper::get_live_entry(&mut map, key).is_some() {
+            unlinked.extend(map.remove(key));
+        }
+    }
+    drop(map);
+
+    // the keys are already gone, big values are freed off this thread
+    let deleted = unlinked.len();
+    for entry in unlinked {
+        helper::lazy_free(entry);
+    }
+
+    format!(":{}\r\n", deleted).into_bytes()
+}
+
+// handle exists cmd
+pub fn handle_exists(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() < 2 {
+        return b"-ERR wrong number of arguments for 'exists' command\r\n".to_vec();
+    }
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    // a key given more than once is counted every time
+    let count = elems[1..]
+        .iter()
+        .filter(|key| helper::get_live_entry(&mut map, key).is_some())
+        .count();
 
-        return "-ERR entry is not a stream".to_string();
+    format!(":{}\r\n", count).into_bytes()
+}
 
-        // stream doesn't exists
-    } else {
-        return "-ERR stream with given key doesn't exists".to_string();
+// handle rename and renamenx cmds
+pub fn handle_rename(elems: Vec<Vec<u8>>, store: &types::SharedStore, nx: bool) -> Vec<u8> {
+    if elems.len() != 3 {
+        let cmd = if nx { "renamenx" } else { "rename" };
+        return format!("-ERR wrong number of arguments for '{}' command\r\n", cmd).into_bytes();
     }
-}
 
-// handle xread
-pub fn handle_xread(elems: &mut Vec<String>, store: &types::SharedStore) -> String {
-    // $ will pass nonetheless, anything will pass in place of entry ids
-    // blocking will always happen irrespective of whatever data is already present or not
-    // blocking if stream is not available or entries in it are not available
     let (s, cvar) = &**store;
     let mut map = s.lock().unwrap();
 
-    if elems[1].to_ascii_lowercase() == "block" {
-        let _ = elems.remove(1);
-
-        // since above element was removed, following is basically elems[2]
-        let block_time = match elems.remove(1).parse::<u64>().unwrap() {
-            0 => u64::MAX,
-            t => t,
-        };
-
-        // this adjusts the start ids by mutating them in elements array
-        helper::adjust_xread_start_ids(&map, elems);
+    let (src, dst) = (&elems[1], &elems[2]);
 
-        (map, _) = cvar
-            .wait_timeout(map, Duration::from_millis(block_time))
-            .unwrap();
+    if helper::get_live_entry(&mut map, src).is_none() {
+        return b"-ERR no such key\r\n".to_vec();
     }
 
-    let (final_array_data_of_streams, no_of_valid_streams) = helper::get_streams_array(&map, elems);
+    if nx && helper::get_live_entry(&mut map, dst).is_some() {
+        return b":0\r\n".to_vec();
+    }
 
-    if no_of_valid_streams != 0 {
-        let final_array_data_of_streams_head = format!("*{}\r\n", no_of_valid_streams);
+    // the ttl moves along with the value
+    if src != dst {
+        let entry = map.remove(src).unwrap();
+        map.insert(dst.clone(), entry);
+    }
 
-        let data_to_send = format!(
-            "{}{}",
-            final_array_data_of_streams_head, final_array_data_of_streams
-        );
+    // a list may have appeared under a key blpop is waiting on
+    cvar.notify_all();
 
-        return data_to_send;
+    if nx {
+        b":1\r\n".to_vec()
     } else {
-        return "*-1\r\n".to_string();
+        b"+OK\r\n".to_vec()
     }
 }
 
-// handle incr cmd
-pub fn handle_incr(elems: &mut Vec<String>, store: &types::SharedStore) -> String {
-    let (s, _) = &**store;
-    let mut map = s.lock().unwrap();
-    if let Some(val) = map.get_mut(&elems[1]) {
-        match &mut val.value {
-            types::StoredValue::String(string_var) => match string_var.parse::<u32>() {
-                Ok(n) => {
-                    let updated_num = n + 1;
-
-                    *string_var = updated_num.to_string();
+// handle copy cmd
+pub fn handle_copy(elems: Vec<Vec<u8>>, dbs: &types::SharedDatabases, db: usize) -> Vec<u8> {
+    if elems.len() < 3 {
+        return b"-ERR wrong number of arguments for 'copy' command\r\n".to_vec();
+    }
 
-                    let data_to_send = format!(":{}\r\n", updated_num);
-                    return data_to_send;
-                }
-                Err(_) => {
-                    let data_to_send = "-ERR value is not an integer or out of range\r\n";
-                    return data_to_send.to_string();
-                }
-            },
-            _ => {
-                return "unused".to_string();
+    let mut replace = false;
+    let mut dst_db = db;
+    let mut i = 3;
+    while i < elems.len() {
+        match helper::cmd_name(&elems[i]).as_str() {
+            "replace" => replace = true,
+            "db" if i + 1 < elems.len() => {
+                dst_db = match parse_db_index(&elems[i + 1], dbs) {
+                    Ok(index) => index,
+                    Err(e) => return e,
+                };
+                i += 1;
             }
+            _ => return b"-ERR syntax error\r\n".to_vec(),
         }
-    } else {
-        let value_entry = types::ValueEntry {
-            value: types::StoredValue::String("1".to_string()),
-            expires_at: None,
-        };
-        map.insert(elems[1].clone(), value_entry);
-        let data_to_send = ":1\r\n";
-
-        return data_to_send.to_string();
+        i += 1;
     }
-}
 
-// handle multi cmd
-pub fn handle_multi(
-    stream: &mut TcpStream,
-    store: &types::SharedStore,
-    main_list_store: &types::SharedMainList,
-) {
-    let mut cmd_buf = [0; 512];
-    let _ = stream.write_all(b"+OK\r\n");
+    let (src, dst) = (&elems[1], &elems[2]);
+
+    if src == dst && dst_db == db {
+        return b"-ERR source and destination objects are the same\r\n".to_vec();
+    }
 
-    let mut cmds: Vec<Vec<String>> = Vec::new();
+    if dst_db == db {
+        let (s, cvar) = &*dbs[db];
+        let mut map = s.lock().unwrap();
 
-    loop {
-        match stream.read(&mut cmd_buf) {
-            Ok(0) => {
-                println!("[info] connection disconnected");
-                return;
-            }
+        let Some(entry) = helper::get_live_entry(&mut map, src) else {
+            return b":0\r\n".to_vec();
+        };
+        let copy = types::ValueEntry::with_expiry(entry.value.clone(), entry.expires_at);
 
-            Ok(_) => {
-                let (no_of_elements, counter) = helper::parse_number(&cmd_buf, 0);
+        if !replace && helper::get_live_entry(&mut map, dst).is_some() {
+            return b":0\r\n".to_vec();
+        }
 
-                let (cmd_array, _) = helper::parsing_elements(&cmd_buf, counter, no_of_elements);
+        map.insert(dst.clone(), copy);
 
-                if cmd_array[0].to_ascii_lowercase() == "exec" {
-                    let response = helper::handle_exec_under_multi(&cmds, store, main_list_store);
-                    let _ = stream.write_all(response.as_bytes());
-                    break;
-                } else if cmd_array[0].to_ascii_lowercase() == "discard" {
-                    let _ = stream.write_all("+OK\r\n".as_bytes());
-                    break;
-                }
+        // a list may have appeared under a key blpop is waiting on
+        cvar.notify_all();
+    } else {
+        let (mut src_map, mut dst_map) = helper::lock_pair(dbs, db, dst_db);
 
-                cmds.push(cmd_array);
+        let Some(entry) = helper::get_live_entry(&mut src_map, src) else {
+            return b":0\r\n".to_vec();
+        };
+        let copy = types::ValueEntry::with_expiry(entry.value.clone(), entry.expires_at);
 
-                let _ = stream.write_all(b"+QUEUED\r\n");
-            }
-            Err(e) => {
-                println!("[error] error reading stream: {e}");
-            }
+        if !replace && helper::is_live_key(&dst_map, dst) {
+            return b":0\r\n".to_vec();
         }
+
+        dst_map.insert(dst.clone(), copy);
+        dbs[dst_db].1.notify_all();
     }
-}
 
-/// handle info command
-pub fn handle_info(elems: &Vec<String>, role: &str) -> Option<String> {
-    if elems[1] == "replication" {
-        let data = format!(
-            "role:{}\r\nmaster_repl_offset:0\r\nmaster_replid:8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb",
-            role
-        );
+    b":1\r\n".to_vec()
+}
 
-        let response = format!("${}\r\n{}\r\n", data.len(), data);
+/// index of an existing db given as argument
+fn parse_db_index(arg: &[u8], dbs: &types::SharedDatabases) -> Result<usize, Vec<u8>> {
+    let Some(index) = helper::parse_arg::<i64>(arg) else {
+        return Err(b"-ERR value is not an integer or out of range\r\n".to_vec());
+    };
 
-        return Some(response);
+    match usize::try_from(index) {
+        Ok(index) if index < dbs.len() => Ok(index),
+        _ => Err(b"-ERR DB index is out of range\r\n".to_vec()),
     }
-
-    None
 }
 
-/// handle replconf command
-pub fn handle_replconf(
-    elems: &Vec<String>,
-    master_repl_offset: &Arc<Mutex<usize>>,
-    shared_replica_count_clone: &Arc<Mutex<usize>>,
-) -> Option<String> {
-    if elems[1].to_ascii_lowercase() == "ack" {
-        let master_offset = *master_repl_offset.lock().unwrap();
-        let replica_offset = elems[2].parse::<usize>().unwrap();
+// handle select cmd
+pub fn handle_select(elems: &[Vec<u8>], dbs: &types::SharedDatabases, db: &mut usize) -> Vec<u8> {
+    if elems.len() != 2 {
+        return b"-ERR wrong number of arguments for 'select' command\r\n".to_vec();
+    }
 
-        if replica_offset >= master_offset {
-            *shared_replica_count_clone.lock().unwrap() += 1;
+    match parse_db_index(&elems[1], dbs) {
+        Ok(index) => {
+            *db = index;
+            b"+OK\r\n".to_vec()
         }
-
-        None
-    } else {
-        Some("+OK\r\n".to_string())
+        Err(e) => e,
     }
 }
 
-// handle psync cmd
-pub fn handle_psync(stream: &mut TcpStream, tcpstream_vector_clone: &Arc<Mutex<Vec<TcpStream>>>) {
-    let _ = stream.write_all(b"+FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0\r\n");
+// handle move cmd
+pub fn handle_move(elems: Vec<Vec<u8>>, dbs: &types::SharedDatabases, db: usize) -> Vec<u8> {
+    if elems.len() != 3 {
+        return b"-ERR wrong number of arguments for 'move' command\r\n".to_vec();
+    }
+
+    let dst_db = match parse_db_index(&elems[2], dbs) {
+        Ok(index) => index,
+        Err(e) => return e,
+    };
+    if dst_db == db {
+        return b"-ERR source and destination objects are the same\r\n".to_vec();
+    }
 
-    let rdb_file_bytes = fs::read("rdbfile.rdb").unwrap();
-    let header = format!("${}\r\n", rdb_file_bytes.len());
+    let key = &elems[1];
+    let (mut src_map, mut dst_map) = helper::lock_pair(dbs, db, dst_db);
+
+    // nothing moves over a key that already exists in the other db
+    if helper::get_live_entry(&mut src_map, key).is_none() || helper::is_live_key(&dst_map, key) {
+        return b":0\r\n".to_vec();
+    }
 
-    let _ = stream.write_all(header.as_bytes());
-    let _ = stream.write_all(&rdb_file_bytes);
+    // the ttl moves along with the value
+    let entry = src_map.remove(key).unwrap();
+    dst_map.insert(key.clone(), entry);
 
-    // assuming slave doesn't send psync again
-    let mut tcp_vecc = tcpstream_vector_clone.lock().unwrap();
-    tcp_vecc.push(stream.try_clone().unwrap());
+    // a list may have appeared under a key blpop is waiting on
+    dbs[dst_db].1.notify_all();
+    b":1\r\n".to_vec()
 }
 
-// handle wait cmd
-pub fn handle_wait(
-    elems: &Vec<String>,
-    tcpstream_vector_clone: &Arc<Mutex<Vec<TcpStream>>>,
-    master_repl_offset: &Arc<Mutex<usize>>,
-    shared_replica_count_clone: &Arc<Mutex<usize>>,
-) -> String {
-    let replica_needed: usize = elems[1].parse().unwrap();
-    let timeout_ms: u64 = elems[2].parse().unwrap();
+// handle swapdb cmd
+pub fn handle_swapdb(elems: Vec<Vec<u8>>, dbs: &types::SharedDatabases) -> Vec<u8> {
+    if elems.len() != 3 {
+        return b"-ERR wrong number of arguments for 'swapdb' command\r\n".to_vec();
+    }
 
-    let mut streams: Vec<TcpStream> = {
-        let guard = tcpstream_vector_clone.lock().unwrap();
-        guard.iter().map(|s| s.try_clone().unwrap()).collect()
+    let Some(first) = helper::parse_arg::<i64>(&elems[1]) else {
+        return b"-ERR invalid first DB index\r\n".to_vec();
+    };
+    let Some(second) = helper::parse_arg::<i64>(&elems[2]) else {
+        return b"-ERR invalid second DB index\r\n".to_vec();
+    };
+    let in_range = |index: i64| {
+        usize::try_from(index)
+            .ok()
+            .filter(|index| *index < dbs.len())
+    };
+    let (Some(first), Some(second)) = (in_range(first), in_range(second)) else {
+        return b"-ERR DB index is out of range\r\n".to_vec();
     };
 
-    if streams.len() == 0 {
-        return ":0\r\n".to_string();
+    if first != second {
+        let (mut first_map, mut second_map) = helper::lock_pair(dbs, first, second);
+        std::mem::swap(&mut *first_map, &mut *second_map);
+
+        // clients blocked in either db now wait on the other one's keys
+        dbs[first].1.notify_all();
+        dbs[second].1.notify_all();
     }
 
-    let master_offset = *master_repl_offset.lock().unwrap();
+    b"+OK\r\n".to_vec()
+}
 
-    if master_offset == 0 {
-        let data = format!(":{}\r\n", streams.len());
-        return data;
+/// true for FLUSHDB/FLUSHALL ASYNC, false for SYNC or no option
+fn parse_flush_mode(elems: &[Vec<u8>]) -> Result<bool, Vec<u8>> {
+    if elems.len() > 2 {
+        return Err(b"-ERR syntax error\r\n".to_vec());
     }
 
-    // let initial_synced = streams.len(); // replicas already connected & synced
+    match elems
+        .get(1)
+        .map(|option| helper::cmd_name(option))
+        .as_deref()
+    {
+        None | Some("sync") => Ok(false),
+        Some("async") => Ok(true),
+        Some(_) => Err(b"-ERR syntax error\r\n".to_vec()),
+    }
+}
 
-    let getack_cmd = b"*3\r\n$8\r\nREPLCONF\r\n$6\r\nGETACK\r\n$1\r\n*\r\n";
+// handle flushdb cmd
+pub fn handle_flushdb(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let is_async = match parse_flush_mode(&elems) {
+        Ok(is_async) => is_async,
+        Err(e) => return e,
+    };
 
-    let streams_len = streams.len();
+    let (s, _) = &**store;
+    let keyspace = std::mem::take(&mut *s.lock().unwrap());
 
-    for slave in &mut streams {
-        println!("[info] sent getack to salve: {:?}", slave);
-        let _ = slave.write_all(getack_cmd);
+    // the db is already empty, with ASYNC its old contents are freed off this thread
+    if is_async {
+        helper::lazy_free_keyspace(keyspace);
     }
 
-    // waiting for
+    b"+OK\r\n".to_vec()
+}
 
-    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
-    let response = loop {
-        let replica_count = *shared_replica_count_clone.lock().unwrap();
+// handle flushall cmd
+pub fn handle_flushall(elems: Vec<Vec<u8>>, dbs: &types::SharedDatabases) -> Vec<u8> {
+    let is_async = match parse_flush_mode(&elems) {
+        Ok(is_async) => is_async,
+        Err(e) => return e,
+    };
 
-        if Instant::now() > deadline {
-            break format!(":{}\r\n", replica_count);
-        }
+    // all of them are emptied at once
+    let mut maps: Vec<_> = dbs.iter().map(|db| db.0.lock().unwrap()).collect();
+    let keyspaces: Vec<_> = maps
+        .iter_mut()
+        .map(|map| std::mem::take(&mut **map))
+        .collect();
+    drop(maps);
 
-        if replica_count >= replica_needed {
-            break format!(":{}\r\n", replica_count);
+    if is_async {
+        for keyspace in keyspaces {
+            helper::lazy_free_keyspace(keyspace);
         }
+    }
 
-        if replica_count == streams_len {
-            break format!(":{}\r\n", replica_count);
-        }
+    b"+OK\r\n".to_vec()
+}
 
-        thread::sleep(Duration::from_millis(10));
-    };
+// handle dbsize cmd
+pub fn handle_dbsize(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 1 {
+        return b"-ERR wrong number of arguments for 'dbsize' command\r\n".to_vec();
+    }
 
-    *shared_replica_count_clone.lock().unwrap() = 0;
-    *master_repl_offset.lock().unwrap() += getack_cmd.len();
+    let (s, _) = &**store;
+    let map = s.lock().unwrap();
 
-    return response;
+    let count = map.values().filter(|entry| !entry.is_expired()).count();
+    format!(":{}\r\n", count).into_bytes()
 }
 
-// handle config cmd
-pub fn handle_config(
-    dir_clone: &Arc<Mutex<Option<String>>>,
-    dbfilename_clone: &Arc<Mutex<Option<String>>>,
-    elems: Vec<String>,
-) -> String {
-    if elems[1].to_ascii_lowercase() == "get" {
-        match elems[2].to_ascii_lowercase().as_str() {
-            "dir" => {
-                let dir = dir_clone.lock().unwrap();
-                if let Some(dir) = dir.as_ref() {
-                    let arr = vec!["dir".to_string(), dir.clone()];
-                    return helper::elements_arr_to_resp_arr(&arr);
-                } else {
-                    "-ERR error getting dir".to_string()
-                }
-            }
-            "dbfilename" => {
-                let dbfilename = &dbfilename_clone.lock().unwrap();
-                if let Some(dbfilename) = dbfilename.as_ref() {
-                    let arr = vec![dbfilename.clone(), "dbfilename".to_string()];
-                    return helper::elements_arr_to_resp_arr(&arr);
-                } else {
-                    "-ERR error getting dbfilename".to_string()
-                }
+// handle randomkey cmd
+pub fn handle_randomkey(store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let map = s.lock().unwrap();
+
+    // a read only hides expired keys, after this many of them in a row the
+    // keyspace is taken as empty
+    const MAX_TRIES: usize = 100;
+
+    for _ in 0..MAX_TRIES {
+        let Some(key) = map.sample_keys(1).pop() else {
+            break;
+        };
+        if map.get(&key).is_some_and(|entry| !entry.is_expired()) {
+            return helper::bulk_string(&key);
+        }
+    }
+
+    b"$-1\r\n".to_vec()
+}
+
+// handle expire, pexpire, expireat and pexpireat cmds
+pub fn handle_expire(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let cmd = helper::cmd_name(&elems[0]);
+    if elems.len() < 3 {
+        return format!("-ERR wrong number of arguments for '{}' command\r\n", cmd).into_bytes();
+    }
+
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    for option in &elems[3..] {
+        match helper::cmd_name(option).as_str() {
+            "nx" => nx = true,
+            "xx" => xx = true,
+            "gt" => gt = true,
+            "lt" => lt = true,
+            _ => {
+                return format!(
+                    "-ERR Unsupported option {}\r\n",
+                    String::from_utf8_lossy(option)
+                )
+                .into_bytes();
             }
-            _ => "-ERR wrong arguement".to_string(),
         }
+    }
+
+    if nx && (xx || gt || lt) {
+        return b"-ERR NX and XX, GT or LT options at the same time are not compatible\r\n"
+            .to_vec();
+    }
+    if gt && lt {
+        return b"-ERR GT and LT options at the same time are not compatible\r\n".to_vec();
+    }
+
+    if helper::parse_arg::<i64>(&elems[2]).is_none() {
+        return b"-ERR value is not an integer or out of range\r\n".to_vec();
+    }
+    let Some(target) = helper::expire_target_ms(&cmd, &elems[2]) else {
+        return format!("-ERR invalid expire time in '{}' command\r\n", cmd).into_bytes();
+    };
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let key = &elems[1];
+    let Some(entry) = helper::get_live_entry(&mut map, key) else {
+        return b":0\r\n".to_vec();
+    };
+
+    // a key without a ttl counts as one that never expires
+    let current = entry.expires_at.map_or(i64::MAX, |unix_ms| unix_ms as i64);
+    let allowed = if nx {
+        entry.expires_at.is_none()
+    } else if xx && entry.expires_at.is_none() {
+        false
+    } else if gt {
+        target > current
+    } else if lt {
+        target < current
     } else {
-        "-ERR not a GET command".to_string()
+        true
+    };
+    if !allowed {
+        return b":0\r\n".to_vec();
     }
-}
 
-// handle keys cmd
-pub fn handle_keys(
-    dir_clone: &Arc<Mutex<Option<String>>>,
-    dbfilename_clone: &Arc<Mutex<Option<String>>>,
-    elems: Vec<String>,
-) -> String {
-    let dir = &*dir_clone.lock().unwrap();
-    let dbfilename = &*dbfilename_clone.lock().unwrap();
+    helper::record_stored_expiry(target.max(0) as u64);
+    // a time in the past deletes the key right away
+    if target <= helper::now_unix_ms() as i64 && !helper::is_replaying() {
+        map.remove(key);
+    } else {
+        map.set_expiry(key, Some(target.max(0) as u64));
+    }
+
+    b":1\r\n".to_vec()
+}
 
-    if elems[1] != "*" {
-        return "-ERR Wrong field with KEYS command".to_string();
+// handle ttl and pttl cmds
+pub fn handle_ttl(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let cmd = helper::cmd_name(&elems[0]);
+    if elems.len() != 2 {
+        return format!("-ERR wrong number of arguments for '{}' command\r\n", cmd).into_bytes();
     }
 
-    let (Some(dir), Some(dbfilename)) = (dir.as_ref(), dbfilename.as_ref()) else {
-        return "*0\r\n\r\n".to_string();
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let ttl_ms = match helper::get_live_entry(&mut map, &elems[1]) {
+        None => return b":-2\r\n".to_vec(),
+        Some(entry) => match entry.expires_at {
+            None => return b":-1\r\n".to_vec(),
+            Some(unix_ms) => unix_ms.saturating_sub(helper::now_unix_ms()),
+        },
     };
 
-    let path = format!("{}/{}", dir, dbfilename);
+    if cmd == "ttl" {
+        // rounded to the closest second
+        format!(":{}\r\n", (ttl_ms + 500) / 1000).into_bytes()
+    } else {
+        format!(":{}\r\n", ttl_ms).into_bytes()
+    }
+}
+
+// handle expiretime and pexpiretime cmds
+pub fn handle_expiretime(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let cmd = helper::cmd_name(&elems[0]);
+    if elems.len() != 2 {
+        return format!("-ERR wrong number of arguments for '{}' command\r\n", cmd).into_bytes();
+    }
+
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    let data = match fs::read(path) {
-        Ok(data) => data,
-        Err(_) => {
-            return "*0\r\n\r\n".to_string();
-        }
+    let unix_ms = match helper::get_live_entry(&mut map, &elems[1]) {
+        None => return b":-2\r\n".to_vec(),
+        Some(entry) => match entry.expires_at {
+            None => return b":-1\r\n".to_vec(),
+            Some(unix_ms) => unix_ms,
+        },
     };
 
-    let kv_arr = helper::parse_rdb_get_kv(&data);
-    let mut k_arr: Vec<String> = Vec::new();
+    if cmd == "expiretime" {
+        format!(":{}\r\n", unix_ms / 1000).into_bytes()
+    } else {
+        format!(":{}\r\n", unix_ms).into_bytes()
+    }
+}
 
-    for i in kv_arr {
-        k_arr.push(i[0].clone());
+// handle persist cmd
+pub fn handle_persist(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    if elems.len() != 2 {
+        return b"-ERR wrong number of arguments for 'persist' command\r\n".to_vec();
     }
 
-    return helper::elements_arr_to_resp_arr(&k_arr);
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
+
+    let persisted = helper::get_live_entry(&mut map, &elems[1]).is_some()
+        && map.set_expiry(&elems[1], None).is_some();
+
+    if persisted {
+        b":1\r\n".to_vec()
+    } else {
+        b":0\r\n".to_vec()
+    }
 }
 
 // handle subscribe cmd
 pub fn handle_subscribe(
-    elems: Vec<String>,
+    elems: Vec<Vec<u8>>,
     is_subscribed: &mut bool,
-    channels_subscribed: &Arc<Mutex<Vec<String>>>,
-    subs_htable: &Arc<Mutex<HashMap<String, Vec<TcpStream>>>>,
+    channels_subscribed: &Arc<Mutex<Vec<Vec<u8>>>>,
+    subs_htable: &Arc<Mutex<HashMap<Vec<u8>, Vec<TcpStream>>>>,
     stream: &mut TcpStream,
-) -> String {
+) -> Vec<u8> {
     let channel = &elems[1];
     let mut map = subs_htable.lock().unwrap();
 
@@ -785,14 +2734,10 @@ pub fn handle_subscribe(
         subscribers.push(stream.try_clone().unwrap());
     }
 
-    let resp = format!(
-        "*3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n",
-        "subscribe".len(),
-        "subscribe".to_string(),
-        channel.len(),
-        channel.clone(),
-        subs_channels.len()
-    );
+    let mut resp = b"*3\r\n".to_vec();
+    resp.extend(helper::bulk_string(b"subscribe"));
+    resp.extend(helper::bulk_string(channel));
+    resp.extend(format!(":{}\r\n", subs_channels.len()).as_bytes());
 
     *is_subscribed = true;
 
@@ -801,124 +2746,112 @@ pub fn handle_subscribe(
 
 // handle publish cmd
 pub fn handle_publish(
-    elems: Vec<String>,
-    subs_htable: &Arc<Mutex<HashMap<String, Vec<TcpStream>>>>,
-) -> String {
+    elems: Vec<Vec<u8>>,
+    subs_htable: &Arc<Mutex<HashMap<Vec<u8>, Vec<TcpStream>>>>,
+) -> Vec<u8> {
     let mut map = subs_htable.lock().unwrap();
 
-    if let Some(subscribers) = map.get_mut(&elems[1]) {
-        let subs_len = subscribers.len();
-        for subscriber in subscribers {
-            let arr = vec!["message".to_string(), elems[1].clone(), elems[2].clone()];
-            let resp = helper::elements_arr_to_resp_arr(&arr);
-            let _ = subscriber.write_all(resp.as_bytes());
-        }
-        format!(":{}\r\n", subs_len)
-    } else {
-        "-ERR channel doesn't exists".to_string()
+    // a channel nobody listens to reaches 0 subscribers
+    let subscribers = map
+        .get_mut(&elems[1])
+        .map(Vec::as_mut_slice)
+        .unwrap_or_default();
+    for subscriber in subscribers.iter_mut() {
+        let arr = vec![b"message".to_vec(), elems[1].clone(), elems[2].clone()];
+        let resp = helper::elements_arr_to_resp_arr(&arr);
+        let _ = subscriber.write_all(&resp);
     }
+    format!(":{}\r\n", subscribers.len()).into_bytes()
 }
 
 // handle unsubscribe cmd
 pub fn handle_unsubscribe(
-    elems: Vec<String>,
-    subs_htable: &Arc<Mutex<HashMap<String, Vec<TcpStream>>>>,
-    channels_subscribed: &Arc<Mutex<Vec<String>>>,
+    elems: Vec<Vec<u8>>,
+    subs_htable: &Arc<Mutex<HashMap<Vec<u8>, Vec<TcpStream>>>>,
+    channels_subscribed: &Arc<Mutex<Vec<Vec<u8>>>>,
     stream: &mut TcpStream,
-) -> String {
+) -> Vec<u8> {
     let mut map = subs_htable.lock().unwrap();
     let mut subs_channels = channels_subscribed.lock().unwrap();
-    let chan = &elems[1];
 
-    // remove from channels list
-    let index = subs_channels
-        .iter()
-        .position(|channel| channel == chan)
-        .unwrap();
-    subs_channels.remove(index);
+    // without channels it unsubscribes from all of them
+    let channels: Vec<Vec<u8>> = if elems.len() > 1 {
+        elems[1..].to_vec()
+    } else {
+        subs_channels.clone()
+    };
+    if channels.is_empty() {
+        return b"*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:0\r\n".to_vec();
+    }
 
-    // remove from subs_htable
+    let addr = stream.peer_addr().ok();
+    let mut resp = Vec::new();
+    for chan in &channels {
+        // remove from channels list
+        if let Some(index) = subs_channels.iter().position(|channel| channel == chan) {
+            subs_channels.remove(index);
+        }
 
-    if let Some(subscribers) = map.get_mut(chan) {
-        let index = subscribers
-            .iter()
-            .position(|subs| subs.peer_addr().unwrap() == stream.peer_addr().unwrap())
-            .unwrap();
-        subscribers.remove(index);
-
-        let resp = format!(
-            "*3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n",
-            "unsubscribe".len(),
-            "unsubscribe",
-            &elems[1].len(),
-            &elems[1],
-            subs_channels.len()
-        );
+        // remove from subs_htable
+        if let Some(subscribers) = map.get_mut(chan) {
+            subscribers.retain(|subs| subs.peer_addr().ok() != addr);
+        }
 
-        resp
-    } else {
-        "-ERR channel doesn't exists".to_string()
+        resp.extend(b"*3\r\n");
+        resp.extend(helper::bulk_string(b"unsubscribe"));
+        resp.extend(helper::bulk_string(chan));
+        resp.extend(format!(":{}\r\n", subs_channels.len()).as_bytes());
     }
+
+    resp
 }
 
 // handle zadd cmd
-pub fn handle_zadd(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
-    let mut hmap = zset_hmap.lock().unwrap();
+pub fn handle_zadd(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
     let zset_key = &elems[1];
-    let score = elems[2].parse::<f64>().unwrap();
+    let Some(score) = helper::parse_arg::<f64>(&elems[2]) else {
+        return b"-ERR value is not a valid float\r\n".to_vec();
+    };
     let member = &elems[3];
 
-    let ordered_score = OrderedFloat(score);
-
-    let zset = hmap
-        .entry(zset_key.clone())
-        .or_insert_with(|| types::ZSet::new());
-
-    let is_new = match zset.scores.insert(member.clone(), score) {
-        Some(old_score) => {
-            zset.ordered
-                .remove(&(OrderedFloat(old_score), member.clone()));
-            false
-        }
-        None => true,
+    let Some(zset) = helper::get_or_create_zset(&mut map, zset_key) else {
+        return helper::WRONGTYPE.to_vec();
     };
 
-    zset.ordered.insert((ordered_score, member.clone()));
+    let is_new = zset.insert(member.clone(), score);
 
     let resp = if is_new { ":1\r\n" } else { ":0\r\n" };
 
-    resp.to_string()
+    resp.as_bytes().to_vec()
 }
 
 // handle zrank cmd
-pub fn handle_zrank(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
+pub fn handle_zrank(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
     let zset_key = &elems[1];
     let member = &elems[2];
-    let hmap = zset_hmap.lock().unwrap();
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    let zset = match hmap.get(zset_key) {
-        Some(z) => z,
+    let zset = match helper::get_live_entry(&mut map, zset_key).map(|e| &mut e.value) {
+        Some(types::StoredValue::ZSet(z)) => z,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
         None => {
-            return "$-1\r\n".to_string();
+            return b"$-1\r\n".to_vec();
         }
     };
 
     let score = *match zset.scores.get(member) {
         Some(score) => score,
         None => {
-            return "$-1\r\n".to_string();
+            return b"$-1\r\n".to_vec();
         }
     };
 
-    let start = (OrderedFloat(score), "".to_string());
-    let end = (OrderedFloat(score + 1.0), "".to_string());
+    let start = (OrderedFloat(score), Vec::new());
+    let end = (OrderedFloat(score + 1.0), Vec::new());
 
     let base_rank = zset.ordered.range(..start.clone()).count();
 
@@ -926,31 +2859,34 @@ pub fn handle_zrank(
         .ordered
         .range(start..end)
         .position(|(_, m)| m == member)
-        .map(|idx| format!(":{}\r\n", base_rank + idx))
-        .unwrap_or_else(|| "$-1\r\n".to_string());
+        .map(|idx| format!(":{}\r\n", base_rank + idx).into_bytes())
+        .unwrap_or_else(|| b"$-1\r\n".to_vec());
 
     resp
 }
 
 // handle zrange cmd
-pub fn handle_zrange(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
+pub fn handle_zrange(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
     let zset_key = &elems[1];
-    let mut hmap = zset_hmap.lock().unwrap();
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    let mut start = elems[2].parse::<i32>().unwrap();
-    let mut end = elems[3].parse::<i32>().unwrap();
+    let (Some(mut start), Some(mut end)) = (
+        helper::parse_arg::<i64>(&elems[2]),
+        helper::parse_arg::<i64>(&elems[3]),
+    ) else {
+        return b"-ERR value is not an integer or out of range\r\n".to_vec();
+    };
 
-    let zset = match hmap.get_mut(zset_key) {
-        Some(z) => z,
+    let zset = match helper::get_live_entry(&mut map, zset_key).map(|e| &mut e.value) {
+        Some(types::StoredValue::ZSet(z)) => z,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
         None => {
-            return "*0\r\n".to_string();
+            return b"*0\r\n".to_vec();
         }
     };
 
-    let sorted_set_len = zset.ordered.len() as i32;
+    let sorted_set_len = zset.ordered.len() as i64;
 
     // ensuring start and end are valid
 
@@ -971,7 +2907,7 @@ pub fn handle_zrange(
     }
 
     if start >= sorted_set_len {
-        return "*0\r\n".to_string();
+        return b"*0\r\n".to_vec();
     }
 
     if end > sorted_set_len {
@@ -979,12 +2915,12 @@ pub fn handle_zrange(
     }
 
     if start > end {
-        return "*0\r\n".to_string();
+        return b"*0\r\n".to_vec();
     }
 
     let start = start as usize;
     let end = end as usize;
-    let vec: Vec<String> = zset
+    let vec: Vec<Vec<u8>> = zset
         .ordered
         .iter()
         .skip(start)
@@ -997,137 +2933,130 @@ pub fn handle_zrange(
 }
 
 // handle zcard cmd
-pub fn handle_zcard(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
+pub fn handle_zcard(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
     let zset_key = &elems[1];
-    let mut hmap = zset_hmap.lock().unwrap();
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    let zset = match hmap.get_mut(zset_key) {
-        Some(z) => z,
+    let zset = match helper::get_live_entry(&mut map, zset_key).map(|e| &mut e.value) {
+        Some(types::StoredValue::ZSet(z)) => z,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
         None => {
-            return ":0\r\n".to_string();
+            return b":0\r\n".to_vec();
         }
     };
 
     let sorted_set_len = zset.ordered.len();
     let resp = format!(":{}\r\n", sorted_set_len);
 
-    resp
+    resp.into_bytes()
 }
 
 // handle zscore cmd
-pub fn handle_zscore(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
+pub fn handle_zscore(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
     let zset_key = &elems[1];
     let member = &elems[2];
 
-    let mut hmap = zset_hmap.lock().unwrap();
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    let zset = match hmap.get_mut(zset_key) {
-        Some(z) => z,
+    let zset = match helper::get_live_entry(&mut map, zset_key).map(|e| &mut e.value) {
+        Some(types::StoredValue::ZSet(z)) => z,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
         None => {
-            return "$-1\r\n".to_string();
+            return b"$-1\r\n".to_vec();
         }
     };
 
     let resp = match zset.scores.get(member) {
         Some(s) => {
             let score = s.to_string();
-            format!("${}\r\n{}\r\n", score.len(), score)
+            helper::bulk_string(score.as_bytes())
         }
-        None => "$-1\r\n".to_string(),
+        None => b"$-1\r\n".to_vec(),
     };
     resp
 }
 
 // handle zrem cmd
-pub fn handle_zrem(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
+pub fn handle_zrem(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
     let zset_key = &elems[1];
     let member = &elems[2];
 
-    let mut hmap = zset_hmap.lock().unwrap();
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
-    let zset = match hmap.get_mut(zset_key) {
-        Some(z) => z,
+    let zset = match helper::get_live_entry(&mut map, zset_key).map(|e| &mut e.value) {
+        Some(types::StoredValue::ZSet(z)) => z,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
         None => {
-            return ":0\r\n".to_string();
+            return b":0\r\n".to_vec();
         }
     };
 
-    let resp = match zset.scores.get(member) {
-        Some(score) => {
-            zset.ordered.remove(&(OrderedFloat(*score), member.clone()));
-            zset.scores.remove(member);
-
-            ":1\r\n".to_string()
-        }
-        None => ":0\r\n".to_string(),
+    let resp = if zset.remove(member) {
+        b":1\r\n".to_vec()
+    } else {
+        b":0\r\n".to_vec()
     };
 
+    // empty sorted sets don't exist
+    if zset.scores.is_empty() {
+        map.remove(zset_key);
+    }
+
     resp
 }
 
 // handle geoadd cmd
-pub fn handle_geoadd(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
-    let mut hmap = zset_hmap.lock().unwrap();
+pub fn handle_geoadd(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
     let geo_key = &elems[1];
-    let lon = elems[2].parse::<f64>().unwrap();
-    let lat = elems[3].parse::<f64>().unwrap();
+    let (Some(lon), Some(lat)) = (
+        helper::parse_arg::<f64>(&elems[2]),
+        helper::parse_arg::<f64>(&elems[3]),
+    ) else {
+        return b"-ERR value is not a valid float\r\n".to_vec();
+    };
     let place = &elems[4];
 
     if !(-180.0..=180.0).contains(&lon) || !(-85.05112878..=85.05112878).contains(&lat) {
-        return format!("-ERR invalid longitude,latitude pair {},{}\r\n", lon, lat);
+        return format!("-ERR invalid longitude,latitude pair {},{}\r\n", lon, lat).into_bytes();
     }
 
     let gscore = helper::get_score(lon, lat) as f64;
 
-    let zset = hmap
-        .entry(geo_key.clone())
-        .or_insert_with(|| types::ZSet::new());
-
-    let is_exist = match zset.scores.get(place) {
-        Some(old_gscore) => {
-            zset.ordered
-                .remove(&(OrderedFloat(*old_gscore as f64), place.clone()));
-
-            true
-        }
-        None => false,
+    let Some(zset) = helper::get_or_create_zset(&mut map, geo_key) else {
+        return helper::WRONGTYPE.to_vec();
     };
-    zset.scores.insert(place.clone(), gscore);
-    zset.ordered.insert((OrderedFloat(gscore), place.clone()));
 
-    let resp = if is_exist { ":0\r\n" } else { ":1\r\n" };
+    let is_new = zset.insert(place.clone(), gscore);
+
+    let resp = if is_new { ":1\r\n" } else { ":0\r\n" };
 
-    resp.to_string()
+    resp.as_bytes().to_vec()
 }
 
 // handle geopos cmd
-pub fn handle_geopos(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
-    let hmap = zset_hmap.lock().unwrap();
+pub fn handle_geopos(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
     let geo_key = &elems[1];
     let places = &elems[2..];
 
-    let mut coords: String = format!("*{}\r\n", places.len());
+    let zset = match helper::get_live_entry(&mut map, geo_key).map(|e| &e.value) {
+        Some(types::StoredValue::ZSet(z)) => Some(z),
+        Some(_) => return helper::WRONGTYPE.to_vec(),
+        None => None,
+    };
+
+    let mut coords = format!("*{}\r\n", places.len()).into_bytes();
 
     for place in places {
-        let resp = hmap
-            .get(geo_key)
+        let resp = zset
             .and_then(|z| z.scores.get(place))
             .map(|geocode| {
                 let (lat, lon) = helper::get_coordinates(*geocode as u64);
@@ -1144,26 +3073,25 @@ pub fn handle_geopos(
             })
             .unwrap_or_else(|| "*-1\r\n".to_string());
 
-        coords.push_str(&resp);
+        coords.extend(resp.as_bytes());
     }
 
     coords
 }
 
 // handle geodist cmd
-pub fn handle_geodist(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
-    let mut hmap = zset_hmap.lock().unwrap();
+pub fn handle_geodist(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
     let geo_key = &elems[1];
     let places = &elems[2..4];
 
-    let zset = match hmap.get_mut(geo_key) {
-        Some(z) => z,
+    let zset = match helper::get_live_entry(&mut map, geo_key).map(|e| &mut e.value) {
+        Some(types::StoredValue::ZSet(z)) => z,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
         None => {
-            return "-ERR coords do not exists".to_string();
+            return b"-ERR coords do not exists\r\n".to_vec();
         }
     };
 
@@ -1180,36 +3108,40 @@ pub fn handle_geodist(
     let coords_vec = match coords {
         Some(v) => v,
         None => {
-            return "-ERR coords do not exists".to_string();
+            return b"-ERR coords do not exists\r\n".to_vec();
         }
     };
 
     // I have the coords now
     let dist = helper::haversine(coords_vec);
 
-    let resp = format!("${}\r\n{}\r\n", dist.len(), dist);
+    let resp = helper::bulk_string(dist.as_bytes());
     resp
 }
 
 // handle geosearch cmd
-pub fn handle_geosearch(
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    elems: Vec<String>,
-) -> String {
-    let mut hmap = zset_hmap.lock().unwrap();
+pub fn handle_geosearch(elems: Vec<Vec<u8>>, store: &types::SharedStore) -> Vec<u8> {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap();
 
     let geo_key = &elems[1];
-    let coord_given = [elems[3].clone(), elems[4].clone()];
-    let dist_given = elems[6].parse::<f64>().unwrap();
+    let coord_given = [
+        String::from_utf8_lossy(&elems[3]).to_string(),
+        String::from_utf8_lossy(&elems[4]).to_string(),
+    ];
+    let Some(dist_given) = helper::parse_arg::<f64>(&elems[6]) else {
+        return b"-ERR need numeric radius\r\n".to_vec();
+    };
 
-    let zset = match hmap.get_mut(geo_key) {
-        Some(z) => z,
+    let zset = match helper::get_live_entry(&mut map, geo_key).map(|e| &mut e.value) {
+        Some(types::StoredValue::ZSet(z)) => z,
+        Some(_) => return helper::WRONGTYPE.to_vec(),
         None => {
-            return "-ERR coords do no exists".to_string();
+            return b"-ERR coords do no exists\r\n".to_vec();
         }
     };
 
-    let mut places: Vec<String> = Vec::new();
+    let mut places: Vec<Vec<u8>> = Vec::new();
 
     for (place, geo_code) in zset.scores.iter() {
         let (lat, lon) = helper::get_coordinates(*geo_code as u64);
@@ -1231,49 +3163,54 @@ pub fn handle_geosearch(
 
 // handle acl cmd
 pub fn handle_acl(
-    elems: Vec<String>,
+    elems: Vec<Vec<u8>>,
     userpw_hmap_clone: &Arc<Mutex<HashMap<String, Vec<[u8; 32]>>>>,
     user_guard: &Arc<Mutex<types::UserInfo>>,
-) -> String {
-    let flag = elems[1].to_ascii_lowercase();
+) -> Vec<u8> {
+    let flag = helper::cmd_name(&elems[1]);
 
     let mut userpw_hmap = userpw_hmap_clone.lock().unwrap();
     let mut userinfo = user_guard.lock().unwrap();
 
-    let user = elems.get(2);
+    let user = elems.get(2).map(|u| String::from_utf8_lossy(u).to_string());
 
     let resp = match flag.as_str() {
         "whoami" => match userpw_hmap.get(&userinfo.name) {
             Some(_) => {
                 if userinfo.is_authenticated {
-                    format!("${}\r\n{}\r\n", userinfo.name.len(), userinfo.name)
+                    helper::bulk_string(userinfo.name.as_bytes())
                 } else {
-                    "-NOAUTH Authentication required.\r\n".to_string()
+                    b"-NOAUTH Authentication required.\r\n".to_vec()
                 }
             }
-            None => "$7\r\ndefault\r\n".to_string(),
+            None => b"$7\r\ndefault\r\n".to_vec(),
         },
 
         "getuser" => match user {
-            Some(user) => match userpw_hmap.get(user) {
+            Some(user) => match userpw_hmap.get(&user) {
                 Some(hash_vec) => {
-                    let hash_v: Vec<String> =
-                        hash_vec.iter().map(|hash| hex::encode(hash)).collect();
+                    let hash_v: Vec<Vec<u8>> = hash_vec
+                        .iter()
+                        .map(|hash| hex::encode(hash).into_bytes())
+                        .collect();
                     let pw_len = helper::elements_arr_to_resp_arr(&hash_v);
 
-                    format!("*4\r\n$5\r\nflags\r\n*0\r\n$9\r\npasswords\r\n{}", pw_len)
+                    let mut resp = b"*4\r\n$5\r\nflags\r\n*0\r\n$9\r\npasswords\r\n".to_vec();
+                    resp.extend(pw_len);
+                    resp
+                }
+                None => {
+                    b"*4\r\n$5\r\nflags\r\n*1\r\n$6\r\nnopass\r\n$9\r\npasswords\r\n*0\r\n".to_vec()
                 }
-                None => "*4\r\n$5\r\nflags\r\n*1\r\n$6\r\nnopass\r\n$9\r\npasswords\r\n*0\r\n"
-                    .to_string(),
             },
-            None => "*2\r\n$5\r\nflags\r\n*0\r\n".to_string(),
+            None => b"*2\r\n$5\r\nflags\r\n*0\r\n".to_vec(),
         },
 
         "setuser" => {
             let mut pw_vec: Vec<[u8; 32]> = Vec::new();
 
-            let username = elems[2].clone();
-            let password = elems[3].as_bytes()[1..].to_vec();
+            let username = String::from_utf8_lossy(&elems[2]).to_string();
+            let password = elems[3][1..].to_vec();
             let hash = Sha256::digest(password).as_slice().try_into().unwrap();
 
             pw_vec.push(hash);
@@ -1281,10 +3218,10 @@ pub fn handle_acl(
             userpw_hmap.insert(username, pw_vec);
             userinfo.is_authenticated = true;
 
-            "+OK\r\n".to_string()
+            b"+OK\r\n".to_vec()
         }
 
-        _ => "-ERR Invalid Flag".to_string(),
+        _ => b"-ERR Invalid Flag\r\n".to_vec(),
     };
 
     resp
@@ -1292,21 +3229,25 @@ pub fn handle_acl(
 
 // handle auth cmd
 pub fn handle_auth(
-    elems: Vec<String>,
+    elems: Vec<Vec<u8>>,
     userpw_hmap_clone: &Arc<Mutex<HashMap<String, Vec<[u8; 32]>>>>,
     user_guard: &Arc<Mutex<types::UserInfo>>,
-) -> String {
+) -> Vec<u8> {
+    // AUTH <password> is for the default user
+    let (user, password) = match elems.len() {
+        2 => ("default".to_string(), &elems[1]),
+        3 => (String::from_utf8_lossy(&elems[1]).to_string(), &elems[2]),
+        _ => return b"-ERR syntax error\r\n".to_vec(),
+    };
+
     let mut userinfo = user_guard.lock().unwrap();
     let userpw_hmap = userpw_hmap_clone.lock().unwrap();
 
-    let password = elems[2].as_bytes();
-    let user = &elems[1];
-
     let pw = hex::encode(Sha256::digest(password));
 
-    let hash_vec = match userpw_hmap.get(user) {
+    let hash_vec = match userpw_hmap.get(&user) {
         Some(v) => v,
-        None => return "-ERR username doesn't exists".to_string(),
+        None => return b"-ERR username doesn't exists\r\n".to_vec(),
     };
 
     let hash_v: Vec<String> = hash_vec.iter().map(|hash| hex::encode(hash)).collect();
@@ -1314,9 +3255,56 @@ pub fn handle_auth(
     for pwi in hash_v {
         if pw == pwi {
             userinfo.is_authenticated = true;
-            return "+OK\r\n".to_string();
+            return b"+OK\r\n".to_vec();
         }
     }
 
-    return "-WRONGPASS invalid username-password pair or user is disabled.\r\n".to_string();
+    return b"-WRONGPASS invalid username-password pair or user is disabled.\r\n".to_vec();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn float_sum_matches_redis_long_double_output() {
+        assert_eq!(float_sum(b"0.1", b"0.2").as_deref(), Some("0.3"));
+        assert_eq!(float_sum(b"10.50", b"0.1").as_deref(), Some("10.6"));
+        assert_eq!(float_sum(b"5.0e3", b"2.0e2").as_deref(), Some("5200"));
+        assert_eq!(float_sum(b"3", b"1.0e-7").as_deref(), Some("3.0000001"));
+        assert_eq!(
+            float_sum(b"0", b"1e20").as_deref(),
+            Some("100000000000000000000")
+        );
+        assert_eq!(float_sum(b"1.5", b"-1.5").as_deref(), Some("0"));
+        assert_eq!(float_sum(b"-0.1", b"-0.2").as_deref(), Some("-0.3"));
+        assert_eq!(float_sum(b"1", b"-3").as_deref(), Some("-2"));
+    }
+
+    #[test]
+    fn float_sum_rounds_to_17_decimal_places() {
+        assert_eq!(
+            float_sum(b"0.123456789012345678901", b"0").as_deref(),
+            Some("0.12345678901234568")
+        );
+        assert_eq!(
+            float_sum(b"123456789.123456789123456789", b"0").as_deref(),
+            Some("123456789.12345678912345679")
+        );
+        assert_eq!(float_sum(b"0", b"1e-18").as_deref(), Some("0"));
+        assert_eq!(float_sum(b"0", b"-1e-18").as_deref(), Some("0"));
+        assert_eq!(
+            float_sum(b"0", b"1e-17").as_deref(),
+            Some("0.00000000000000001")
+        );
+        assert_eq!(float_sum(b"0", b"1e-40").as_deref(), Some("0"));
+        assert_eq!(float_sum(b"1e-300", b"1").as_deref(), Some("1"));
+        assert_eq!(float_sum(b"1e300", b"0").map(|sum| sum.len()), Some(301));
+    }
+
+    #[test]
+    fn float_sum_rejects_infinite_results() {
+        assert_eq!(float_sum(b"1.7e308", b"1.7e308"), None);
+        assert_eq!(float_sum(b"inf", b"1"), None);
+    }
 }
@@ -1,11 +1,15 @@
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::net::TcpStream;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::u32;
 use std::{collections::HashMap, time::Instant};
 
+use crate::aof;
+use crate::command_table;
 use crate::commands;
+use crate::memory;
+use crate::rdb;
 use crate::types;
 
 const MIN_LONGITUDE: f64 = -180.0;
@@ -104,614 +108,1901 @@ pub fn alread_present(subscribers: &Vec<TcpStream>, stream: &TcpStream) -> bool
 
 pub fn handle_subscribed_mode(
     mut stream: TcpStream,
-    bytes_received: &[u8],
-    subs_htable: &Arc<Mutex<HashMap<String, Vec<TcpStream>>>>,
+    elems: Vec<Vec<u8>>,
+    subs_htable: &Arc<Mutex<HashMap<Vec<u8>, Vec<TcpStream>>>>,
     is_subscribed: &mut bool,
-    channels_subscribed: &Arc<Mutex<Vec<String>>>,
+    channels_subscribed: &Arc<Mutex<Vec<Vec<u8>>>>,
 ) -> TcpStream {
-    match bytes_received[0] {
-        b'*' => {
-            let elems = get_elems(&bytes_received);
+    if let Err(resp) = command_table::check_arity(&elems) {
+        let _ = stream.write_all(&resp);
+        return stream;
+    }
 
-            match elems[0].to_ascii_lowercase().as_str() {
-                "subscribe" => {
-                    let mut map = subs_htable.lock().unwrap();
+    match cmd_name(&elems[0]).as_str() {
+        "subscribe" => {
+            let resp = commands::handle_subscribe(
+                elems,
+                is_subscribed,
+                channels_subscribed,
+                subs_htable,
+                &mut stream,
+            );
+
+            let _ = stream.write_all(&resp);
+        }
 
-                    // add channel to subscribed channels vec
-                    let mut subs_channels = channels_subscribed.lock().unwrap();
+        "publish" => {
+            let resp = commands::handle_publish(elems, subs_htable);
+            let _ = stream.write_all(&resp);
+        }
 
-                    match subs_channels.iter().find(|channel| *channel == &elems[1]) {
-                        Some(_) => {
-                            // channel already added to list
-                        }
-                        None => {
-                            // channel not added to list
-                            subs_channels.push(elems[1].clone());
-                        }
-                    }
+        "unsubscribe" => {
+            let resp =
+                commands::handle_unsubscribe(elems, subs_htable, channels_subscribed, &mut stream);
 
-                    //
-                    match map.get_mut(&elems[1]) {
-                        Some(subscribers) => {
-                            if alread_present(subscribers, &stream) {
-                                let resp = format!(
-                                    "*3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n",
-                                    "subscribe".len(),
-                                    "subscribe".to_string(),
-                                    &elems[1].len(),
-                                    &elems[1].clone(),
-                                    subs_channels.len()
-                                );
-
-                                println!("[debug] resp when subscriber exists: {:?}", resp);
-                                let _ = stream.write_all(resp.as_bytes());
-                            } else {
-                                subscribers.push(stream.try_clone().unwrap());
-
-                                let resp = format!(
-                                    "*3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n",
-                                    "subscribe".len(),
-                                    "subscribe".to_string(),
-                                    elems[1].len(),
-                                    elems[1].clone(),
-                                    subs_channels.len()
-                                );
-
-                                println!("[debug] resp when subscriber doesn't exists: {:?}", resp);
-                                let _ = stream.write_all(resp.as_bytes());
-                            };
-                        }
-                        None => {
-                            let mut subscribers: Vec<TcpStream> = Vec::new();
-                            subscribers.push(stream.try_clone().unwrap());
-
-                            let resp = format!(
-                                "*3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n",
-                                "subscribe".len(),
-                                "subscribe".to_string(),
-                                elems[1].len(),
-                                elems[1].clone(),
-                                subs_channels.len()
-                            );
-
-                            map.insert(elems[1].clone(), subscribers);
-
-                            println!("[debug] resp when channel doesn't exists: {:?}", resp);
-                            let _ = stream.write_all(resp.as_bytes());
-                        }
-                    }
+            let _ = stream.write_all(&resp);
+        }
 
-                    *is_subscribed = true;
-                }
+        "ping" => {
+            let _ = stream.write_all("*2\r\n$4\r\npong\r\n$0\r\n\r\n".as_bytes());
+        }
+        _ => {
+            let error = format!(
+                "-ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n",
+                String::from_utf8_lossy(&elems[0])
+            );
+            let _ = stream.write_all(error.as_bytes());
+        }
+    }
+    stream
+}
 
-                "publish" => {
-                    let mut map = subs_htable.lock().unwrap();
-
-                    match map.get_mut(&elems[1]) {
-                        Some(subscribers) => {
-                            let subs_len = subscribers.len();
-                            for subscriber in subscribers {
-                                let arr =
-                                    vec!["message".to_string(), elems[1].clone(), elems[2].clone()];
-                                let resp = elements_arr_to_resp_arr(&arr);
-                                let _ = subscriber.write_all(resp.as_bytes());
-                            }
-                            let _ = stream.write_all(format!(":{}\r\n", subs_len).as_bytes());
-                        }
-                        None => {}
-                    }
-                }
+pub fn now_unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
 
-                "unsubscribe" => {
-                    let mut map = subs_htable.lock().unwrap();
-                    let mut subs_channels = channels_subscribed.lock().unwrap();
+/// absolute unix time in ms the time argument of EXPIRE, PEXPIRE, EXPIREAT or
+/// PEXPIREAT points at, None if it isn't an integer or overflows
+pub fn expire_target_ms(cmd: &str, time: &[u8]) -> Option<i64> {
+    let time = parse_arg::<i64>(time)?;
+    let time_ms = match cmd {
+        "expire" | "expireat" => time.checked_mul(1000)?,
+        _ => time,
+    };
 
-                    // remove from channels list
-                    let index = subs_channels
-                        .iter()
-                        .position(|channel| *channel == elems[1])
-                        .unwrap();
-                    subs_channels.remove(index);
-
-                    // remove from subs_htable
-                    match map.get_mut(&elems[1]) {
-                        Some(subscribers) => {
-                            let index = subscribers
-                                .iter()
-                                .position(|subs| {
-                                    subs.peer_addr().unwrap() == stream.peer_addr().unwrap()
-                                })
-                                .unwrap();
-                            subscribers.remove(index);
-
-                            let resp = format!(
-                                "*3\r\n${}\r\n{}\r\n${}\r\n{}\r\n:{}\r\n",
-                                "unsubscribe".len(),
-                                "unsubscribe",
-                                &elems[1].len(),
-                                &elems[1],
-                                subs_channels.len()
-                            );
-
-                            let _ = stream.write_all(resp.as_bytes());
-                        }
-                        None => {}
-                    }
-                }
+    match cmd {
+        "expire" | "pexpire" => time_ms.checked_add(now_unix_ms() as i64),
+        _ => Some(time_ms),
+    }
+}
 
-                "ping" => {
-                    let _ = stream.write_all("*2\r\n$4\r\npong\r\n$0\r\n\r\n".as_bytes());
-                }
-                _ => {
-                    let error = format!(
-                        "-ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n",
-                        elems[0]
-                    );
-                    let _ = stream.write_all(error.as_bytes());
-                }
+/// absolute unix time in ms of an EX, PX, EXAT or PXAT option of cmd, the
+/// time given has to be positive
+pub fn option_expire_target_ms(option: &str, time: &[u8], cmd: &str) -> Result<u64, Vec<u8>> {
+    let invalid = || format!("-ERR invalid expire time in '{cmd}' command\r\n").into_bytes();
+
+    match parse_arg::<i64>(time) {
+        Some(time) if time > 0 => {}
+        Some(_) => return Err(invalid()),
+        None => return Err(b"-ERR value is not an integer or out of range\r\n".to_vec()),
+    }
+
+    let expire_cmd = match option {
+        "ex" => "expire",
+        "px" => "pexpire",
+        "exat" => "expireat",
+        _ => "pexpireat",
+    };
+    expire_target_ms(expire_cmd, time)
+        .map(|target| target as u64)
+        .ok_or_else(invalid)
+}
+
+/// parses the options of a SET, conflicting ones are a syntax error
+pub fn parse_set_args(args: &[Vec<u8>]) -> Result<types::SetArgs, Vec<u8>> {
+    let mut set_args = types::SetArgs::default();
+    // checked once every option is known to be valid, like redis does
+    let mut expire: Option<(String, &[u8])> = None;
+
+    let mut i = 0;
+    while i < args.len() {
+        match cmd_name(&args[i]).as_str() {
+            "nx" if !set_args.xx => set_args.nx = true,
+            "xx" if !set_args.nx => set_args.xx = true,
+            "get" => set_args.get = true,
+            "keepttl" if expire.is_none() => set_args.keep_ttl = true,
+            option @ ("ex" | "px" | "exat" | "pxat")
+                if expire.is_none() && !set_args.keep_ttl && i + 1 < args.len() =>
+            {
+                expire = Some((option.to_string(), &args[i + 1]));
+                i += 1;
             }
+            _ => return Err(b"-ERR syntax error\r\n".to_vec()),
         }
+        i += 1;
+    }
 
-        _ => {}
+    if let Some((option, time)) = expire {
+        set_args.expires_at = Some(option_expire_target_ms(&option, time, "set")?);
     }
-    stream
+
+    Ok(set_args)
 }
 
-pub fn parse_db(data: &Vec<u8>) -> (Vec<[String; 2]>, Vec<[String; 3]>, Vec<[String; 3]>) {
-    // fe => start of database
-    // 00 index of database
-    // fb indicates that hash table size information
-    // 03 the size of the hash table
-    // 02 size of hash table that stores expiry
-    // 00 1 byte flag that specifies the value's type and encoding
-
-    // db start index
-    let start = data.iter().position(|b| *b == 0xfe).unwrap();
-    let end = data.iter().position(|b| *b == 0xff).unwrap();
-
-    let _index_of_database = data[start + 1];
-    let _indicates_hash_table_info_follows = data[start + 2];
-    let _size_of_h_table = data[start + 3];
-    let _size_of_h_table_stores_expiry = data[start + 4];
-
-    // 1 byte flag that specifies value
-    // length of key
-    // key
-    // length of value
-    // value
-
-    // println!("length of data: {}", data.len());
-    // println!("start: {}", start);
-    // println!("end: {}", end);
-
-    let mut kv_arr: Vec<[String; 2]> = Vec::new();
-    let mut kv_arr_fc: Vec<[String; 3]> = Vec::new();
-    let mut kv_arr_fd: Vec<[String; 3]> = Vec::new();
-
-    let mut counter = start + 5;
-
-    while counter < end {
-        match data[counter] {
-            0x00 => {
-                counter += 1;
-                let (c, key) = parse_kv(counter, &data);
-                let k = String::from_utf8_lossy(&key);
-
-                counter = c;
-                counter += 1;
-                let (c, value) = parse_kv(counter, &data);
-                counter = c;
-                counter += 1;
-
-                let v = String::from_utf8_lossy(&value);
-                kv_arr.push([k.to_string(), v.to_string()]);
+/// parses the options of a GETEX, at most one of them
+pub fn parse_getex_ttl(args: &[Vec<u8>]) -> Result<types::GetExTtl, Vec<u8>> {
+    match args {
+        [] => Ok(types::GetExTtl::Keep),
+        [option] if cmd_name(option) == "persist" => Ok(types::GetExTtl::Persist),
+        [option, time] => match cmd_name(option).as_str() {
+            option @ ("ex" | "px" | "exat" | "pxat") => {
+                option_expire_target_ms(option, time, "getex").map(types::GetExTtl::ExpireAt)
             }
-            0xfc => {
-                // handling timout
-                counter += 1;
-                let timeout = u64::from_le_bytes(data[counter..counter + 8].try_into().unwrap());
-                counter += 8;
-
-                // handling key value
-                counter += 1;
-                let (c, key) = parse_kv(counter, &data);
-                let k = String::from_utf8_lossy(&key);
-
-                counter = c;
-                counter += 1;
-                let (c, value) = parse_kv(counter, &data);
-                counter = c;
-                counter += 1;
-
-                let v = String::from_utf8_lossy(&value);
-                kv_arr_fc.push([k.to_string(), v.to_string(), format!("{timeout}")]);
+            _ => Err(b"-ERR syntax error\r\n".to_vec()),
+        },
+        _ => Err(b"-ERR syntax error\r\n".to_vec()),
+    }
+}
+
+/// parses save points in the "<seconds> <changes> ..." format
+/// an empty string means snapshotting is disabled
+pub fn parse_save_points(arg: &str) -> Option<Vec<(u64, u64)>> {
+    let parts: Vec<&str> = arg.split_whitespace().collect();
+    if !parts.len().is_multiple_of(2) {
+        return None;
+    }
+
+    parts
+        .chunks_exact(2)
+        .map(|pair| Some((pair[0].parse().ok()?, pair[1].parse().ok()?)))
+        .collect()
+}
+
+/// parses a size like redis config does: plain bytes or with a k/kb/m/mb/g/gb unit
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let digits_end = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits_end);
+
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// location of the rdb file, falling back to redis defaults when not configured
+pub fn rdb_path(
+    dir: &Arc<Mutex<Option<String>>>,
+    dbfilename: &Arc<Mutex<Option<String>>>,
+) -> std::path::PathBuf {
+    let dir = dir
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| ".".to_string());
+    let dbfilename = dbfilename
+        .lock()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| "dump.rdb".to_string());
+
+    std::path::Path::new(&dir).join(dbfilename)
+}
+
+/// copies every live key out of the dbs so they can be serialized without holding
+/// the locks, ordered by db
+/// every db is locked for the copy so it's a single point in time
+/// it is a deep clone of the whole keyspace: every client waits for as long as
+/// copying the dataset takes, and the copy needs as much memory again
+pub fn snapshot_store(dbs: &types::SharedDatabases) -> types::Snapshot {
+    let maps: Vec<_> = dbs.iter().map(|db| db.0.lock().unwrap()).collect();
+
+    let before = memory::used_memory();
+    let keys = maps
+        .iter()
+        .enumerate()
+        .flat_map(|(db, map)| {
+            map.iter()
+                .filter(|(_, entry)| !entry.is_expired())
+                .map(move |(key, entry)| rdb::RdbKey {
+                    db,
+                    key: key.to_vec(),
+                    value: entry.value.clone(),
+                    expires_at_ms: entry.expires_at,
+                })
+        })
+        .collect();
+
+    // what got allocated meanwhile is the copy, near enough with the other
+    // threads allocating too
+    let copied = memory::used_memory().saturating_sub(before);
+    types::Snapshot::new(keys, copied)
+}
+
+/// forks off a snapshot: the keyspace is copied and written by a background
+/// thread, so clients are only blocked for the copy
+/// there is no copy on write like after a fork, the copy deep clones every key
+/// with all the db locks held (see snapshot_store)
+pub fn start_bgsave(
+    dbs: &types::SharedDatabases,
+    dir: &Arc<Mutex<Option<String>>>,
+    dbfilename: &Arc<Mutex<Option<String>>>,
+    save_state: &types::SharedSaveState,
+) -> Result<(), &'static str> {
+    let mut state = save_state.lock().unwrap();
+    if state.bgsave_in_progress {
+        return Err("Background save already in progress");
+    }
+
+    state.bgsave_in_progress = true;
+    state.last_bgsave_try = now_unix_ms() / 1000;
+    let dirty_at_start = state.dirty;
+    let snapshot = snapshot_store(dbs);
+    drop(state);
+
+    let path = rdb_path(dir, dbfilename);
+    let save_state = Arc::clone(save_state);
+
+    std::thread::spawn(move || {
+        let result = rdb::write_file(&path, &snapshot.keys);
+
+        let mut state = save_state.lock().unwrap();
+        state.bgsave_in_progress = false;
+        state.last_bgsave_ok = result.is_ok();
+
+        match result {
+            Ok(()) => {
+                state.dirty = state.dirty.saturating_sub(dirty_at_start);
+                state.last_save = now_unix_ms() / 1000;
+                println!("[info] background saving terminated with success");
             }
-            0xfd => {
-                // handling timout
-                counter += 1;
-                let timeout = u32::from_le_bytes(data[counter..counter + 4].try_into().unwrap());
-                counter += 4;
-
-                // handling key value
-                counter += 1;
-                let (c, key) = parse_kv(counter, &data);
-                let k = String::from_utf8_lossy(&key);
-
-                counter = c;
-                counter += 1;
-                let (c, value) = parse_kv(counter, &data);
-                counter = c;
-                counter += 1;
-
-                let v = String::from_utf8_lossy(&value);
-                kv_arr_fd.push([k.to_string(), v.to_string(), format!("{timeout}")]);
+            Err(e) => {
+                println!(
+                    "[error] background saving to {} failed: {e}",
+                    path.display()
+                );
             }
-            _ => {}
+        }
+    });
+
+    Ok(())
+}
+
+/// checks the configured save points once a second and starts a BGSAVE
+/// when enough changes happened in the given amount of time
+pub fn run_save_points(
+    dbs: types::SharedDatabases,
+    dir: Arc<Mutex<Option<String>>>,
+    dbfilename: Arc<Mutex<Option<String>>>,
+    save_state: types::SharedSaveState,
+) {
+    // failed saves are only retried after this many seconds
+    const BGSAVE_RETRY_DELAY: u64 = 5;
+
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+
+        let should_save = {
+            let state = save_state.lock().unwrap();
+            let now = now_unix_ms() / 1000;
+
+            !state.bgsave_in_progress
+                && (state.last_bgsave_ok
+                    || now.saturating_sub(state.last_bgsave_try) >= BGSAVE_RETRY_DELAY)
+                && state.save_points.iter().any(|&(seconds, changes)| {
+                    state.dirty >= changes && now.saturating_sub(state.last_save) >= seconds
+                })
+        };
+
+        if should_save {
+            println!("[info] save point reached, saving in the background");
+            let _ = start_bgsave(&dbs, &dir, &dbfilename, &save_state);
         }
     }
+}
 
-    println!("kv arr: {:?}", kv_arr);
-    (kv_arr, kv_arr_fc, kv_arr_fd)
+/// reads the rdb file once at startup and puts every key into its db
+/// a corrupt file stops the server instead of starting with partial data
+pub fn load_rdb_into_store(path: &std::path::Path, dbs: &types::SharedDatabases) {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("[info] no rdb file loaded from {}: {e}", path.display());
+            return;
+        }
+    };
+    let path = path.display();
+
+    let contents = match rdb::parse(&data) {
+        Ok(contents) => contents,
+        Err(e) => {
+            println!("[error] failed to load rdb file {path}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let loaded = insert_rdb_keys(contents.keys, dbs);
+    println!(
+        "[info] loaded {loaded} keys from {path} (rdb version {})",
+        contents.version
+    );
 }
 
-/// returns counter and element
-/// assumes counter at length of kv byte
-fn parse_kv(counter: usize, data: &Vec<u8>) -> (usize, Vec<u8>) {
-    let mut c = counter;
-    let length = data[c];
-    let mut kv = Vec::new();
+/// puts keys read from an rdb into their db, returns how many were inserted
+/// keys whose expiry is already in the past are skipped
+pub fn insert_rdb_keys(keys: Vec<rdb::RdbKey>, dbs: &types::SharedDatabases) -> usize {
+    let mut maps: Vec<_> = dbs.iter().map(|db| db.0.lock().unwrap()).collect();
+    let mut loaded = 0;
+
+    for rdb_key in keys {
+        // the file may come from a server configured with more databases
+        let Some(map) = maps.get_mut(rdb_key.db) else {
+            println!("[info] skipping key from db {}", rdb_key.db);
+            continue;
+        };
+
+        if rdb_key
+            .expires_at_ms
+            .is_some_and(|unix_ms| unix_ms <= now_unix_ms())
+        {
+            continue;
+        }
 
-    for _ in 0..length {
-        c += 1;
-        kv.push(data[c]);
+        map.insert(
+            rdb_key.key,
+            types::ValueEntry::with_expiry(rdb_key.value, rdb_key.expires_at_ms),
+        );
+        loaded += 1;
     }
 
-    (c, kv)
+    loaded
 }
 
-pub fn elements_arr_to_resp_arr(elems: &Vec<String>) -> String {
-    let mut resp = format!("*{}\r\n", elems.len());
+/// encodes raw bytes as a resp bulk string
+pub fn bulk_string(value: &[u8]) -> Vec<u8> {
+    let mut resp = format!("${}\r\n", value.len()).into_bytes();
+    resp.extend_from_slice(value);
+    resp.extend_from_slice(b"\r\n");
+    resp
+}
+
+pub fn elements_arr_to_resp_arr(elems: &[Vec<u8>]) -> Vec<u8> {
+    let mut resp = format!("*{}\r\n", elems.len()).into_bytes();
     for item in elems {
-        resp.push_str(&format!("${}\r\n{}\r\n", item.len(), item));
+        resp.extend(bulk_string(item));
     }
     resp
 }
 
-/// jumps over rdb bulk string if any and is at the starting.
-/// returns offset index at character immediately after rdb file resp string ends
-pub fn skip_rdb_if_present(buf: &[u8]) -> &[u8] {
-    let mut offset = 0;
+/// glob style matching the way redis does it: `*`, `?`, `[abc]`, `[^a-z]` and
+/// `\` to escape a special character
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // where to retry from when a mismatch happens after a *
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, s));
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match_class(&pattern[p..], string[s]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == string[s]).then_some(2),
+            Some(&c) => (c == string[s]).then_some(1),
+            None => None,
+        };
+
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                s += 1;
+            }
+            // the last * swallows one more byte
+            (None, Some((after_star, star_s))) => {
+                p = after_star;
+                s = star_s + 1;
+                star = Some((after_star, s));
+            }
+            (None, None) => return false,
+        }
+    }
 
-    if buf.get(offset).unwrap() != &b'$' {
-        return buf;
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// matches a byte against the [...] class at the start of the pattern
+/// returns the length of the class when it matches
+fn match_class(pattern: &[u8], c: u8) -> Option<usize> {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
     }
 
-    let (number, c) = parse_number(buf, offset);
-    offset = c;
-    offset += number;
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (start, end) = (pattern[i], pattern[i + 2]);
+            matched |= (start.min(end)..=start.max(end)).contains(&c);
+            i += 3;
+        } else {
+            matched |= pattern[i] == c;
+            i += 1;
+        }
+    }
 
-    return &buf[offset..];
+    // an unterminated class runs to the end of the pattern
+    let len = (i + 1).min(pattern.len());
+    (matched != negate).then_some(len)
 }
 
-/// get elements array with offset after parsing resp string
-pub fn get_elems_with_counter(
-    starting_count: usize,
-    bytes_received: &[u8],
-) -> (Vec<String>, usize) {
-    let (no_of_elements, offset) = parse_number(bytes_received, starting_count);
-    return parsing_elements(bytes_received, offset, no_of_elements);
+/// where an element comes in a SCAN: a hash with fixed keys, so the order doesn't
+/// depend on where it sits in the table, which changes when the table is resized
+pub fn scan_hash(element: &[u8]) -> u64 {
+    use std::hash::{Hash, Hasher};
+
+    let mut hasher = std::hash::DefaultHasher::new();
+    element.hash(&mut hasher);
+    hasher.finish()
 }
 
-/// get elements array from resp string
-pub fn get_elems(bytes_received: &[u8]) -> Vec<String> {
-    let (no_of_elements, offset) = parse_number(bytes_received, 0);
-    return parsing_elements(bytes_received, offset, no_of_elements).0;
+/// parses the cursor and the options of a SCAN style command
+pub fn parse_scan_args(args: &[Vec<u8>], cmd: &str) -> Result<types::ScanArgs, Vec<u8>> {
+    let Some(cursor) = args.first() else {
+        return Err(format!("-ERR wrong number of arguments for '{cmd}' command\r\n").into_bytes());
+    };
+    let Some(cursor) = parse_arg::<u64>(cursor) else {
+        return Err(b"-ERR invalid cursor\r\n".to_vec());
+    };
+
+    let mut scan_args = types::ScanArgs {
+        cursor,
+        pattern: None,
+        count: 10,
+        type_name: None,
+        novalues: false,
+    };
+
+    let mut i = 1;
+    while i < args.len() {
+        let value = args.get(i + 1);
+        match (cmd_name(&args[i]).as_str(), value) {
+            ("match", Some(pattern)) => scan_args.pattern = Some(pattern.clone()),
+            ("count", Some(count)) => match parse_arg::<usize>(count) {
+                Some(count) if count > 0 => scan_args.count = count,
+                Some(_) => return Err(b"-ERR syntax error\r\n".to_vec()),
+                None => return Err(b"-ERR value is not an integer or out of range\r\n".to_vec()),
+            },
+            ("type", Some(type_name)) if cmd == "scan" => {
+                scan_args.type_name = Some(String::from_utf8_lossy(type_name).to_string())
+            }
+            ("novalues", _) if cmd == "hscan" => {
+                scan_args.novalues = true;
+                i += 1;
+                continue;
+            }
+            _ => return Err(b"-ERR syntax error\r\n".to_vec()),
+        }
+        i += 2;
+    }
+
+    Ok(scan_args)
 }
 
-/// handle connections other than master as slave
-pub fn handle_other_clients_as_slave(
-    mut stream: TcpStream,
-    bytes_received: &[u8],
-    store: &types::SharedStore,
-    role: &str,
-) -> TcpStream {
-    match bytes_received[0] {
-        b'*' => {
-            let elems = get_elems(&bytes_received);
-
-            match elems[0].to_ascii_lowercase().as_str() {
-                "get" => {
-                    let response = commands::handle_get_old(&elems, store);
-                    let _ = stream.write_all(response.as_bytes());
+/// reply of a SCAN style command: the next cursor and the elements
+pub fn scan_reply(cursor: u64, items: &[Vec<u8>]) -> Vec<u8> {
+    let mut resp = b"*2\r\n".to_vec();
+    resp.extend(bulk_string(cursor.to_string().as_bytes()));
+    resp.extend(elements_arr_to_resp_arr(items));
+    resp
+}
+
+pub const WRONGTYPE: &[u8] =
+    b"-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+
+/// version reported in INFO and written into rdb files
+pub const REDIS_VERSION: &str = "7.2.0";
+
+pub const READONLY: &[u8] = b"-READONLY You can't write against a read only replica.\r\n";
+
+/// returns the live entry at key if any and counts it as accessed, the same
+/// lazy expiry for every type
+/// an expired key is deleted while a write runs (see start_lazy_expiry), only
+/// hidden otherwise and left alone while replaying
+pub fn get_live_entry<'a>(
+    map: &'a mut types::Keyspace,
+    key: &[u8],
+) -> Option<&'a mut types::ValueEntry> {
+    if !is_replaying() && map.get(key).is_some_and(|entry| entry.is_expired()) {
+        let deleted = LAZY_EXPIRED.with_borrow_mut(|lazy_expired| match lazy_expired {
+            Some(keys) => {
+                keys.push(key.to_vec());
+                true
+            }
+            None => false,
+        });
+        if !deleted {
+            return None;
+        }
+        map.remove(key);
+    }
+
+    let entry = map.get_mut(key)?;
+    entry.touch();
+    Some(entry)
+}
+
+/// whether key holds a live entry, without deleting or touching it
+/// for a db other than the one a write runs in, whose expired keys can't be
+/// propagated as deleted
+pub fn is_live_key(map: &types::Keyspace, key: &[u8]) -> bool {
+    map.get(key)
+        .is_some_and(|entry| is_replaying() || !entry.is_expired())
+}
+
+/// locks two different dbs in index order, so threads locking the same pair
+/// can't deadlock, the guards come back in the order asked for
+pub fn lock_pair(
+    dbs: &types::SharedDatabases,
+    first: usize,
+    second: usize,
+) -> (types::KeyspaceGuard<'_>, types::KeyspaceGuard<'_>) {
+    if first < second {
+        let first_map = dbs[first].0.lock().unwrap();
+        (first_map, dbs[second].0.lock().unwrap())
+    } else {
+        let second_map = dbs[second].0.lock().unwrap();
+        (dbs[first].0.lock().unwrap(), second_map)
+    }
+}
+
+/// lets the write about to run on this thread delete the expired keys it finds
+/// the caller holds the aof lock, so their DELs can be propagated in order
+pub fn start_lazy_expiry() {
+    LAZY_EXPIRED.set(Some(Vec::new()));
+}
+
+/// keys the write deleted because they had expired
+pub fn finish_lazy_expiry() -> Vec<Vec<u8>> {
+    LAZY_EXPIRED.take().unwrap_or_default()
+}
+
+/// called by a write that gave its key an expire time, so the command it's
+/// propagated as carries that exact deadline rather than one computed again
+pub fn record_stored_expiry(expires_at: u64) {
+    STORED_EXPIRY.set(Some(expires_at));
+}
+
+/// expire time recorded by the write that just ran on this thread, if any
+pub fn take_stored_expiry() -> Option<u64> {
+    STORED_EXPIRY.take()
+}
+
+/// sends the deletion of expired keys to the aof and the replicas as DELs
+/// the aof guard must be held since they are ordered with the other writes
+pub fn propagate_expired(
+    db: usize,
+    keys: &[Vec<u8>],
+    aof: &mut aof::Aof,
+    replicas: &types::SharedReplicas,
+    repl_state: &types::SharedReplicationState,
+    stats: &types::SharedServerStats,
+) {
+    if keys.is_empty() {
+        return;
+    }
+
+    stats.lock().unwrap().expired_keys += keys.len() as u64;
+    propagate_deletions(db, keys, aof, replicas, repl_state);
+}
+
+/// sends keys the server deleted by itself to the aof and the replicas as DELs
+pub fn propagate_deletions(
+    db: usize,
+    keys: &[Vec<u8>],
+    aof: &mut aof::Aof,
+    replicas: &types::SharedReplicas,
+    repl_state: &types::SharedReplicationState,
+) {
+    for key in keys {
+        let payload = elements_arr_to_resp_arr(&[b"DEL".to_vec(), key.clone()]);
+        propagate_write(db, &payload, aof, replicas, repl_state);
+    }
+}
+
+/// sends a write made in db to the aof and the replicas, after a SELECT when
+/// the previous one was made in another db
+pub fn propagate_write(
+    db: usize,
+    payload: &[u8],
+    aof: &mut aof::Aof,
+    replicas: &types::SharedReplicas,
+    repl_state: &types::SharedReplicationState,
+) {
+    let mut bytes = Vec::new();
+    if aof.selected_db != Some(db) {
+        bytes = select_command(db);
+        aof.selected_db = Some(db);
+    }
+    bytes.extend_from_slice(payload);
+
+    aof.feed(&bytes);
+    propagate(replicas, repl_state, &bytes);
+}
+
+/// the SELECT that makes db the one the following writes apply to
+fn select_command(db: usize) -> Vec<u8> {
+    elements_arr_to_resp_arr(&[b"SELECT".to_vec(), db.to_string().into_bytes()])
+}
+
+/// active expiry, ten times a second: samples keys with a ttl and deletes the
+/// expired ones, so keys nobody touches again don't stay in memory forever
+/// replicas leave this to their master and apply its DELs
+pub fn run_active_expire(
+    dbs: types::SharedDatabases,
+    aof: aof::SharedAof,
+    replicas: types::SharedReplicas,
+    repl_state: types::SharedReplicationState,
+    stats: types::SharedServerStats,
+) {
+    // keys with a ttl looked at per round
+    const SAMPLE_SIZE: usize = 20;
+    // another round follows while more than this share of the sample had expired
+    const REPEAT_PERCENT: usize = 25;
+    // longest a cycle goes on, writes wait for it
+    const CYCLE_BUDGET: std::time::Duration = std::time::Duration::from_millis(25);
+
+    // the db a cycle starts with, where the previous one ran out of time
+    let mut next_db = 0;
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+
+        // the budget is shared by all the dbs
+        let started = Instant::now();
+        'cycle: for i in 0..dbs.len() {
+            let db = (next_db + i) % dbs.len();
+            loop {
+                if started.elapsed() >= CYCLE_BUDGET {
+                    next_db = db;
+                    break 'cycle;
+                }
+
+                // a handler that panicked holding a lock must not stop expiry for good
+                let mut aof_guard = aof.lock().unwrap_or_else(PoisonError::into_inner);
+                let is_replica = repl_state
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .master_addr
+                    .is_some();
+                if is_replica {
+                    break 'cycle;
                 }
-                "info" => {
-                    if elems[1] == "replication" {
-                        let data = format!(
-                            "role:{}\r\nmaster_repl_offset:0\r\nmaster_replid:8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb",
-                            role
-                        );
 
-                        let response = format!("${}\r\n{}\r\n", data.len(), data);
+                let (sampled, expired) = delete_expired_sample(&dbs[db], SAMPLE_SIZE);
+                propagate_expired(db, &expired, &mut aof_guard, &replicas, &repl_state, &stats);
+                drop(aof_guard);
 
-                        let _ = stream.write_all(response.as_bytes());
-                    }
+                if expired.len() * 100 <= sampled * REPEAT_PERCENT {
+                    break;
                 }
-                _ => {}
             }
         }
+    }
+}
 
-        _ => {}
+/// deletes the expired keys among a random sample of the keys with a ttl
+/// returns the sample size and the deleted keys
+fn delete_expired_sample(store: &types::SharedStore, sample_size: usize) -> (usize, Vec<Vec<u8>>) {
+    let (s, _) = &**store;
+    let mut map = s.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let sample = map.sample_volatile(sample_size);
+    let expired: Vec<Vec<u8>> = sample
+        .iter()
+        .filter(|key| map.get(key).is_some_and(|entry| entry.is_expired()))
+        .cloned()
+        .collect();
+
+    for key in &expired {
+        map.remove(key);
+    }
+
+    (sample.len(), expired)
+}
+
+/// gets the sorted set at key, creating an empty one if the key doesn't exist
+/// returns None if the key holds another type
+pub fn get_or_create_zset<'a>(
+    map: &'a mut types::Keyspace,
+    key: &[u8],
+) -> Option<&'a mut types::ZSet> {
+    if get_live_entry(map, key).is_none() {
+        map.insert(
+            key.to_vec(),
+            types::ValueEntry::new(types::StoredValue::ZSet(types::ZSet::new())),
+        );
+    }
+
+    match map.get_mut(key).map(|e| &mut e.value) {
+        Some(types::StoredValue::ZSet(zset)) => Some(zset),
+        _ => None,
+    }
+}
+
+/// lowercased command (or subcommand/option) name for matching
+pub fn cmd_name(elem: &[u8]) -> String {
+    String::from_utf8_lossy(elem).to_ascii_lowercase()
+}
+
+/// parses a numeric argument, None if it isn't a valid number
+pub fn parse_arg<T: std::str::FromStr>(arg: &[u8]) -> Option<T> {
+    std::str::from_utf8(arg).ok()?.parse::<T>().ok()
+}
+
+/// parses an integer in the only form redis stores it: no sign but a leading
+/// minus, no leading zeros and no spaces
+pub fn parse_int(arg: &[u8]) -> Option<i64> {
+    let n = parse_arg::<i64>(arg)?;
+    (n.to_string().as_bytes() == arg).then_some(n)
+}
+
+/// parses a float the way redis reads one, anything but NaN
+pub fn parse_float(arg: &[u8]) -> Option<f64> {
+    parse_arg::<f64>(arg).filter(|n| !n.is_nan())
+}
+
+/// max length of a bulk string accepted in a request (proto-max-bulk-len)
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+/// max number of elements accepted in a multibulk request
+const MAX_MULTIBULK_LEN: i64 = 1024 * 1024;
+
+/// max bytes buffered while waiting for the end of a header or inline command
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// returns index of the '\r' of the first \r\n found at or after offset
+fn find_crlf(buf: &[u8], offset: usize) -> Option<usize> {
+    buf.get(offset..)?
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|p| p + offset)
+}
+
+/// offset must be at '$' or '*'
+/// returns None if the header line isn't fully received yet,
+/// otherwise the number and the offset immediately after \r\n
+fn parse_length(buf: &[u8], offset: usize) -> Result<Option<(i64, usize)>, String> {
+    let Some(end) = find_crlf(buf, offset + 1) else {
+        if buf.len() - offset > MAX_INLINE_LEN {
+            return Err("Protocol error: too big mbulk count string".to_string());
+        }
+        return Ok(None);
+    };
+
+    let number = std::str::from_utf8(&buf[offset + 1..end])
+        .ok()
+        .and_then(|n| n.parse::<i64>().ok());
+
+    match (number, buf[offset]) {
+        (Some(number), _) => Ok(Some((number, end + 2))),
+        (None, b'*') => Err("Protocol error: invalid multibulk length".to_string()),
+        (None, _) => Err("Protocol error: invalid bulk length".to_string()),
+    }
+}
+
+/// parses an inline command (plain text terminated by \n, like telnet sends)
+fn parse_inline(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>, String> {
+    let Some(end) = buf.iter().position(|b| *b == b'\n') else {
+        if buf.len() > MAX_INLINE_LEN {
+            return Err("Protocol error: too big inline request".to_string());
+        }
+        return Ok(None);
+    };
+
+    let elems = buf[..end]
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|e| !e.is_empty())
+        .map(|e| e.to_vec())
+        .collect();
+
+    Ok(Some((elems, end + 1)))
+}
+
+/// streaming resp decoder, tries to parse exactly one command from the start of buf.
+/// returns Ok(None) when the frame is incomplete and more bytes have to be read,
+/// otherwise the elements array and the number of bytes the frame occupied.
+/// an empty elements array means there was nothing to execute (e.g. an empty line)
+pub fn parse_frame(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>, String> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+
+    if buf[0] != b'*' {
+        return parse_inline(buf);
+    }
+
+    let Some((no_of_elements, mut offset)) = parse_length(buf, 0)? else {
+        return Ok(None);
+    };
+
+    if no_of_elements > MAX_MULTIBULK_LEN {
+        return Err("Protocol error: invalid multibulk length".to_string());
+    }
+
+    let mut elems: Vec<Vec<u8>> = Vec::with_capacity(no_of_elements.max(0) as usize);
+
+    for _ in 0..no_of_elements {
+        let Some(marker) = buf.get(offset) else {
+            return Ok(None);
+        };
+
+        if *marker != b'$' {
+            return Err(format!(
+                "Protocol error: expected '$', got '{}'",
+                *marker as char
+            ));
+        }
+
+        let Some((len, start)) = parse_length(buf, offset)? else {
+            return Ok(None);
+        };
+
+        if !(0..=MAX_BULK_LEN).contains(&len) {
+            return Err("Protocol error: invalid bulk length".to_string());
+        }
+
+        let end = start + len as usize;
+
+        // waiting for the whole payload and its trailing \r\n
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err("Protocol error: expected '\\r\\n' after bulk string".to_string());
+        }
+
+        elems.push(buf[start..end].to_vec());
+        offset = end + 2;
+    }
+
+    Ok(Some((elems, offset)))
+}
+
+/// random number from the randomly seeded hasher std uses for hash maps
+pub fn random_u64() -> u64 {
+    use std::hash::{BuildHasher, Hasher};
+
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish()
+}
+
+/// values with more elements than this are freed on the lazy free thread
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// drops a value removed from the store, big ones on a background thread so
+/// freeing them doesn't hold up the client that unlinked them
+pub fn lazy_free(entry: types::ValueEntry) {
+    if entry.value.element_count() <= LAZYFREE_THRESHOLD {
+        return;
+    }
+
+    free_in_background(Box::new(entry));
+}
+
+/// drops a whole keyspace taken out of a db on the lazy free thread
+pub fn lazy_free_keyspace(map: types::Keyspace) {
+    free_in_background(Box::new(map));
+}
+
+fn free_in_background(value: Box<dyn Send>) {
+    static LAZY_FREE: std::sync::OnceLock<std::sync::mpsc::Sender<Box<dyn Send>>> =
+        std::sync::OnceLock::new();
+
+    let sender = LAZY_FREE.get_or_init(|| {
+        let (sender, receiver) = std::sync::mpsc::channel::<Box<dyn Send>>();
+        std::thread::spawn(move || {
+            for value in receiver {
+                drop(value);
+            }
+        });
+        sender
+    });
+    let _ = sender.send(value);
+}
+
+/// 40 character hex id, different for every boot of the server
+pub fn random_replid() -> String {
+    use sha2::{Digest, Sha256};
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+
+    let mut hasher = Sha256::new();
+    hasher.update(nanos.to_le_bytes());
+    hasher.update(std::process::id().to_le_bytes());
+
+    hex::encode(hasher.finalize())[..40].to_string()
+}
+
+/// body of INFO replication
+pub fn replication_info(
+    repl_state: &types::SharedReplicationState,
+    replicas: &types::SharedReplicas,
+) -> String {
+    let repl = repl_state.lock().unwrap();
+    let mut data = format!("{}\r\n", repl.role());
+
+    if let Some((host, port)) = repl.master_addr.as_ref().and_then(|a| a.rsplit_once(':')) {
+        let link_status = if repl.master_link.is_some() {
+            "up"
+        } else {
+            "down"
+        };
+        data.push_str(&format!(
+            "master_host:{host}\r\nmaster_port:{port}\r\nmaster_link_status:{link_status}\r\n"
+        ));
+    }
+
+    let replicas = replicas.lock().unwrap();
+    data.push_str(&format!("connected_slaves:{}\r\n", replicas.len()));
+    for (i, replica) in replicas.iter().enumerate() {
+        data.push_str(&format!(
+            "slave{i}:ip={},port={},offset={},lag={}\r\n",
+            replica.addr.ip(),
+            replica.listening_port.unwrap_or(0),
+            replica.ack_offset,
+            replica.last_ack.elapsed().as_secs()
+        ));
+    }
+
+    data.push_str(&format!(
+        "master_repl_offset:{}\r\nmaster_replid:{}\r\n",
+        repl.master_repl_offset, repl.replid
+    ));
+
+    data
+}
+
+/// 1.50K, 3.20M, ... as in INFO memory
+pub fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{value:.2}{}", units[unit])
     }
-    stream
 }
 
 /// handle connection with master as slave
+/// writes are applied silently, the master only expects an answer to GETACK
 pub fn handle_connection_as_slave_with_master(
     mut stream: TcpStream,
-    elems: Vec<String>,
-    store: &types::SharedStore,
-    main_list_store: &types::SharedMainList,
-    role: &str,
+    elems: Vec<Vec<u8>>,
+    dbs: &types::SharedDatabases,
+    db: &mut usize,
+    users: &types::SharedUsers,
     offset: usize,
 ) -> TcpStream {
-    let mut elems = elems;
+    match cmd_name(&elems[0]).as_str() {
+        "replconf" => {
+            if elems
+                .get(1)
+                .is_some_and(|e| e.eq_ignore_ascii_case(b"getack"))
+            {
+                send_ack(&mut stream, offset);
+            }
+        }
 
-    println!(
-        "[info] elements array received from master as slave: {:?}",
-        elems
-    );
+        // keepalive, only counts towards the offset
+        "ping" => {}
+
+        // the db the following writes apply to
+        "select" => {
+            let _ = commands::handle_select(&elems, dbs, db);
+        }
+
+        _ if command_table::is_write(&elems) => {
+            let master_user = Arc::new(Mutex::new(types::UserInfo::default()));
+            let _ = replay_command(elems, dbs, *db, users, &master_user);
+        }
+
+        _ => println!("[info] ignoring non write command from master"),
+    }
+    stream
+}
+
+/// tells the master how much of the replication stream was processed
+fn send_ack(stream: &mut TcpStream, offset: usize) {
+    let _ = stream.write_all(&elements_arr_to_resp_arr(&[
+        b"REPLCONF".to_vec(),
+        b"ACK".to_vec(),
+        offset.to_string().into_bytes(),
+    ]));
+}
 
-    match elems[0].to_ascii_lowercase().as_str() {
-        "set" => {
-            let _ = commands::handle_set(elems, store);
+/// write all the state changing commands to all the slaves/replicas
+/// replicas whose connection is gone are dropped, they come back through PSYNC
+pub fn handle_slaves(replicas: &types::SharedReplicas, payload: &[u8]) {
+    let mut replicas = replicas.lock().unwrap();
+
+    replicas.retain_mut(|replica| match replica.stream.write_all(payload) {
+        Ok(()) => true,
+        Err(e) => {
+            println!("[info] dropping disconnected replica: {e}");
+            false
         }
+    });
+}
 
-        "get" => {
-            let _ = commands::handle_get_old(&elems, store);
-        }
+/// sends an executed write to every replica and adds it to the backlog
+pub fn propagate(
+    replicas: &types::SharedReplicas,
+    repl_state: &types::SharedReplicationState,
+    payload: &[u8],
+) {
+    handle_slaves(replicas, payload);
+    repl_state.lock().unwrap().feed(payload);
+}
 
-        "rpush" => {
-            let _ = commands::handle_rpush(elems, main_list_store);
-        }
+/// what an executed write is propagated as, to replicas and the aof
+/// failed commands are dropped, a BLPOP is sent as the LPOP it turned into and
+/// an expire as PEXPIREAT, or DEL when it deleted the key
+pub fn propagated_command(
+    elems: &[Vec<u8>],
+    resp: &[u8],
+    stored_expiry: Option<u64>,
+) -> Option<Vec<u8>> {
+    if !command_table::is_write(elems) || resp.starts_with(b"-") {
+        return None;
+    }
 
-        "lpush" => {
-            let _ = commands::handle_lpush(elems, main_list_store);
-        }
+    if cmd_name(&elems[0]) == "blpop" {
+        // *2 [key, value] when something was popped, a null array on timeout
+        let Ok(Some((popped, _))) = parse_frame(resp) else {
+            return None;
+        };
+        let key = popped.into_iter().next()?;
+        return Some(elements_arr_to_resp_arr(&[b"LPOP".to_vec(), key]));
+    }
 
-        "lrange" => {
-            let _ = commands::handle_lrange(elems, main_list_store);
+    match cmd_name(&elems[0]).as_str() {
+        // nothing changed
+        "setnx" | "msetnx" | "del" | "unlink" | "persist" | "renamenx" | "copy" | "move"
+        | "zrem"
+            if resp == b":0\r\n" =>
+        {
+            return None;
         }
+        "getdel" | "getex" | "lpop" if resp == b"$-1\r\n" => return None,
 
-        "llen" => {
-            let _ = commands::handle_llen(elems, main_list_store);
+        "getdel" => {
+            return Some(elements_arr_to_resp_arr(&[
+                b"DEL".to_vec(),
+                elems[1].clone(),
+            ]));
         }
 
-        "lpop" => {
-            let _ = commands::handle_lpop(elems, main_list_store);
+        // the ttl change is all a GETEX writes
+        "getex" => {
+            let key = elems[1].clone();
+            return match parse_getex_ttl(&elems[2..]).ok()? {
+                types::GetExTtl::Keep => None,
+                types::GetExTtl::Persist => {
+                    Some(elements_arr_to_resp_arr(&[b"PERSIST".to_vec(), key]))
+                }
+                // the deadline the handler stored, EX and PX are relative to when it ran
+                types::GetExTtl::ExpireAt(target) => match stored_expiry.unwrap_or(target) {
+                    target if target <= now_unix_ms() => {
+                        Some(elements_arr_to_resp_arr(&[b"DEL".to_vec(), key]))
+                    }
+                    target => Some(elements_arr_to_resp_arr(&[
+                        b"PEXPIREAT".to_vec(),
+                        key,
+                        target.to_string().into_bytes(),
+                    ])),
+                },
+            };
         }
 
-        "blpop" => {
-            let _ = commands::handle_blpop(elems, main_list_store);
+        // the result is sent instead, so replicas don't depend on float rounding
+        "incrbyfloat" => {
+            let (_, start) = parse_length(resp, 0).ok()??;
+            let value = resp.get(start..resp.len().checked_sub(2)?)?.to_vec();
+            return Some(elements_arr_to_resp_arr(&[
+                b"SET".to_vec(),
+                elems[1].clone(),
+                value,
+                b"KEEPTTL".to_vec(),
+            ]));
         }
 
-        "type" => {
-            let _ = commands::handle_type(elems, store);
+        // sent as the SET with an absolute time they are the same as
+        "setex" | "psetex" => {
+            let target = stored_expiry?;
+            return Some(elements_arr_to_resp_arr(&[
+                b"SET".to_vec(),
+                elems[1].clone(),
+                elems[3].clone(),
+                b"PXAT".to_vec(),
+                target.to_string().into_bytes(),
+            ]));
         }
 
-        "xadd" => {
-            let _ = commands::handle_xadd(&mut elems, store);
-        }
+        _ => {}
+    }
 
-        "xrange" => {
-            let _ = commands::handle_xrange(&mut elems, store);
+    if cmd_name(&elems[0]) == "set" {
+        let set_args = parse_set_args(&elems[3..]).ok()?;
+        // nothing changed when a NX/XX condition failed, which the reply tells
+        let applied = match (set_args.get, set_args.nx, set_args.xx) {
+            (false, _, _) => resp == b"+OK\r\n",
+            (true, true, _) => resp == b"$-1\r\n",
+            (true, _, true) => resp != b"$-1\r\n",
+            (true, false, false) => true,
+        };
+        if !applied {
+            return None;
         }
 
-        "xread" => {
-            let _ = commands::handle_xread(&mut elems, store);
+        // the deadline the handler stored, EX and PX are relative to when it ran
+        let expires_at = set_args
+            .expires_at
+            .map(|target| stored_expiry.unwrap_or(target));
+
+        // an expire time in the past deleted the key
+        if expires_at.is_some_and(|target| target <= now_unix_ms()) {
+            return Some(elements_arr_to_resp_arr(&[
+                b"DEL".to_vec(),
+                elems[1].clone(),
+            ]));
         }
 
-        "incr" => {
-            let _ = commands::handle_incr(&mut elems, store);
+        // relative times are made absolute like for the expire family, the
+        // conditions already held so they are left out
+        let mut set = vec![b"SET".to_vec(), elems[1].clone(), elems[2].clone()];
+        if let Some(target) = expires_at {
+            set.extend([b"PXAT".to_vec(), target.to_string().into_bytes()]);
+        } else if set_args.keep_ttl {
+            set.push(b"KEEPTTL".to_vec());
         }
+        return Some(elements_arr_to_resp_arr(&set));
+    }
 
-        "info" => {
-            if elems[1] == "replication" {
-                let data = format!(
-                    "role:{}\r\nmaster_repl_offset:0\r\nmaster_replid:8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb",
-                    role
-                );
-
-                let response = format!("${}\r\n{}\r\n", data.len(), data);
-
-                let _ = stream.write_all(response.as_bytes());
-            }
+    if let "expire" | "pexpire" | "expireat" | "pexpireat" = cmd_name(&elems[0]).as_str() {
+        // nothing changed when the key is missing or a NX/XX/GT/LT condition failed
+        if resp != b":1\r\n" {
+            return None;
         }
-
-        "replconf" => {
-            if elems[1].to_ascii_lowercase() == "getack" {
-                let offset = format!("{}", offset);
-                let data = format!(
-                    "*3\r\n$8\r\nreplconf\r\n$3\r\nACK\r\n${}\r\n{}\r\n",
-                    offset.len(),
-                    offset
-                );
-                let _ = stream.write_all(data.as_bytes());
-            }
+        // relative times are made absolute so the key expires at the same moment
+        // on the replicas and after an aof replay, the options were already applied
+        let target = stored_expiry?;
+        if target <= now_unix_ms() {
+            return Some(elements_arr_to_resp_arr(&[
+                b"DEL".to_vec(),
+                elems[1].clone(),
+            ]));
         }
-        _ => {}
+        return Some(elements_arr_to_resp_arr(&[
+            b"PEXPIREAT".to_vec(),
+            elems[1].clone(),
+            target.to_string().into_bytes(),
+        ]));
     }
-    stream
+
+    Some(elements_arr_to_resp_arr(elems))
 }
 
-/// write all the state changing commands to all the slaves/replicas
-pub fn handle_slaves(tcpstream_vector: &Arc<Mutex<Vec<TcpStream>>>, payload: &[u8]) {
-    let mut tcp_vec = tcpstream_vector.lock().unwrap();
+/// the writes of an EXEC wrapped in MULTI/EXEC, so they are applied atomically
+/// each write comes with the db it ran in, a SELECT is added inside the
+/// transaction whenever that changes and selected_db is updated to the last one
+pub fn propagated_transaction(
+    writes: Vec<(usize, Vec<u8>)>,
+    selected_db: &mut Option<usize>,
+) -> Option<Vec<u8>> {
+    if writes.is_empty() {
+        return None;
+    }
 
-    for stream in tcp_vec.iter_mut() {
-        let _ = stream.write_all(payload);
+    let mut payload = elements_arr_to_resp_arr(&[b"MULTI".to_vec()]);
+    for (db, write) in writes {
+        if *selected_db != Some(db) {
+            payload.extend(select_command(db));
+            *selected_db = Some(db);
+        }
+        payload.extend(write);
     }
+    payload.extend(elements_arr_to_resp_arr(&[b"EXEC".to_vec()]));
+    Some(payload)
 }
 
 /// completing and securing connection from slave with master
-pub fn hand_shake(port: &String, stream: &mut TcpStream) -> Option<Vec<u8>> {
+/// asks to continue from the last offset when this replica was synced before,
+/// otherwise the rdb payload sent by the master replaces the contents of the store
+/// returns whatever was received after the handshake, which are already commands
+pub fn hand_shake(
+    port: &String,
+    stream: &mut TcpStream,
+    dbs: &types::SharedDatabases,
+    repl_state: &types::SharedReplicationState,
+    link_id: u64,
+    was_synced: bool,
+) -> Result<Vec<u8>, String> {
+    let mut pending: Vec<u8> = Vec::new();
+
     let replconf_second: &str = &format!(
-        "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n{}\r\n",
+        "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n${}\r\n{}\r\n",
+        port.len(),
         port
     );
 
-    let commands_to_send: [(&str, Option<&str>); 4] = [
-        ("*1\r\n$4\r\nPING\r\n", Some("+PONG\r\n")),
-        (replconf_second, Some("+OK\r\n")),
+    let commands_to_send: [(&str, &str); 3] = [
+        ("*1\r\n$4\r\nPING\r\n", "+PONG"),
+        (replconf_second, "+OK"),
         (
             "*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n",
-            Some("+OK\r\n"),
+            "+OK",
         ),
-        ("*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n", None),
     ];
 
-    for i in 0..commands_to_send.len() {
-        let (is_expected_response, left_commands) =
-            send_and_validate(stream, commands_to_send[i].0, commands_to_send[i].1);
+    for (command, exp_resp) in commands_to_send {
+        let _ = stream.write_all(command.as_bytes());
+
+        let act_resp = read_line(stream, &mut pending);
 
-        if !is_expected_response {
-            panic!("[ERROR] [SLAVE] exp_resp doesn't equal actual_resp");
+        if act_resp.as_deref() != Some(exp_resp) {
+            return Err(format!("expected {exp_resp}, got {act_resp:?}"));
         }
+    }
+
+    // the offset sent is the first byte this replica doesn't have yet
+    let (replid, offset) = if was_synced {
+        let repl = repl_state.lock().unwrap();
+        (
+            repl.replid.clone(),
+            (repl.master_repl_offset + 1).to_string(),
+        )
+    } else {
+        ("?".to_string(), "-1".to_string())
+    };
+    let _ = stream.write_all(&elements_arr_to_resp_arr(&[
+        b"PSYNC".to_vec(),
+        replid.into_bytes(),
+        offset.into_bytes(),
+    ]));
+
+    // +FULLRESYNC <replid> <offset> or +CONTINUE [<replid>]
+    let psync_resp = read_line(stream, &mut pending).ok_or("master closed the connection")?;
+    println!("[info] psync response: {psync_resp}");
+    let mut parts = psync_resp.split(' ');
+
+    match parts.next() {
+        Some("+CONTINUE") => {
+            let mut repl = repl_state.lock().unwrap();
+            if repl.link_id != link_id {
+                return Err("replication target changed".to_string());
+            }
 
-        match left_commands {
-            Some(l) => {
-                return Some(l);
+            // the master got promoted from a replica, its stream follows ours
+            if let Some(new_replid) = parts.next().filter(|id| *id != repl.replid) {
+                let old_replid = std::mem::replace(&mut repl.replid, new_replid.to_string());
+                repl.replid2 = Some((old_replid, repl.master_repl_offset));
             }
-            None => {}
+            return Ok(pending);
         }
+        Some("+FULLRESYNC") => {}
+        _ => return Err(format!("unexpected psync response: {psync_resp}")),
+    }
+
+    let replid = parts.next().unwrap_or_default().to_string();
+    let offset = parts
+        .next()
+        .and_then(|offset| offset.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    // $<len>\r\n followed by rdb bytes without trailing \r\n
+    let rdb_len = read_line(stream, &mut pending)
+        .and_then(|header| header.strip_prefix('$').map(|l| l.to_string()))
+        .and_then(|l| l.parse::<usize>().ok())
+        .ok_or("invalid rdb payload header")?;
+
+    while pending.len() < rdb_len {
+        if !read_more(stream, &mut pending) {
+            return Err("master disconnected while sending rdb".to_string());
+        }
+    }
+
+    let rdb_bytes: Vec<u8> = pending.drain(..rdb_len).collect();
+    let contents =
+        rdb::parse(&rdb_bytes).map_err(|e| format!("invalid rdb payload from master: {e}"))?;
+
+    // held while the store is replaced, so a REPLICAOF NO ONE in the meantime
+    // either happens before (and nothing is replaced) or keeps the new contents
+    let mut repl = repl_state.lock().unwrap();
+    if repl.link_id != link_id {
+        return Err("replication target changed".to_string());
     }
 
-    return None;
+    for store in dbs.iter() {
+        store.0.lock().unwrap().clear();
+    }
+    let loaded = insert_rdb_keys(contents.keys, dbs);
+    println!("[info] loaded {loaded} keys from the master's snapshot");
+
+    // the stream continues from the snapshot, whatever was in the backlog is stale
+    // and the master selects a db again before its first write
+    repl.replid = replid;
+    repl.replid2 = None;
+    repl.master_repl_offset = offset;
+    repl.stream_db = 0;
+    repl.backlog.clear();
+
+    Ok(pending)
 }
 
-/// if expected response equals actual response, returns true
-/// helper function for handshake function
-pub fn send_and_validate(
-    stream: &mut TcpStream,
-    command: &str,
-    exp_resp_option: Option<&str>,
-) -> (bool, Option<Vec<u8>>) {
-    let mut buffer = [0; 512];
+/// makes this server a replica of the given master, or a master when None
+/// the link with the previous master is cut and its thread stops on its own
+pub fn set_master(
+    master_addr: Option<String>,
+    dbs: &types::SharedDatabases,
+    users: &types::SharedUsers,
+    repl_state: &types::SharedReplicationState,
+    was_synced: bool,
+) {
+    let mut repl = repl_state.lock().unwrap();
+    repl.link_id += 1;
+    repl.master_addr = master_addr.clone();
+    if let Some(link) = repl.master_link.take() {
+        let _ = link.shutdown(std::net::Shutdown::Both);
+    }
 
-    let _ = stream.write_all(command.as_bytes());
-    match stream.read(&mut buffer) {
-        Ok(n) => {
-            if command == "*3\r\n$5\r\nPSYNC\r\n$1\r\n?\r\n$2\r\n-1\r\n" {
-                if let Some(start) = buffer
-                    .windows(2)
-                    .position(|w| w[0] == b'*' && w[1].is_ascii_digit())
+    let Some(master_addr) = master_addr else {
+        return;
+    };
+
+    let link_id = repl.link_id;
+    let dbs = Arc::clone(dbs);
+    let users = Arc::clone(users);
+    let repl_state = Arc::clone(repl_state);
+    std::thread::spawn(move || {
+        run_replica(master_addr, dbs, users, repl_state, link_id, was_synced)
+    });
+}
+
+/// keeps this server replicating from the master until the role changes again
+/// a lost connection is retried with exponential backoff, and resumes with a
+/// partial resync whenever the master still has the missing bytes in its backlog
+pub fn run_replica(
+    master_addr: String,
+    dbs: types::SharedDatabases,
+    users: types::SharedUsers,
+    repl_state: types::SharedReplicationState,
+    link_id: u64,
+    mut was_synced: bool,
+) {
+    const MIN_BACKOFF: std::time::Duration = std::time::Duration::from_millis(100);
+    const MAX_BACKOFF: std::time::Duration = std::time::Duration::from_secs(5);
+
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let port = {
+            let repl = repl_state.lock().unwrap();
+            if repl.link_id != link_id {
+                println!("[info] stopped replicating from {master_addr}");
+                return;
+            }
+            repl.listening_port.clone()
+        };
+
+        let synced = TcpStream::connect(&master_addr)
+            .map_err(|e| e.to_string())
+            .and_then(|mut stream| {
+                println!("[info] connected with master with addr: {master_addr}");
+                let pending =
+                    hand_shake(&port, &mut stream, &dbs, &repl_state, link_id, was_synced)?;
+                Ok((stream, pending))
+            });
+
+        match synced {
+            Ok((stream, pending)) => {
+                was_synced = true;
+                backoff = MIN_BACKOFF;
+
+                // kept in the state so REPLICAOF can cut the link
                 {
-                    return (true, Some(buffer[start..n].to_owned()));
+                    let mut repl = repl_state.lock().unwrap();
+                    if repl.link_id != link_id {
+                        continue;
+                    }
+                    repl.master_link = stream.try_clone().ok();
+                }
+
+                follow_master(stream, pending, &dbs, &users, &repl_state, link_id);
+                println!("[info] disconnected from master");
+
+                let mut repl = repl_state.lock().unwrap();
+                if repl.link_id == link_id {
+                    repl.master_link = None;
                 }
             }
+            Err(e) => {
+                println!("[error] replication with {master_addr} failed: {e}");
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// applies the commands streamed by the master until the connection drops
+/// or the role of this server changes
+/// commands of a MULTI from the master and the raw bytes they came in
+type QueuedTransaction = (Vec<Vec<Vec<u8>>>, Vec<u8>);
+
+fn follow_master(
+    mut master_stream: TcpStream,
+    mut pending: Vec<u8>,
+    dbs: &types::SharedDatabases,
+    users: &types::SharedUsers,
+    repl_state: &types::SharedReplicationState,
+    link_id: u64,
+) {
+    // kept in the state as well, a partial resync continues in the same db
+    let mut db = repl_state.lock().unwrap().stream_db;
+
+    // commands between MULTI and EXEC with their raw bytes, applied and counted
+    // in the offset only once the EXEC arrived, so a transaction cut off by a
+    // disconnect is sent again as a whole
+    let mut transaction: Option<QueuedTransaction> = None;
+
+    // the master hears from us at least once a second, which is what the lag
+    // in its INFO is measured from
+    let _ = master_stream.set_read_timeout(Some(std::time::Duration::from_secs(1)));
+
+    loop {
+        let mut consumed = 0;
+
+        loop {
+            let (elems, len) = match parse_frame(&pending[consumed..]) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("[error] invalid resp from master: {e}");
+                    return;
+                }
+            };
+            let frame = &pending[consumed..consumed + len];
+            consumed += len;
+
+            // GETACK is answered with the offset before itself
+            let offset = {
+                let repl = repl_state.lock().unwrap();
+                if repl.link_id != link_id {
+                    return;
+                }
+                repl.master_repl_offset
+            };
 
-            match exp_resp_option {
-                Some(exp_resp) => {
-                    let act_resp: &str = &String::from_utf8_lossy(&buffer[..n]);
+            let cmd = elems.first().map(|c| cmd_name(c)).unwrap_or_default();
 
-                    if act_resp == exp_resp {
-                        return (true, None);
-                    } else {
-                        return (false, None);
+            if let Some((queued, bytes)) = transaction.as_mut() {
+                bytes.extend_from_slice(frame);
+
+                if cmd == "exec" {
+                    let master_user = Arc::new(Mutex::new(types::UserInfo::default()));
+                    for command in queued.drain(..) {
+                        if cmd_name(&command[0]) == "select" {
+                            let _ = commands::handle_select(&command, dbs, &mut db);
+                        } else {
+                            let _ = replay_command(command, dbs, db, users, &master_user);
+                        }
                     }
+                    let mut repl = repl_state.lock().unwrap();
+                    repl.feed(bytes);
+                    repl.stream_db = db;
+                    drop(repl);
+                    transaction = None;
+                } else if !elems.is_empty() {
+                    queued.push(elems);
                 }
+                continue;
+            }
 
-                None => {
-                    return (true, None);
-                }
+            if cmd == "multi" {
+                transaction = Some((Vec::new(), frame.to_vec()));
+                continue;
+            }
+
+            if !elems.is_empty() {
+                master_stream = handle_connection_as_slave_with_master(
+                    master_stream,
+                    elems,
+                    dbs,
+                    &mut db,
+                    users,
+                    offset,
+                );
+            }
+
+            let mut repl = repl_state.lock().unwrap();
+            repl.feed(frame);
+            repl.stream_db = db;
+        }
+
+        pending.drain(..consumed);
+
+        let mut buffer = [0; 16 * 1024];
+        match master_stream.read(&mut buffer) {
+            Ok(0) => return,
+            Ok(n) => pending.extend_from_slice(&buffer[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                let offset = repl_state.lock().unwrap().master_repl_offset;
+                send_ack(&mut master_stream, offset);
             }
+            Err(e) => {
+                eprintln!("[error] error reading stream: {e}");
+                return;
+            }
+        }
+    }
+}
+
+/// reads a single \r\n terminated line, buffering whatever comes after it
+/// returns None if the connection got closed
+fn read_line(stream: &mut TcpStream, pending: &mut Vec<u8>) -> Option<String> {
+    loop {
+        if let Some(end) = find_crlf(pending, 0) {
+            let line = String::from_utf8_lossy(&pending[..end]).to_string();
+            pending.drain(..end + 2);
+            return Some(line);
+        }
+
+        if !read_more(stream, pending) {
+            return None;
         }
-        Err(_) => {}
     }
+}
 
-    return (false, None);
+/// appends whatever is available on the stream to pending
+/// returns false if the connection got closed
+pub fn read_more(stream: &mut TcpStream, pending: &mut Vec<u8>) -> bool {
+    let mut buffer = [0; 16 * 1024];
+
+    match stream.read(&mut buffer) {
+        Ok(0) => false,
+        Ok(n) => {
+            pending.extend_from_slice(&buffer[..n]);
+            true
+        }
+        Err(e) => {
+            eprintln!("[error] error reading stream: {e}");
+            false
+        }
+    }
 }
 
-/// runs the queued commands in FIFO basis
-/// collect all the responses
-/// return response array
 pub fn handle_exec_under_multi(
-    vector_of_commands: &Vec<Vec<String>>,
-    store: &types::SharedStore,
-    main_list_store: &types::SharedMainList,
-) -> String {
+    vector_of_commands: &Vec<Vec<Vec<u8>>>,
+    ctx: &types::ServerContext,
+    db: &mut usize,
+    user_guard: &Arc<Mutex<types::UserInfo>>,
+) -> Vec<u8> {
+    let types::ServerContext {
+        dbs,
+        save_state,
+        aof,
+        replicas,
+        repl_state,
+        users,
+        stats,
+        maxmemory,
+        ..
+    } = ctx;
+
     if vector_of_commands.len() == 0 {
-        return "*0\r\n".to_string();
+        return b"*0\r\n".to_vec();
+    }
+
+    // other writes can't get in between the transaction and its propagation, nor
+    // can the role change
+    let mut aof_guard = aof.lock().unwrap();
+
+    // a read only transaction neither needs memory nor cares about the role
+    if vector_of_commands
+        .iter()
+        .any(|command| command_table::is_write(command))
+    {
+        // queued before a REPLICAOF turned this server into a replica
+        if repl_state.lock().unwrap().master_addr.is_some() {
+            return b"-ERR Transaction contains write commands but instance is now a read-only replica. EXEC aborted.\r\n".to_vec();
+        }
+        if let Some(misconf) = aof_guard.misconf_reply() {
+            return misconf;
+        }
+
+        let under_limit =
+            memory::evict_if_needed(dbs, maxmemory, &mut aof_guard, replicas, repl_state, stats);
+        if !under_limit
+            && vector_of_commands
+                .iter()
+                .any(|command| command_table::has_flag(command, command_table::DENYOOM))
+        {
+            return memory::OOM.to_vec();
+        }
+    }
+
+    let mut response_array = format!("*{}\r\n", vector_of_commands.len()).into_bytes();
+    let mut writes = Vec::new();
+    let mut expired = Vec::new();
+
+    for command in vector_of_commands {
+        let stat_name = command_table::stat_name(command);
+        let started = Instant::now();
+
+        // a SELECT changes the db of the connection for the rest of the transaction
+        let mut stored_expiry = None;
+        let response = if cmd_name(&command[0]) == "select" {
+            commands::handle_select(command, dbs, db)
+        } else {
+            start_lazy_expiry();
+            let response = execute_command(command.clone(), dbs, *db, users, user_guard);
+            expired.push((*db, finish_lazy_expiry()));
+            stored_expiry = take_stored_expiry();
+            response
+        };
+
+        let usec = started.elapsed().as_micros() as u64;
+        stats
+            .lock()
+            .unwrap()
+            .record_call(stat_name, usec, &response);
+
+        response_array.extend_from_slice(&response);
+        if let Some(write) = propagated_command(command, &response, stored_expiry) {
+            writes.push((*db, write));
+        }
     }
 
-    let mut response_array = format!("*{}\r\n", vector_of_commands.len());
+    // keys the transaction found expired were already gone before it ran
+    for (db, keys) in &expired {
+        propagate_expired(*db, keys, &mut aof_guard, replicas, repl_state, stats);
+    }
 
-    for i in 0..vector_of_commands.len() {
-        let response = handle_exec_commands(vector_of_commands[i].clone(), store, main_list_store);
-        response_array.push_str(&response);
+    save_state.lock().unwrap().dirty += writes.len() as u64;
+    if let Some(payload) = propagated_transaction(writes, &mut aof_guard.selected_db) {
+        aof_guard.feed(&payload);
+        propagate(replicas, repl_state, &payload);
     }
 
     return response_array;
 }
 
-/// execute all the commands queuing after EXE command
-/// returns response of each command
-fn handle_exec_commands<'a>(
-    elems: Vec<String>,
-    store: &types::SharedStore,
-    main_list_store: &types::SharedMainList,
-) -> String {
+/// executes a command against the dataset and returns its response
+/// the single dispatcher for client connections, EXEC, the replication stream
+/// and the aof replay, connection level commands are handled by the caller
+pub fn execute_command(
+    elems: Vec<Vec<u8>>,
+    dbs: &types::SharedDatabases,
+    db: usize,
+    users: &types::SharedUsers,
+    user_guard: &Arc<Mutex<types::UserInfo>>,
+) -> Vec<u8> {
     let mut elems = elems;
+    let store = &dbs[db];
 
-    match elems[0].to_ascii_lowercase().as_str() {
+    match cmd_name(&elems[0]).as_str() {
         "echo" => commands::handle_echo(elems),
 
-        "ping" => "+PONG\r\n".to_string(),
+        "ping" => b"+PONG\r\n".to_vec(),
 
         "set" => commands::handle_set(elems, store),
 
-        "get" => commands::handle_get_old(&elems, store),
+        "get" => commands::handle_get(&elems, store),
+
+        "mget" => commands::handle_mget(elems, store),
+
+        "mset" => commands::handle_mset(elems, store, false),
+
+        "msetnx" => commands::handle_mset(elems, store, true),
+
+        "setnx" => commands::handle_setnx(elems, store),
+
+        "setex" | "psetex" => commands::handle_setex(elems, store),
+
+        "getset" => commands::handle_getset(elems, store),
 
-        "rpush" => commands::handle_rpush(elems, main_list_store),
+        "getdel" => commands::handle_getdel(elems, store),
 
-        "lpush" => commands::handle_lpush(elems, main_list_store),
+        "getex" => commands::handle_getex(elems, store),
 
-        "lrange" => commands::handle_lrange(elems, main_list_store),
+        "append" => commands::handle_append(elems, store),
 
-        "llen" => commands::handle_llen(elems, main_list_store),
+        "strlen" => commands::handle_strlen(elems, store),
 
-        "lpop" => commands::handle_lpop(elems, main_list_store),
+        "getrange" => commands::handle_getrange(elems, store),
 
-        "blpop" => commands::handle_blpop(elems, main_list_store),
+        "setrange" => commands::handle_setrange(elems, store),
+
+        "lcs" => commands::handle_lcs(elems, store),
+
+        "setbit" => commands::handle_setbit(elems, store),
+
+        "getbit" => commands::handle_getbit(elems, store),
+
+        "bitcount" => commands::handle_bitcount(elems, store),
+
+        "bitpos" => commands::handle_bitpos(elems, store),
+
+        "bitop" => commands::handle_bitop(elems, store),
+
+        "rpush" => commands::handle_rpush(elems, store),
+
+        "lpush" => commands::handle_lpush(elems, store),
+
+        "lrange" => commands::handle_lrange(elems, store),
+
+        "llen" => commands::handle_llen(elems, store),
+
+        "lpop" => commands::handle_lpop(elems, store),
+
+        "blpop" => commands::handle_blpop(elems, store),
 
         "type" => commands::handle_type(elems, store),
 
+        "keys" => commands::handle_keys(elems, store),
+
+        "scan" => commands::handle_scan(elems, store),
+
+        "hscan" | "sscan" | "zscan" => commands::handle_collection_scan(elems, store),
+
         "xadd" => commands::handle_xadd(&mut elems, store),
 
-        "xrange" => commands::handle_xrange(&mut elems, store),
+        "xrange" => commands::handle_xrange(&elems, store),
 
         "xread" => commands::handle_xread(&mut elems, store),
 
-        "incr" => commands::handle_incr(&mut elems, store),
+        "incr" | "decr" | "incrby" | "decrby" => commands::handle_incr(elems, store),
+
+        "incrbyfloat" => commands::handle_incrbyfloat(elems, store),
 
-        _ => "Not a valid command".to_string(),
+        "zadd" => commands::handle_zadd(elems, store),
+
+        "zrank" => commands::handle_zrank(elems, store),
+
+        "zrange" => commands::handle_zrange(elems, store),
+
+        "zcard" => commands::handle_zcard(elems, store),
+
+        "zscore" => commands::handle_zscore(elems, store),
+
+        "zrem" => commands::handle_zrem(elems, store),
+
+        "geoadd" => commands::handle_geoadd(elems, store),
+
+        "geopos" => commands::handle_geopos(elems, store),
+
+        "geodist" => commands::handle_geodist(elems, store),
+
+        "geosearch" => commands::handle_geosearch(elems, store),
+
+        "acl" => commands::handle_acl(elems, users, user_guard),
+
+        "del" => commands::handle_del(elems, store),
+
+        "unlink" => commands::handle_unlink(elems, store),
+
+        "exists" => commands::handle_exists(elems, store),
+
+        "rename" => commands::handle_rename(elems, store, false),
+
+        "renamenx" => commands::handle_rename(elems, store, true),
+
+        "copy" => commands::handle_copy(elems, dbs, db),
+
+        "move" => commands::handle_move(elems, dbs, db),
+
+        "swapdb" => commands::handle_swapdb(elems, dbs),
+
+        "flushdb" => commands::handle_flushdb(elems, store),
+
+        "flushall" => commands::handle_flushall(elems, dbs),
+
+        "dbsize" => commands::handle_dbsize(elems, store),
+
+        "randomkey" => commands::handle_randomkey(store),
+
+        "expire" | "pexpire" | "expireat" | "pexpireat" => commands::handle_expire(elems, store),
+
+        "ttl" | "pttl" => commands::handle_ttl(elems, store),
+
+        "expiretime" | "pexpiretime" => commands::handle_expiretime(elems, store),
+
+        "persist" => commands::handle_persist(elems, store),
+
+        _ => b"-ERR Not a valid command\r\n".to_vec(),
+    }
+}
+
+thread_local! {
+    static REPLAYING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+
+    // keys deleted by lazy expiry during the current write, None outside of one
+    static LAZY_EXPIRED: std::cell::RefCell<Option<Vec<Vec<u8>>>> =
+        const { std::cell::RefCell::new(None) };
+
+    // absolute expire time the current write gave its key, for its propagation
+    static STORED_EXPIRY: std::cell::Cell<Option<u64>> = const { std::cell::Cell::new(None) };
+}
+
+/// runs a command coming from the aof or the master, whose history is replayed
+/// as it happened: keys don't expire on their own meanwhile, a later command in
+/// the stream may still touch a key whose absolute expire time already passed
+pub fn replay_command(
+    elems: Vec<Vec<u8>>,
+    dbs: &types::SharedDatabases,
+    db: usize,
+    users: &types::SharedUsers,
+    user_guard: &Arc<Mutex<types::UserInfo>>,
+) -> Vec<u8> {
+    if let Err(resp) = command_table::check_arity(&elems) {
+        return resp;
     }
+
+    REPLAYING.set(true);
+    let resp = execute_command(elems, dbs, db, users, user_guard);
+    REPLAYING.set(false);
+    // replayed writes aren't propagated
+    take_stored_expiry();
+    resp
+}
+
+/// true while a replayed command runs on this thread
+pub fn is_replaying() -> bool {
+    REPLAYING.get()
+}
+
+/// parses a stream id of the form <ms>-<seq>
+fn parse_stream_id(id: &str) -> Option<(u128, u128)> {
+    let (ms, seq) = id.split_once('-')?;
+    Some((ms.parse().ok()?, seq.parse().ok()?))
 }
 
 /// gives the last stream id as option<(last_ms, last_seq)>
-pub fn get_last_stream_id(
-    key: &String,
-    map: &HashMap<String, types::ValueEntry>,
-) -> Option<(u128, u128)> {
+pub fn get_last_stream_id(key: &[u8], map: &types::Keyspace) -> Option<(u128, u128)> {
     // this whole code is an expression
     map.get(key).and_then(|entry| {
         if let types::StoredValue::Stream(vec) = &entry.value {
-            vec.last().map(|e| {
-                let mut s = e.id.splitn(2, '-');
-                (
-                    s.next().unwrap().parse().unwrap(),
-                    s.next().unwrap().parse().unwrap(),
-                )
-            })
+            vec.last().and_then(|e| parse_stream_id(&e.id))
         } else {
             None
         }
@@ -751,17 +2042,13 @@ pub fn generate_seq_id(raw_id: &str, last: Option<(u128, u128)>) -> String {
 }
 
 /// validate if entry id is valid
-pub fn validate_entry_id(
-    elems: &Vec<String>,
-    map: &HashMap<String, types::ValueEntry>,
-) -> Option<&'static str> {
-    let id = elems[2].clone();
+pub fn validate_entry_id(elems: &[Vec<u8>], map: &types::Keyspace) -> Option<&'static str> {
+    let id = String::from_utf8_lossy(&elems[2]).to_string();
     let key = &elems[1];
-    let s = &mut id.splitn(2, "-");
-    let (id_ms, id_seq) = (
-        s.next().unwrap().parse::<u128>().unwrap(),
-        s.next().unwrap().parse::<u128>().unwrap(),
-    );
+
+    let Some((id_ms, id_seq)) = parse_stream_id(&id) else {
+        return Some("-ERR Invalid stream ID specified as stream command argument\r\n");
+    };
 
     // checks for 0-0
     if id_ms == 0 && id_seq == 0 {
@@ -773,7 +2060,7 @@ pub fn validate_entry_id(
         Some((last_ms, _)) if id_ms < last_ms => Some(
             "-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n",
         ),
-        Some((_, last_seq)) if id_seq <= last_seq => Some(
+        Some((last_ms, last_seq)) if id_ms == last_ms && id_seq <= last_seq => Some(
             "-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n",
         ),
         _ => None,
@@ -784,197 +2071,96 @@ pub fn validate_entry_id(
 
 /// get stream related data
 /// returns (id, map)
-pub fn get_stream_related_data(elems: &Vec<String>) -> (String, HashMap<String, String>) {
-    let id = elems[2].clone();
-    let mut map: HashMap<String, String> = HashMap::new();
-
-    let kv_count = (elems.len() - 3) / 2;
+pub fn get_stream_related_data(elems: &[Vec<u8>]) -> (String, HashMap<Vec<u8>, Vec<u8>>) {
+    let id = String::from_utf8_lossy(&elems[2]).to_string();
+    let mut map: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
 
-    for i in 0..kv_count {
-        map.insert(elems[i + 3].clone(), elems[i + 4].clone());
+    for pair in elems[3..].chunks_exact(2) {
+        map.insert(pair[0].clone(), pair[1].clone());
     }
 
     (id, map)
 }
 
-/// get starting and ending indexes from elements array
-pub fn get_start_and_end_indexes(elems: &Vec<String>) -> (u128, u128, u128, u128) {
-    let start_id = elems[2].clone(); // start_id
-    let end_id = elems[3].clone(); // end_id
+/// resp array of [id, [field, value, ...]] for a single stream entry
+pub fn stream_entry_to_resp(entry: &types::Entry) -> Vec<u8> {
+    let mut entry_arr = b"*2\r\n".to_vec();
+    entry_arr.extend(bulk_string(entry.id.as_bytes()));
+
+    entry_arr.extend(format!("*{}\r\n", entry.map.len() * 2).as_bytes());
+    for (key, value) in &entry.map {
+        entry_arr.extend(bulk_string(key));
+        entry_arr.extend(bulk_string(value));
+    }
 
-    let start_time;
-    let start_seq;
+    entry_arr
+}
 
-    let end_time;
-    let end_seq;
+/// get starting and ending indexes from elements array
+/// None if one of the ids is invalid
+pub fn get_start_and_end_indexes(elems: &[Vec<u8>]) -> Option<(u128, u128, u128, u128)> {
+    let start_id = String::from_utf8_lossy(&elems[2]).to_string(); // start_id
+    let end_id = String::from_utf8_lossy(&elems[3]).to_string(); // end_id
 
-    if start_id == "-" {
-        start_time = 0;
-        start_seq = 1;
+    let (start_time, start_seq) = if start_id == "-" {
+        (0, 1)
     } else if start_id.contains("-") {
-        let mut s = start_id.splitn(2, "-");
-        (start_time, start_seq) = (
-            s.next().unwrap().parse::<u128>().unwrap(),
-            s.next().unwrap().parse::<u128>().unwrap(),
-        );
+        parse_stream_id(&start_id)?
     } else {
-        start_time = start_id.parse::<u128>().unwrap();
-        start_seq = 0;
-    }
+        (start_id.parse::<u128>().ok()?, 0)
+    };
 
-    if end_id == "+" {
-        end_time = u128::MAX;
-        end_seq = u128::MAX;
+    let (end_time, end_seq) = if end_id == "+" {
+        (u128::MAX, u128::MAX)
     } else if end_id.contains("-") {
-        let mut s = end_id.splitn(2, "-");
-        (end_time, end_seq) = (
-            s.next().unwrap().parse::<u128>().unwrap(),
-            s.next().unwrap().parse::<u128>().unwrap(),
-        );
+        parse_stream_id(&end_id)?
     } else {
-        end_time = end_id.parse::<u128>().unwrap();
-        end_seq = u128::MAX;
-    }
-
-    (start_time, start_seq, end_time, end_seq)
-}
-
-// handle expiry
-pub fn handle_expiry(time_setter_args: &str, elems: Vec<String>) -> Option<Instant> {
-    match time_setter_args.to_ascii_lowercase().as_str() {
-        "px" => {
-            let time = elems[4].parse::<u32>().unwrap(); // in milliseconds
-
-            Some(Instant::now() + std::time::Duration::from_millis(time as u64))
-        }
-
-        "ex" => {
-            let time = elems[4].parse::<u32>().unwrap() * 1000; // in milliseconds
-
-            Some(Instant::now() + std::time::Duration::from_millis(time as u64))
-        }
-
-        _ => None,
-    }
-}
-
-// offset must be at '$' or '*'
-// returned coutner is whatever is immediately after \r\n
-pub fn parse_number(bytes_received: &[u8], mut offset: usize) -> (usize, usize) {
-    let mut num_in_bytes: Vec<u8> = vec![];
-
-    match bytes_received[offset] {
-        b'$' | b'*' => {
-            offset += 1;
-            loop {
-                if bytes_received[offset] == b'\r' {
-                    break;
-                }
-                num_in_bytes.push(bytes_received[offset]);
-                offset += 1;
-            }
-            offset += 2;
-
-            let number_required = String::from_utf8(num_in_bytes)
-                .unwrap()
-                .parse::<usize>()
-                .unwrap();
-
-            (number_required, offset)
-        }
-
-        _ => {
-            panic!("Didn't recieved the dollar or asterik");
-        }
-    }
-}
-
-// get the elements in Vec<String> from resp
-pub fn parsing_elements(
-    bytes_received: &[u8],
-    mut offset: usize,
-    no_of_elements: usize,
-) -> (Vec<String>, usize) {
-    let mut elems: Vec<String> = Vec::new();
-
-    for _ in 0..no_of_elements as u32 {
-        match bytes_received[offset] {
-            b'$' => {
-                let no_of_elements_in_bulk_str;
-                (no_of_elements_in_bulk_str, offset) = parse_number(bytes_received, offset);
-
-                let word = &bytes_received[offset..offset + no_of_elements_in_bulk_str];
-
-                elems.push(String::from_utf8_lossy(word).to_string());
-
-                offset += (no_of_elements_in_bulk_str as usize) - 1;
-
-                // to jump over \r\n to next element
-                offset += 3;
-            }
-
-            _ => {}
-        }
-    }
+        (end_id.parse::<u128>().ok()?, u128::MAX)
+    };
 
-    (elems, offset)
+    Some((start_time, start_seq, end_time, end_seq))
 }
 
-pub fn get_start_and_end_indexes_for_xread(start_id: &String) -> (u128, u128, u128, u128) {
-    let start_id_time;
-    let start_id_seq;
-
-    let end_id_time;
-    let end_id_seq;
+pub fn get_start_and_end_indexes_for_xread(start_id: &[u8]) -> (u128, u128, u128, u128) {
+    let start_id = String::from_utf8_lossy(start_id).to_string();
 
-    if start_id.contains("-") {
-        let mut s = start_id.splitn(2, "-");
-        (start_id_time, start_id_seq) = (
-            s.next().unwrap().parse::<u128>().unwrap(),
-            s.next().unwrap().parse::<u128>().unwrap(),
-        );
+    let (start_id_time, start_id_seq) = if start_id.contains("-") {
+        parse_stream_id(&start_id).unwrap_or((u128::MAX, u128::MAX))
     } else {
-        start_id_time = start_id.parse::<u128>().unwrap();
-        start_id_seq = 0;
-    }
+        (start_id.parse::<u128>().unwrap_or(u128::MAX), 0)
+    };
 
-    end_id_time = u128::MAX;
-    end_id_seq = u128::MAX;
+    let end_id_time = u128::MAX;
+    let end_id_seq = u128::MAX;
 
     (start_id_time, start_id_seq, end_id_time, end_id_seq)
 }
 
 /// mutates the elements array starting indexes
 /// for easier processing of xread command
-pub fn adjust_xread_start_ids(map: &HashMap<String, types::ValueEntry>, elems: &mut Vec<String>) {
+pub fn adjust_xread_start_ids(map: &types::Keyspace, elems: &mut [Vec<u8>]) {
     let streams_len = (elems.len() - 2) / 2;
 
     for i in 0..streams_len {
         if let Some(entry) = map.get(&elems[i + 2]) {
             if let types::StoredValue::Stream(entry_vec) = &entry.value {
-                let id = entry_vec.last().unwrap().id.clone();
-
-                let mut s = id.splitn(2, "-");
-                let (last_one, mut last_two) = (
-                    s.next().unwrap().parse::<u64>().unwrap(),
-                    s.next().unwrap().parse::<u64>().unwrap(),
-                );
+                let Some((last_one, last_two)) =
+                    entry_vec.last().and_then(|e| parse_stream_id(&e.id))
+                else {
+                    continue;
+                };
 
-                last_two += 1;
-                let start_new = format!("{}-{}", last_one, last_two);
+                let start_new = format!("{}-{}", last_one, last_two + 1);
 
-                elems[i + 2 + streams_len] = start_new;
+                elems[i + 2 + streams_len] = start_new.into_bytes();
             }
         }
     }
 }
 
-pub fn get_streams_array(
-    map: &HashMap<String, types::ValueEntry>,
-    elems: &Vec<String>,
-) -> (String, u32) {
+pub fn get_streams_array(map: &types::Keyspace, elems: &[Vec<u8>]) -> (Vec<u8>, u32) {
     let mut no_of_valid_streams = 0;
-    let mut final_array_data_of_streams = format!("");
+    let mut final_array_data_of_streams: Vec<u8> = Vec::new();
 
     let no_of_streams = (elems.len() - 2) / 2;
 
@@ -984,7 +2170,8 @@ pub fn get_streams_array(
             // helper::get_start_and_end_indexes_for_xread...
             get_start_and_end_indexes_for_xread(&elems[i + 2 + no_of_streams]);
 
-        let mut streams_array = format!("*2\r\n${}\r\n{}\r\n", &elems[i + 2].len(), &elems[i + 2]);
+        let mut streams_array = b"*2\r\n".to_vec();
+        streams_array.extend(bulk_string(&elems[i + 2]));
 
         // getting the full entries array
         // stream exists
@@ -995,11 +2182,8 @@ pub fn get_streams_array(
                     let filtered_data: Vec<&types::Entry> = entry_vector
                         .iter()
                         .filter(|e| {
-                            let mut s = e.id.splitn(2, "-");
-                            let (iteration_id_time, iteration_id_seq) = (
-                                s.next().unwrap().parse::<u128>().unwrap(),
-                                s.next().unwrap().parse::<u128>().unwrap(),
-                            );
+                            let (iteration_id_time, iteration_id_seq) =
+                                parse_stream_id(&e.id).unwrap_or_default();
                             iteration_id_time <= end_id_time
                                 && iteration_id_time >= start_id_time
                                 && iteration_id_seq >= start_id_sequence
@@ -1007,41 +2191,22 @@ pub fn get_streams_array(
                         })
                         .collect();
 
-                    if filtered_data.len() == 0 {
+                    if filtered_data.is_empty() {
                         continue;
                     }
 
                     no_of_valid_streams += 1;
 
                     // array of array data is all the data for a particular stream
-                    let mut array_of_array_data = format!("*{}\r\n", filtered_data.len());
+                    let mut array_of_array_data =
+                        format!("*{}\r\n", filtered_data.len()).into_bytes();
 
                     for entry in filtered_data {
-                        let entry_id = &entry.id;
-                        let hashmap = &entry.map;
-
-                        let mut entry_array = format!("*2\r\n");
-                        let mut hashmap_array = format!("*{}\r\n", hashmap.len() * 2);
-
-                        for (key, value) in hashmap {
-                            let map_bulk_data = format!(
-                                "${}\r\n{}\r\n${}\r\n{}\r\n",
-                                key.len(),
-                                key,
-                                value.len(),
-                                value
-                            );
-                            hashmap_array.push_str(&map_bulk_data);
-                        }
-
-                        entry_array.push_str(&format!("${}\r\n{}\r\n", entry_id.len(), entry_id));
-                        entry_array.push_str(&hashmap_array);
-
-                        array_of_array_data.push_str(&entry_array);
+                        array_of_array_data.extend(stream_entry_to_resp(entry));
                     }
 
-                    streams_array.push_str(&array_of_array_data);
-                    final_array_data_of_streams.push_str(&streams_array);
+                    streams_array.extend(array_of_array_data);
+                    final_array_data_of_streams.extend(streams_array);
                 }
                 _ => {}
             }
@@ -1055,97 +2220,140 @@ pub fn get_streams_array(
     (final_array_data_of_streams, no_of_valid_streams)
 }
 
-pub fn parse_rdb_get_kv(data: &Vec<u8>) -> Vec<[String; 2]> {
-    // fe => start of database
-    // 00 index of database
-    // fb indicates that hash table size information
-    // 03 the size of the hash table
-    // 02 size of hash table that stores expiry
-    // 00 1 byte flag that specifies the value's type and encoding
-
-    // db start index
-    let start = data.iter().position(|b| *b == 0xfe).unwrap();
-    let end = data.iter().position(|b| *b == 0xff).unwrap();
-
-    let _index_of_database = data[start + 1];
-    let _indicates_hash_table_info_follows = data[start + 2];
-    let _size_of_h_table = data[start + 3];
-    let _size_of_h_table_stores_expiry = data[start + 4];
-
-    // 1 byte flag that specifies value
-    // length of key
-    // key
-    // length of value
-    // value
-
-    println!("length of data: {}", data.len());
-    println!("start: {}", start);
-    println!("end: {}", end);
-
-    let mut kv_arr: Vec<[String; 2]> = Vec::new();
-
-    let mut counter = start + 5;
-
-    while counter < end {
-        match data[counter] {
-            0x00 => {
-                counter += 1;
-                let (c, key) = parse_kv(counter, &data);
-                let k = String::from_utf8_lossy(&key);
-
-                counter = c;
-                counter += 1;
-                let (c, value) = parse_kv(counter, &data);
-                counter = c;
-                counter += 1;
-
-                let v = String::from_utf8_lossy(&value);
-                kv_arr.push([k.to_string(), v.to_string()]);
-            }
-            0xfc => {
-                // handling timout
-                counter += 1;
-                let _timeout = &data[counter..counter + 8];
-                counter += 8;
-
-                // handling key value
-                counter += 1;
-                let (c, key) = parse_kv(counter, &data);
-                let k = String::from_utf8_lossy(&key);
-
-                counter = c;
-                counter += 1;
-                let (c, value) = parse_kv(counter, &data);
-                counter = c;
-                counter += 1;
-
-                let v = String::from_utf8_lossy(&value);
-                kv_arr.push([k.to_string(), v.to_string()]);
-            }
-            0xfd => {
-                // handling timout
-                counter += 1;
-                let _timeout = &data[counter..counter + 4];
-                counter += 4;
-
-                // handling key value
-                counter += 1;
-                let (c, key) = parse_kv(counter, &data);
-                let k = String::from_utf8_lossy(&key);
-
-                counter = c;
-                counter += 1;
-                let (c, value) = parse_kv(counter, &data);
-                counter = c;
-                counter += 1;
-
-                let v = String::from_utf8_lossy(&value);
-                kv_arr.push([k.to_string(), v.to_string()]);
-            }
-            _ => {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"h*llo", b"hllo"));
+        assert!(glob_match(b"h*llo", b"heeeello"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"*:*:id", b"user:42:id"));
+        assert!(!glob_match(b"*:*:id", b"user:42:name"));
+        assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+        assert!(!glob_match(b"a*b*c", b"aXbYbZ"));
+        assert!(!glob_match(b"foo", b"foobar"));
+        assert!(!glob_match(b"", b"a"));
+    }
+
+    #[test]
+    fn glob_match_classes() {
+        assert!(glob_match(b"h[ae]llo", b"hello"));
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(!glob_match(b"h[a-b]llo", b"hcllo"));
+        // reversed ranges work too
+        assert!(glob_match(b"[z-a]", b"m"));
+        assert!(glob_match(b"[\\]]", b"]"));
+        // an unterminated class runs to the end of the pattern
+        assert!(glob_match(b"a[bc", b"ab"));
+    }
+
+    #[test]
+    fn glob_match_escapes() {
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"axb"));
+        assert!(glob_match(b"a\\?", b"a?"));
+        assert!(!glob_match(b"a\\?", b"ab"));
+        assert!(glob_match(b"\\[x]", b"[x]"));
+    }
+
+    fn elems(items: &[&str]) -> Vec<Vec<u8>> {
+        items.iter().map(|item| item.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn parse_frame_reads_one_command() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n*1\r\n$4\r\nPING\r\n";
+
+        let (first, len) = parse_frame(buf).unwrap().unwrap();
+        assert_eq!(first, elems(&["GET", "foo"]));
+        assert_eq!(len, 22);
+
+        let (second, len2) = parse_frame(&buf[len..]).unwrap().unwrap();
+        assert_eq!(second, elems(&["PING"]));
+        assert_eq!(len + len2, buf.len());
+    }
+
+    #[test]
+    fn parse_frame_keeps_binary_payloads() {
+        let buf = b"*2\r\n$4\r\na\r\nb\r\n$0\r\n\r\n";
+
+        let (parsed, len) = parse_frame(buf).unwrap().unwrap();
+        assert_eq!(parsed, vec![b"a\r\nb".to_vec(), Vec::new()]);
+        assert_eq!(len, buf.len());
+    }
+
+    #[test]
+    fn parse_frame_waits_for_partial_frames() {
+        let buf = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+
+        for end in 0..buf.len() {
+            assert_eq!(parse_frame(&buf[..end]), Ok(None), "prefix of {end} bytes");
         }
+        assert_eq!(
+            parse_frame(buf),
+            Ok(Some((elems(&["SET", "foo", "bar"]), buf.len())))
+        );
     }
 
-    println!("kv arr: {:?}", kv_arr);
-    kv_arr
+    #[test]
+    fn parse_frame_reads_inline_commands() {
+        assert_eq!(
+            parse_frame(b"SET  foo\tbar\r\nGET foo\r\n"),
+            Ok(Some((elems(&["SET", "foo", "bar"]), 14)))
+        );
+        // telnet and nc may send a bare \n
+        assert_eq!(parse_frame(b"PING\n"), Ok(Some((elems(&["PING"]), 5))));
+        // an empty line is nothing to execute
+        assert_eq!(parse_frame(b"\r\n"), Ok(Some((Vec::new(), 2))));
+        assert_eq!(parse_frame(b"PIN"), Ok(None));
+    }
+
+    #[test]
+    fn parse_frame_rejects_bad_frames() {
+        assert!(parse_frame(b"*x\r\n").is_err());
+        assert!(parse_frame(b"*1\r\n+PING\r\n").is_err());
+        assert!(parse_frame(b"*1\r\n$x\r\n").is_err());
+        assert!(parse_frame(b"*1\r\n$-5\r\n").is_err());
+        assert!(parse_frame(b"*1\r\n$4\r\nPINGxx").is_err());
+        assert!(parse_frame(b"*99999999\r\n").is_err());
+        assert!(parse_frame(&vec![b'a'; MAX_INLINE_LEN + 1]).is_err());
+    }
+
+    #[test]
+    fn delete_expired_sample_survives_a_poisoned_lock() {
+        let store: types::SharedStore = Arc::new((
+            Mutex::new(types::Keyspace::default()),
+            std::sync::Condvar::new(),
+        ));
+        {
+            let mut map = store.0.lock().unwrap();
+            let value = types::StoredValue::String(b"v".to_vec());
+            map.insert(
+                b"gone".to_vec(),
+                types::ValueEntry::with_expiry(value.clone(), Some(1)),
+            );
+            map.insert(b"kept".to_vec(), types::ValueEntry::new(value));
+        }
+
+        let poisoner = Arc::clone(&store);
+        let _ = std::thread::spawn(move || {
+            let _map = poisoner.0.lock().unwrap();
+            panic!("handler panicked holding the lock");
+        })
+        .join();
+        assert!(store.0.is_poisoned());
+
+        let (sampled, expired) = delete_expired_sample(&store, 20);
+        assert_eq!(sampled, 1);
+        assert_eq!(expired, vec![b"gone".to_vec()]);
+    }
 }
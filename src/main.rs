@@ -1,14 +1,22 @@
 use clap::Parser;
 use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
+use std::time::Instant;
 
 use crate::types::UserInfo;
 
+#[global_allocator]
+static ALLOCATOR: memory::CountingAllocator = memory::CountingAllocator;
+
+mod aof;
+mod command_table;
 mod commands;
 mod helper;
+mod memory;
+mod rdb;
 mod types;
 
 fn main() {
@@ -18,195 +26,252 @@ fn main() {
         .map(|p| p.to_string())
         .unwrap_or_else(|| "6379".to_string());
 
+    let databases = args.databases.unwrap_or(16);
+    assert!(
+        databases > 0,
+        "[error] invalid databases (expected: at least 1)"
+    );
+    let dbs: types::SharedDatabases = Arc::new(
+        (0..databases)
+            .map(|_| Arc::new((Mutex::new(types::Keyspace::default()), Condvar::new())))
+            .collect(),
+    );
+
     let dir = Arc::new(Mutex::new(args.dir));
     let dbfilename = Arc::new(Mutex::new(args.dbfilename));
 
-    let store: types::SharedStore = Arc::new((Mutex::new(HashMap::new()), Condvar::new()));
-    let main_list: types::SharedMainList = Arc::new((Mutex::new(Vec::new()), Condvar::new()));
+    let appendonly = args
+        .appendonly
+        .as_deref()
+        .is_some_and(|v| v.eq_ignore_ascii_case("yes"));
+    let appendfsync = match &args.appendfsync {
+        Some(policy) => aof::AppendFsync::parse(policy)
+            .expect("[error] invalid appendfsync (expected: always, everysec or no)"),
+        None => aof::AppendFsync::EverySec,
+    };
+    let aof_path = helper::rdb_path(&dir, &dbfilename)
+        .with_file_name(args.appendfilename.as_deref().unwrap_or("appendonly.aof"));
 
-    let role = if let Some(replicaof_string) = args.replicaof {
-        // handling connection with master as slave
-        let (master_url, marter_port) = replicaof_string
-            .split_once(" ")
-            .expect("[error] invalid format (expected: HOST PORT)");
+    let userpw_hmap: types::SharedUsers = Arc::new(Mutex::new(HashMap::new()));
 
-        let master_addr = format!("{master_url}:{marter_port}");
-        let mut master_stream = TcpStream::connect(&master_addr).unwrap();
-        println!("[info] connected with master with addr: {master_addr}");
-
-        let store_clone = Arc::clone(&store);
-        let main_list_clone = Arc::clone(&main_list);
-        let mut offset: usize = 0;
-
-        // run the left commands
-        if let Some(left_cmds) = helper::hand_shake(&port, &mut master_stream) {
-            let mut counter = 0;
-            while counter < left_cmds.len() {
-                let (elems, count) = helper::get_elems_with_counter(counter, &left_cmds);
-
-                master_stream = helper::handle_connection_as_slave_with_master(
-                    master_stream,
-                    elems.clone(),
-                    &store_clone,
-                    &main_list_clone,
-                    "role:slave",
-                    offset,
-                );
-
-                offset += count - counter;
-                counter = count;
-            }
+    // data is loaded once, after this everything is served from memory
+    // the aof has every write so it wins over the rdb when both exist
+    if appendonly && aof_path.exists() {
+        if let Err(e) = aof::load(&aof_path, &dbs, &userpw_hmap) {
+            println!("[error] failed to load {}: {e}", aof_path.display());
+            std::process::exit(1);
         }
+    } else {
+        helper::load_rdb_into_store(&helper::rdb_path(&dir, &dbfilename), &dbs);
+    }
 
-        // handling connection as slave with master
-        thread::spawn(move || {
-            let mut buffer = [0; 512];
+    let aof: aof::SharedAof = Arc::new(Mutex::new(aof::Aof::new(aof_path, appendfsync)));
+    {
+        let mut aof = aof.lock().unwrap();
+        if let Some(percentage) = args.auto_aof_rewrite_percentage {
+            aof.auto_rewrite_percentage = percentage;
+        }
+        if let Some(min_size) = &args.auto_aof_rewrite_min_size {
+            aof.auto_rewrite_min_size =
+                helper::parse_memory(min_size).expect("[error] invalid auto-aof-rewrite-min-size");
+        }
+    }
+    if appendonly {
+        let mut aof = aof.lock().unwrap();
+        if let Err(e) = aof.enable(&dbs) {
+            println!("[error] can't open {}: {e}", aof.path.display());
+            std::process::exit(1);
+        }
+    }
 
-            loop {
-                match master_stream.read(&mut buffer) {
-                    Ok(0) => {
-                        println!("[info] disconnected from master");
-                        return;
-                    }
-                    Ok(n) => {
-                        let buffer_checked = helper::skip_rdb_if_present(&buffer[..n]);
-
-                        let mut counter = 0;
-                        while counter < buffer_checked.len() {
-                            let (elems, count) =
-                                helper::get_elems_with_counter(counter, &buffer_checked);
-
-                            master_stream = helper::handle_connection_as_slave_with_master(
-                                master_stream,
-                                elems.clone(),
-                                &store_clone,
-                                &main_list_clone,
-                                "role:slave",
-                                offset,
-                            );
+    {
+        let aof = Arc::clone(&aof);
+        let dbs = Arc::clone(&dbs);
+        thread::spawn(move || aof::run_aof_cron(aof, dbs));
+    }
 
-                            offset += count - counter;
-                            counter = count;
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("[error] error reading stream: {e}");
-                    }
-                }
-            }
-        });
-        "role:slave"
+    // same defaults as redis: after 1 hour and 1 change, 5 minutes and 100 changes
+    // or 1 minute and 10000 changes
+    let save_points = match &args.save {
+        Some(save) => helper::parse_save_points(save)
+            .expect("[error] invalid save points (expected: SECONDS CHANGES ...)"),
+        None => vec![(3600, 1), (300, 100), (60, 10000)],
+    };
+    let save_state: types::SharedSaveState =
+        Arc::new(Mutex::new(types::SaveState::new(save_points)));
+
+    {
+        let dbs = Arc::clone(&dbs);
+        let dir = Arc::clone(&dir);
+        let dbfilename = Arc::clone(&dbfilename);
+        let save_state = Arc::clone(&save_state);
+        thread::spawn(move || helper::run_save_points(dbs, dir, dbfilename, save_state));
+    }
 
-    // if role != slave
-    } else {
-        "role:master"
+    let maxmemory: memory::SharedMaxMemory = Arc::new(Mutex::new(memory::MaxMemory::new()));
+    {
+        let mut maxmemory = maxmemory.lock().unwrap();
+        if let Some(limit) = &args.maxmemory {
+            maxmemory.limit = helper::parse_memory(limit).expect("[error] invalid maxmemory");
+        }
+        if let Some(policy) = &args.maxmemory_policy {
+            maxmemory.policy =
+                memory::EvictionPolicy::parse(policy).expect("[error] invalid maxmemory-policy");
+        }
+        if let Some(samples) = args.maxmemory_samples {
+            maxmemory.samples = samples;
+        }
+    }
+
+    // a new id every boot, so replicas of a previous run can't continue from its backlog
+    let repl_backlog_size = match &args.repl_backlog_size {
+        Some(size) => {
+            helper::parse_memory(size).expect("[error] invalid repl-backlog-size") as usize
+        }
+        None => 1024 * 1024,
     };
+    let repl_state: types::SharedReplicationState = Arc::new(Mutex::new(
+        types::ReplicationState::new(helper::random_replid(), repl_backlog_size, port.clone()),
+    ));
+
+    if let Some(replicaof_string) = args.replicaof {
+        // handling connection with master as slave
+        let (master_url, marter_port) = replicaof_string
+            .split_once(" ")
+            .expect("[error] invalid format (expected: HOST PORT)");
+
+        // handling connection as slave with master, reconnecting whenever it drops
+        let master_addr = format!("{master_url}:{marter_port}");
+        helper::set_master(Some(master_addr), &dbs, &userpw_hmap, &repl_state, false);
+    }
 
     //////////// HANDLING CONNECTIONS ////////////
 
-    let shared_replicas_count: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
-    let master_repl_offset = Arc::new(Mutex::new(0usize));
-    let tcpstream_vector: Arc<Mutex<Vec<TcpStream>>> = Arc::new(Mutex::new(Vec::new()));
-    let subs_htable: Arc<Mutex<HashMap<String, Vec<TcpStream>>>> =
-        Arc::new(Mutex::new(HashMap::new()));
-    let zset_hmap: Arc<Mutex<HashMap<String, types::ZSet>>> = Arc::new(Mutex::new(HashMap::new()));
-    let userpw_hmap: Arc<Mutex<HashMap<String, Vec<[u8; 32]>>>> =
+    let replicas: types::SharedReplicas = Arc::new(Mutex::new(Vec::new()));
+    let stats: types::SharedServerStats =
+        Arc::new(Mutex::new(types::ServerStats::new(helper::random_replid())));
+    let subs_htable: Arc<Mutex<HashMap<Vec<u8>, Vec<TcpStream>>>> =
         Arc::new(Mutex::new(HashMap::new()));
 
-    println!("[info] {} server with port number: {}", role, port);
+    {
+        let dbs = Arc::clone(&dbs);
+        let aof = Arc::clone(&aof);
+        let replicas = Arc::clone(&replicas);
+        let repl_state = Arc::clone(&repl_state);
+        let stats = Arc::clone(&stats);
+        thread::spawn(move || helper::run_active_expire(dbs, aof, replicas, repl_state, stats));
+    }
+
+    println!(
+        "[info] {} server with port number: {}",
+        repl_state.lock().unwrap().role(),
+        port
+    );
 
     let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap();
 
+    let ctx = types::ServerContext {
+        dbs,
+        dir,
+        dbfilename,
+        save_state,
+        aof,
+        replicas,
+        repl_state,
+        subs_htable,
+        users: userpw_hmap,
+        stats,
+        maxmemory,
+    };
+
     for connection in listener.incoming() {
-        let store_clone = Arc::clone(&store);
-        let dir_clone = Arc::clone(&dir);
-        let zset_hmap = Arc::clone(&zset_hmap);
-        let main_list_clone = Arc::clone(&main_list);
-        let dbfilename_clone = Arc::clone(&dbfilename);
-        let userpw_hmap_clone = Arc::clone(&userpw_hmap);
-        let subs_htable_clone = Arc::clone(&subs_htable);
-        let tcpstream_vector_clone = Arc::clone(&tcpstream_vector);
-        let master_repl_offset_clone = Arc::clone(&master_repl_offset);
-        let shared_replica_count_clone = Arc::clone(&shared_replicas_count);
-        let channels_subscribed: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
-        let user: Arc<Mutex<types::UserInfo>> = Arc::new(Mutex::new(UserInfo {
-            name: "default".to_string(),
-            is_authenticated: false,
-        }));
+        let ctx = ctx.clone();
 
         thread::spawn(move || match connection {
             Ok(mut stream) => {
                 println!("[info] accepted new connection");
+                {
+                    let mut stats = ctx.stats.lock().unwrap();
+                    stats.connected_clients += 1;
+                    stats.total_connections_received += 1;
+                }
 
-                let mut buffer = [0; 512];
-
-                let mut is_subscribed = false;
+                // bytes read from the socket but not yet parsed into a full command
+                let mut pending: Vec<u8> = Vec::new();
+                let mut pending_memory = memory::NotCounted::new(0);
+
+                let mut client = Client {
+                    db: 0,
+                    is_subscribed: false,
+                    channels_subscribed: Arc::new(Mutex::new(Vec::new())),
+                    user: Arc::new(Mutex::new(UserInfo {
+                        name: "default".to_string(),
+                        is_authenticated: false,
+                    })),
+                    multi_queue: None,
+                    multi_failed: false,
+                    listening_port: None,
+                };
 
                 loop {
-                    match stream.read(&mut buffer) {
-                        Ok(0) => {
-                            println!("[info] client disconnected");
-                            return;
-                        }
-                        Ok(n) => {
-                            if role == "role:slave" {
-                                stream = helper::handle_other_clients_as_slave(
-                                    stream,
-                                    &buffer,
-                                    &store_clone,
-                                    role,
-                                );
-
-                                continue;
+                    if !helper::read_more(&mut stream, &mut pending) {
+                        println!("[info] client disconnected");
+                        ctx.stats.lock().unwrap().connected_clients -= 1;
+                        return;
+                    }
+                    pending_memory.set(pending.capacity());
+
+                    let mut consumed = 0;
+
+                    // pipelined commands are executed and replied to in order
+                    while consumed < pending.len() {
+                        let (elems, len) = match helper::parse_frame(&pending[consumed..]) {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(e) => {
+                                let _ = stream.write_all(format!("-ERR {e}\r\n").as_bytes());
+                                println!("[error] closing connection: {e}");
+                                ctx.stats.lock().unwrap().connected_clients -= 1;
+                                return;
                             }
+                        };
 
-                            if is_subscribed {
-                                stream = helper::handle_subscribed_mode(
-                                    stream,
-                                    &buffer,
-                                    &subs_htable_clone,
-                                    &mut is_subscribed,
-                                    &channels_subscribed,
-                                );
+                        consumed += len;
 
-                                continue;
-                            }
+                        if elems.is_empty() {
+                            continue;
+                        }
 
-                            let elems = helper::get_elems(&buffer[..n]);
+                        ctx.stats.lock().unwrap().total_commands_processed += 1;
 
-                            let is_write_cmd = matches!(
-                                elems[0].to_ascii_lowercase().as_str(),
-                                "set" | "rpush" | "lpush" | "lpop" | "blpop" | "xadd" | "incr"
+                        // the role can change at any time through REPLICAOF, a replica
+                        // serves reads from its copy and only the master changes it
+                        let is_replica = ctx.repl_state.lock().unwrap().master_addr.is_some();
+                        if is_replica && command_table::is_write(&elems) {
+                            ctx.stats.lock().unwrap().record_rejected(
+                                command_table::stat_name(&elems),
+                                helper::READONLY,
                             );
+                            let _ = stream.write_all(helper::READONLY);
+                            continue;
+                        }
 
-                            if is_write_cmd {
-                                helper::handle_slaves(&tcpstream_vector_clone, &buffer[..n]);
-                                *master_repl_offset_clone.lock().unwrap() += buffer[..n].len();
-                            }
-
-                            stream = handle_connection(
-                                &buffer,
+                        if client.is_subscribed {
+                            stream = helper::handle_subscribed_mode(
                                 stream,
-                                &store_clone,
-                                &main_list_clone,
-                                role,
-                                &dir_clone,
-                                &dbfilename_clone,
-                                &tcpstream_vector_clone,
-                                &master_repl_offset_clone,
-                                &shared_replica_count_clone,
-                                &subs_htable_clone,
-                                &mut is_subscribed,
-                                &channels_subscribed,
-                                &zset_hmap,
-                                &userpw_hmap_clone,
-                                &user,
+                                elems,
+                                &ctx.subs_htable,
+                                &mut client.is_subscribed,
+                                &client.channels_subscribed,
                             );
+
+                            continue;
                         }
-                        Err(e) => {
-                            println!("[error] error reading stream: {e}");
-                        }
+
+                        stream = handle_connection(elems, stream, &ctx, &mut client);
                     }
+
+                    pending.drain(..consumed);
                 }
             }
             Err(e) => {
@@ -218,152 +283,222 @@ fn main() {
 
 //////////// CONNECTIONS HELPER FUNCTION ////////////
 
+/// state of one client connection
+struct Client {
+    db: usize, // chosen with SELECT, commands work on it
+    is_subscribed: bool,
+    channels_subscribed: Arc<Mutex<Vec<Vec<u8>>>>,
+    user: Arc<Mutex<types::UserInfo>>,
+    multi_queue: Option<Vec<Vec<Vec<u8>>>>, // commands queued after MULTI, None if not in a transaction
+    multi_failed: bool, // a command was refused while queueing, EXEC discards the transaction
+    listening_port: Option<u16>, // set by a replica during its handshake, before PSYNC
+}
+
 fn handle_connection(
-    bytes_received: &[u8],
+    elems: Vec<Vec<u8>>,
     mut stream: TcpStream,
-    store: &types::SharedStore,
-    main_list_store: &types::SharedMainList,
-    role: &str,
-    dir_clone: &Arc<Mutex<Option<String>>>,
-    dbfilename_clone: &Arc<Mutex<Option<String>>>,
-    tcpstream_vector_clone: &Arc<Mutex<Vec<TcpStream>>>,
-    master_repl_offset: &Arc<Mutex<usize>>,
-    shared_replica_count_clone: &Arc<Mutex<usize>>,
-    subs_htable: &Arc<Mutex<HashMap<String, Vec<TcpStream>>>>,
-    is_subscribed: &mut bool,
-    channels_subscribed: &Arc<Mutex<Vec<String>>>,
-    zset_hmap: &Arc<Mutex<HashMap<String, types::ZSet>>>,
-    userpw_hmap_clone: &Arc<Mutex<HashMap<String, Vec<[u8; 32]>>>>,
-    user_guard: &Arc<Mutex<types::UserInfo>>,
+    ctx: &types::ServerContext,
+    client: &mut Client,
 ) -> TcpStream {
-    match bytes_received[0] {
-        b'*' => {
-            let mut elems = helper::get_elems(bytes_received);
-            println!("[info] elements array: {:?}", elems);
-
-            let resp: String = match elems[0].to_ascii_lowercase().as_str() {
-                "echo" => commands::handle_echo(elems),
-
-                "ping" => "+PONG\r\n".to_string(),
-
-                "set" => commands::handle_set(elems, store),
-
-                "get" => commands::handle_get(dir_clone, dbfilename_clone, &elems, store),
-
-                "rpush" => commands::handle_rpush(elems, main_list_store),
-
-                "lpush" => commands::handle_lpush(elems, main_list_store),
-
-                "lrange" => commands::handle_lrange(elems, main_list_store),
-
-                "llen" => commands::handle_llen(elems, main_list_store),
-
-                "lpop" => commands::handle_lpop(elems, main_list_store),
-
-                "blpop" => commands::handle_blpop(elems, main_list_store),
-
-                "type" => commands::handle_type(elems, store),
+    let types::ServerContext {
+        dbs,
+        dir,
+        dbfilename,
+        save_state,
+        aof,
+        replicas,
+        repl_state,
+        subs_htable,
+        users,
+        stats,
+        maxmemory,
+    } = ctx;
+    let Client {
+        db,
+        is_subscribed,
+        channels_subscribed,
+        user,
+        multi_queue,
+        multi_failed,
+        listening_port,
+    } = client;
+
+    let cmd = helper::cmd_name(&elems[0]);
+    let stat_name = command_table::stat_name(&elems);
+    let started = Instant::now();
+
+    // the handlers expect their arguments to be there, a command without them
+    // is refused before it gets near a lock
+    if let Err(resp) = command_table::check_arity(&elems) {
+        stats.lock().unwrap().record_rejected(stat_name, &resp);
+        if multi_queue.is_some() {
+            *multi_failed = true;
+        }
+        let _ = stream.write_all(&resp);
+        return stream;
+    }
 
-                "xadd" => commands::handle_xadd(&mut elems, store),
+    // inside a transaction everything but EXEC/DISCARD/MULTI gets queued
+    if let Some(queue) = multi_queue {
+        let resp = match cmd.as_str() {
+            "exec" if *multi_failed => {
+                *multi_queue = None;
+                *multi_failed = false;
+                b"-EXECABORT Transaction discarded because of previous errors.\r\n".to_vec()
+            }
+            "exec" => {
+                let resp = helper::handle_exec_under_multi(queue, ctx, db, user);
+                *multi_queue = None;
+                resp
+            }
+            "discard" => {
+                *multi_queue = None;
+                *multi_failed = false;
+                b"+OK\r\n".to_vec()
+            }
+            "multi" => b"-ERR MULTI calls can not be nested\r\n".to_vec(),
+            _ => {
+                queue.push(elems);
+                b"+QUEUED\r\n".to_vec()
+            }
+        };
 
-                "xrange" => commands::handle_xrange(&mut elems, store),
+        // queued commands are counted once EXEC runs them
+        if matches!(cmd.as_str(), "exec" | "discard" | "multi") {
+            let usec = started.elapsed().as_micros() as u64;
+            stats.lock().unwrap().record_call(stat_name, usec, &resp);
+        }
 
-                "xread" => commands::handle_xread(&mut elems, store),
+        let _ = stream.write_all(&resp);
+        return stream;
+    }
 
-                "incr" => commands::handle_incr(&mut elems, store),
+    // a write and its propagation to the aof and replicas can't interleave with
+    // other writes, except blocking ones which must not hold the lock while blocked
+    let is_write = command_table::is_write(&elems);
+    let mut aof_guard = if is_write && !command_table::has_flag(&elems, command_table::BLOCKING) {
+        Some(aof.lock().unwrap())
+    } else {
+        None
+    };
 
-                "multi" => {
-                    commands::handle_multi(&mut stream, store, main_list_store);
-                    "".to_string()
-                }
+    // writes are refused while the aof can't be written, rather than kept only
+    // in memory
+    let misconf = match aof_guard.as_ref() {
+        Some(aof_guard) => aof_guard.misconf_reply(),
+        None if is_write => aof.lock().unwrap().misconf_reply(),
+        None => None,
+    };
+    if let Some(misconf) = misconf {
+        stats.lock().unwrap().record_rejected(stat_name, &misconf);
+        let _ = stream.write_all(&misconf);
+        return stream;
+    }
 
-                // discard without multi
-                "discard" => "-ERR DISCARD without MULTI\r\n".to_string(),
-
-                // exec without multi
-                "exec" => "-ERR EXEC without MULTI\r\n".to_string(),
-
-                "info" => match commands::handle_info(&elems, role) {
-                    Some(resp) => resp,
-                    None => "".to_string(),
-                },
-
-                "replconf" => {
-                    match commands::handle_replconf(
-                        &elems,
-                        master_repl_offset,
-                        shared_replica_count_clone,
-                    ) {
-                        Some(resp) => resp,
-                        None => "".to_string(),
-                    }
-                }
+    // over maxmemory keys are evicted first, a write that needs more memory is
+    // refused if that wasn't enough
+    if let Some(aof_guard) = aof_guard.as_mut() {
+        let under_limit =
+            memory::evict_if_needed(dbs, maxmemory, aof_guard, replicas, repl_state, stats);
+        if !under_limit && command_table::has_flag(&elems, command_table::DENYOOM) {
+            stats
+                .lock()
+                .unwrap()
+                .record_rejected(stat_name, memory::OOM);
+            let _ = stream.write_all(memory::OOM);
+            return stream;
+        }
+    }
 
-                "psync" => {
-                    commands::handle_psync(&mut stream, tcpstream_vector_clone);
-                    "".to_string()
-                }
+    let logged_elems = if is_write { Some(elems.clone()) } else { None };
+    if aof_guard.is_some() {
+        helper::start_lazy_expiry();
+    }
 
-                "wait" => commands::handle_wait(
-                    &elems,
-                    tcpstream_vector_clone,
-                    master_repl_offset,
-                    shared_replica_count_clone,
-                ),
-                "config" => commands::handle_config(dir_clone, dbfilename_clone, elems),
+    let resp: Vec<u8> = match cmd.as_str() {
+        "multi" => {
+            *multi_queue = Some(Vec::new());
+            b"+OK\r\n".to_vec()
+        }
 
-                "keys" => commands::handle_keys(dir_clone, dbfilename_clone, elems),
+        // discard without multi
+        "discard" => b"-ERR DISCARD without MULTI\r\n".to_vec(),
 
-                "subscribe" => commands::handle_subscribe(
-                    elems,
-                    is_subscribed,
-                    channels_subscribed,
-                    subs_htable,
-                    &mut stream,
-                ),
+        // exec without multi
+        "exec" => b"-ERR EXEC without MULTI\r\n".to_vec(),
 
-                "publish" => commands::handle_publish(elems, subs_htable),
+        "select" => commands::handle_select(&elems, dbs, db),
 
-                "unsubscribe" => commands::handle_unsubscribe(
-                    elems,
-                    subs_htable,
-                    channels_subscribed,
-                    &mut stream,
-                ),
+        "info" => commands::handle_info(&elems, ctx),
 
-                "zadd" => commands::handle_zadd(zset_hmap, elems),
+        "replconf" => {
+            commands::handle_replconf(&elems, &stream, replicas, listening_port).unwrap_or_default()
+        }
 
-                "zrank" => commands::handle_zrank(zset_hmap, elems),
+        "psync" => {
+            commands::handle_psync(
+                &mut stream,
+                &elems,
+                replicas,
+                dbs,
+                aof,
+                repl_state,
+                *listening_port,
+            );
+            Vec::new()
+        }
 
-                "zrange" => commands::handle_zrange(zset_hmap, elems),
+        "replicaof" | "slaveof" => {
+            commands::handle_replicaof(&elems, dbs, users, aof, replicas, repl_state)
+        }
 
-                "zcard" => commands::handle_zcard(zset_hmap, elems),
+        "wait" => commands::handle_wait(&elems, replicas, aof, repl_state),
+        "config" => commands::handle_config(dir, dbfilename, save_state, aof, maxmemory, elems),
 
-                "zscore" => commands::handle_zscore(zset_hmap, elems),
+        "save" => commands::handle_save(dbs, dir, dbfilename, save_state),
 
-                "zrem" => commands::handle_zrem(zset_hmap, elems),
+        "bgsave" => commands::handle_bgsave(dbs, dir, dbfilename, save_state),
 
-                "geoadd" => commands::handle_geoadd(zset_hmap, elems),
+        "lastsave" => commands::handle_lastsave(save_state),
 
-                "geopos" => commands::handle_geopos(zset_hmap, elems),
+        "bgrewriteaof" => commands::handle_bgrewriteaof(aof, dbs),
 
-                "geodist" => commands::handle_geodist(zset_hmap, elems),
+        "subscribe" => commands::handle_subscribe(
+            elems,
+            is_subscribed,
+            channels_subscribed,
+            subs_htable,
+            &mut stream,
+        ),
 
-                "geosearch" => commands::handle_geosearch(zset_hmap, elems),
+        "publish" => commands::handle_publish(elems, subs_htable),
 
-                "acl" => commands::handle_acl(elems, userpw_hmap_clone, user_guard),
+        "unsubscribe" => {
+            commands::handle_unsubscribe(elems, subs_htable, channels_subscribed, &mut stream)
+        }
 
-                "auth" => commands::handle_auth(elems, userpw_hmap_clone, user_guard),
+        "auth" => commands::handle_auth(elems, users, user),
 
-                _ => "-ERR Not a valid command\r\n".to_string(),
-            };
-            let _ = stream.write_all(resp.as_bytes());
-        }
+        // everything else works on the dataset, the same way everywhere
+        _ => helper::execute_command(elems, dbs, *db, users, user),
+    };
 
-        _ => {
-            eprint!("[error] invalid resp string");
+    if let Some(elems) = logged_elems {
+        let mut aof_guard = aof_guard.take().unwrap_or_else(|| aof.lock().unwrap());
+        let expired = helper::finish_lazy_expiry();
+        helper::propagate_expired(*db, &expired, &mut aof_guard, replicas, repl_state, stats);
+        let stored_expiry = helper::take_stored_expiry();
+        if let Some(payload) = helper::propagated_command(&elems, &resp, stored_expiry) {
+            helper::propagate_write(*db, &payload, &mut aof_guard, replicas, repl_state);
+            // only the writes that changed the dataset count towards the save points
+            save_state.lock().unwrap().dirty += 1;
         }
     }
 
+    let usec = started.elapsed().as_micros() as u64;
+    stats.lock().unwrap().record_call(stat_name, usec, &resp);
+
+    let _ = stream.write_all(&resp);
+
     stream
 }
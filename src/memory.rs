@@ -0,0 +1,255 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::aof;
+use crate::helper;
+use crate::types;
+
+/// the system allocator, counting the bytes in use like zmalloc does for redis
+pub struct CountingAllocator;
+
+static USED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc(layout) };
+        if !ptr.is_null() {
+            USED_MEMORY.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { System.alloc_zeroed(layout) };
+        if !ptr.is_null() {
+            USED_MEMORY.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) };
+        USED_MEMORY.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { System.realloc(ptr, layout, new_size) };
+        if !new_ptr.is_null() {
+            USED_MEMORY.fetch_add(new_size, Ordering::Relaxed);
+            USED_MEMORY.fetch_sub(layout.size(), Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+/// bytes currently allocated by the whole process
+pub fn used_memory() -> usize {
+    USED_MEMORY.load(Ordering::Relaxed)
+}
+
+static NOT_COUNTED_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// bytes held outside the keyspace for as long as it lives: a snapshot being
+/// written, a client's unparsed input. evicting keys wouldn't free them, so
+/// they don't count against maxmemory
+#[derive(Debug)]
+pub struct NotCounted(usize);
+
+impl NotCounted {
+    pub fn new(bytes: usize) -> Self {
+        NOT_COUNTED_MEMORY.fetch_add(bytes, Ordering::Relaxed);
+        Self(bytes)
+    }
+
+    /// the bytes held changed
+    pub fn set(&mut self, bytes: usize) {
+        NOT_COUNTED_MEMORY.fetch_add(bytes, Ordering::Relaxed);
+        NOT_COUNTED_MEMORY.fetch_sub(self.0, Ordering::Relaxed);
+        self.0 = bytes;
+    }
+}
+
+impl Drop for NotCounted {
+    fn drop(&mut self) {
+        NOT_COUNTED_MEMORY.fetch_sub(self.0, Ordering::Relaxed);
+    }
+}
+
+/// bytes that count against maxmemory: all but the buffers and copies that
+/// evicting keys can't shrink, the same ones redis leaves out
+fn counted_memory(aof: &aof::Aof, repl_state: &types::SharedReplicationState) -> usize {
+    let backlog = repl_state.lock().unwrap().backlog.capacity();
+    let not_counted = NOT_COUNTED_MEMORY.load(Ordering::Relaxed) + backlog + aof.buffered_bytes();
+    used_memory().saturating_sub(not_counted)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    NoEviction,
+    AllKeysLru,
+    VolatileLru,
+    AllKeysLfu,
+    VolatileLfu,
+    AllKeysRandom,
+    VolatileRandom,
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "noeviction" => Some(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Some(EvictionPolicy::AllKeysLru),
+            "volatile-lru" => Some(EvictionPolicy::VolatileLru),
+            "allkeys-lfu" => Some(EvictionPolicy::AllKeysLfu),
+            "volatile-lfu" => Some(EvictionPolicy::VolatileLfu),
+            "allkeys-random" => Some(EvictionPolicy::AllKeysRandom),
+            "volatile-random" => Some(EvictionPolicy::VolatileRandom),
+            "volatile-ttl" => Some(EvictionPolicy::VolatileTtl),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::VolatileLfu => "volatile-lfu",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileRandom => "volatile-random",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    /// only keys with a ttl can be evicted
+    fn is_volatile(&self) -> bool {
+        matches!(
+            self,
+            EvictionPolicy::VolatileLru
+                | EvictionPolicy::VolatileLfu
+                | EvictionPolicy::VolatileRandom
+                | EvictionPolicy::VolatileTtl
+        )
+    }
+}
+
+/// maxmemory settings
+#[derive(Debug)]
+pub struct MaxMemory {
+    pub limit: u64, // 0 means no limit
+    pub policy: EvictionPolicy,
+    pub samples: usize, // keys looked at to pick each one to evict
+}
+
+impl MaxMemory {
+    pub fn new() -> Self {
+        Self {
+            limit: 0,
+            policy: EvictionPolicy::NoEviction,
+            samples: 5,
+        }
+    }
+}
+
+pub type SharedMaxMemory = Arc<Mutex<MaxMemory>>;
+
+pub const OOM: &[u8] = b"-OOM command not allowed when used memory > 'maxmemory'.\r\n";
+
+/// evicts keys following the policy until memory is back under maxmemory, the
+/// evicted keys are propagated as DELs so the aof guard must be held
+/// returns false when it's still over the limit: noeviction, or nothing left to evict
+pub fn evict_if_needed(
+    dbs: &types::SharedDatabases,
+    maxmemory: &SharedMaxMemory,
+    aof: &mut aof::Aof,
+    replicas: &types::SharedReplicas,
+    repl_state: &types::SharedReplicationState,
+    stats: &types::SharedServerStats,
+) -> bool {
+    let (limit, policy, samples) = {
+        let maxmemory = maxmemory.lock().unwrap();
+        (
+            maxmemory.limit as usize,
+            maxmemory.policy,
+            maxmemory.samples,
+        )
+    };
+
+    if limit == 0 || counted_memory(aof, repl_state) <= limit {
+        return true;
+    }
+    if policy == EvictionPolicy::NoEviction {
+        return false;
+    }
+
+    // keys evicted from each db
+    let mut evicted = vec![Vec::new(); dbs.len()];
+    {
+        let mut maps: Vec<_> = dbs.iter().map(|db| db.0.lock().unwrap()).collect();
+
+        while counted_memory(aof, repl_state) > limit {
+            // the best candidate of every db's sample
+            let Some((_, db, key)) = maps
+                .iter()
+                .enumerate()
+                .filter_map(|(db, map)| {
+                    pick_victim(map, policy, samples).map(|(score, key)| (score, db, key))
+                })
+                .min_by_key(|(score, _, _)| *score)
+            else {
+                break;
+            };
+            // dropped right here, so the next check sees the memory it freed
+            maps[db].remove(&key);
+            evicted[db].push(key);
+        }
+    }
+
+    let total: usize = evicted.iter().map(Vec::len).sum();
+    if total > 0 {
+        println!("[info] evicted {} keys to stay under maxmemory", total);
+        stats.lock().unwrap().evicted_keys += total as u64;
+        for (db, keys) in evicted.iter().enumerate() {
+            helper::propagate_deletions(db, keys, aof, replicas, repl_state);
+        }
+    }
+
+    counted_memory(aof, repl_state) <= limit
+}
+
+/// best key to evict among a random sample of the candidates with its score,
+/// an approximation of the policy that doesn't need to keep every key ordered
+fn pick_victim(
+    map: &types::Keyspace,
+    policy: EvictionPolicy,
+    samples: usize,
+) -> Option<(u64, Vec<u8>)> {
+    let sample = if policy.is_volatile() {
+        map.sample_volatile(samples.max(1))
+    } else {
+        map.sample_keys(samples.max(1))
+    };
+
+    // the lowest score is evicted
+    let now = helper::now_unix_ms();
+    sample
+        .into_iter()
+        .filter_map(|key| {
+            let entry = map.get(&key)?;
+            let score = match policy {
+                EvictionPolicy::AllKeysLru | EvictionPolicy::VolatileLru => entry.last_access,
+                EvictionPolicy::AllKeysLfu | EvictionPolicy::VolatileLfu => {
+                    // least frequently used, the least recently used of those
+                    ((entry.lfu_count(now) as u64) << 48) | (entry.last_access & ((1 << 48) - 1))
+                }
+                EvictionPolicy::VolatileTtl => entry.expires_at.unwrap_or(u64::MAX),
+                // random scores, so the dbs are picked at random too
+                _ => helper::random_u64(),
+            };
+            Some((score, key))
+        })
+        .min_by_key(|(score, _)| *score)
+}
@@ -0,0 +1,1645 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::helper;
+use crate::memory;
+use crate::types;
+
+// opcodes
+const OPCODE_FUNCTION2: u8 = 0xf5;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+// value types
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_ZSET: u8 = 3;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+const TYPE_HASH_ZIPMAP: u8 = 9;
+const TYPE_LIST_ZIPLIST: u8 = 10;
+const TYPE_SET_INTSET: u8 = 11;
+const TYPE_ZSET_ZIPLIST: u8 = 12;
+const TYPE_HASH_ZIPLIST: u8 = 13;
+const TYPE_LIST_QUICKLIST: u8 = 14;
+const TYPE_STREAM_LISTPACKS: u8 = 15;
+const TYPE_HASH_LISTPACK: u8 = 16;
+const TYPE_ZSET_LISTPACK: u8 = 17;
+const TYPE_LIST_QUICKLIST_2: u8 = 18;
+const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+const TYPE_SET_LISTPACK: u8 = 20;
+const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+// special string encodings, stored in the low 6 bits of a length byte
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+// quicklist2 node containers
+const QUICKLIST_NODE_PLAIN: u64 = 1;
+const QUICKLIST_NODE_PACKED: u64 = 2;
+
+// stream entry flag marking a deleted entry
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 2;
+
+pub const MAX_RDB_VERSION: u32 = 12;
+
+#[derive(Debug)]
+pub enum RdbError {
+    UnexpectedEof { offset: usize },
+    InvalidHeader,
+    UnsupportedVersion(u32),
+    UnsupportedOpcode(u8),
+    UnknownValueType(u8),
+    InvalidStringEncoding(u8),
+    CorruptLzf,
+    CorruptEncoding(&'static str),
+    ChecksumMismatch { expected: u64, actual: u64 },
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RdbError::UnexpectedEof { offset } => {
+                write!(f, "unexpected end of file at offset {offset}")
+            }
+            RdbError::InvalidHeader => write!(f, "invalid header, expected REDIS<version>"),
+            RdbError::UnsupportedVersion(v) => write!(f, "unsupported rdb version {v}"),
+            RdbError::UnsupportedOpcode(op) => write!(f, "unsupported opcode 0x{op:02x}"),
+            RdbError::UnknownValueType(t) => write!(f, "unknown value type {t}"),
+            RdbError::InvalidStringEncoding(e) => write!(f, "invalid string encoding {e}"),
+            RdbError::CorruptLzf => write!(f, "corrupt lzf compressed string"),
+            RdbError::CorruptEncoding(what) => write!(f, "corrupt {what} encoding"),
+            RdbError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch (expected {expected:016x}, got {actual:016x})"
+            ),
+        }
+    }
+}
+
+/// single key as read from the file
+#[derive(Debug)]
+pub struct RdbKey {
+    pub db: usize,
+    pub key: Vec<u8>,
+    pub value: types::StoredValue,
+    pub expires_at_ms: Option<u64>, // absolute unix time in ms
+}
+
+/// everything found in an rdb file
+#[derive(Debug, Default)]
+pub struct RdbContents {
+    pub version: u32,
+    pub size: usize, // bytes taken by the rdb, anything after it is not part of the file
+    pub aux: Vec<(Vec<u8>, Vec<u8>)>,
+    pub keys: Vec<RdbKey>,
+}
+
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn read_bytes(&mut self, n: usize) -> Result<&'a [u8], RdbError> {
+        let end = self
+            .pos
+            .checked_add(n)
+            .filter(|end| *end <= self.data.len())
+            .ok_or(RdbError::UnexpectedEof { offset: self.pos })?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    fn read_u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_length_or_encoding(&mut self) -> Result<Length, RdbError> {
+        let first = self.read_u8()?;
+        let len = match first >> 6 {
+            0b00 => (first & 0x3f) as u64,
+            0b01 => (((first & 0x3f) as u64) << 8) | self.read_u8()? as u64,
+            0b10 => match first {
+                0x80 => u32::from_be_bytes(self.read_array()?) as u64,
+                0x81 => u64::from_be_bytes(self.read_array()?),
+                _ => return Err(RdbError::CorruptEncoding("length")),
+            },
+            _ => return Ok(Length::Encoded(first & 0x3f)),
+        };
+        Ok(Length::Len(len))
+    }
+
+    fn read_length(&mut self) -> Result<u64, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(RdbError::CorruptEncoding("length")),
+        }
+    }
+
+    /// lengths used as element counts, checked against what is left in the file
+    /// so a corrupt count can't make us allocate huge buffers
+    fn read_count(&mut self) -> Result<usize, RdbError> {
+        let len = self.read_length()?;
+        if len > (self.data.len() - self.pos) as u64 {
+            return Err(RdbError::CorruptEncoding("length"));
+        }
+        Ok(len as usize)
+    }
+
+    fn read_string(&mut self) -> Result<Vec<u8>, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Len(len) => {
+                let len = usize::try_from(len).map_err(|_| RdbError::CorruptEncoding("length"))?;
+                Ok(self.read_bytes(len)?.to_vec())
+            }
+            Length::Encoded(ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => Ok(i16::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(ENC_INT32) => Ok(i32::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.read_count()?;
+                let uncompressed_len = self.read_length()?;
+                let compressed = self.read_bytes(compressed_len)?;
+                lzf_decompress(compressed, uncompressed_len as usize)
+            }
+            Length::Encoded(other) => Err(RdbError::InvalidStringEncoding(other)),
+        }
+    }
+
+    /// scores of the old zset type are stored as strings with a 1 byte length
+    fn read_string_double(&mut self) -> Result<f64, RdbError> {
+        let len = self.read_u8()?;
+        match len {
+            253 => Ok(f64::NAN),
+            254 => Ok(f64::INFINITY),
+            255 => Ok(f64::NEG_INFINITY),
+            _ => {
+                let bytes = self.read_bytes(len as usize)?;
+                std::str::from_utf8(bytes)
+                    .ok()
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .ok_or(RdbError::CorruptEncoding("double"))
+            }
+        }
+    }
+
+    fn read_binary_double(&mut self) -> Result<f64, RdbError> {
+        Ok(f64::from_le_bytes(self.read_array()?))
+    }
+}
+
+/// parses a whole rdb file
+/// the trailing checksum is verified unless it was written as zero (checksums disabled)
+pub fn parse(data: &[u8]) -> Result<RdbContents, RdbError> {
+    let mut r = Reader::new(data);
+
+    let header = r.read_bytes(9).map_err(|_| RdbError::InvalidHeader)?;
+    if &header[..5] != b"REDIS" {
+        return Err(RdbError::InvalidHeader);
+    }
+    let version = std::str::from_utf8(&header[5..])
+        .ok()
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or(RdbError::InvalidHeader)?;
+    if version == 0 || version > MAX_RDB_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+
+    let mut contents = RdbContents {
+        version,
+        ..Default::default()
+    };
+    let mut db = 0;
+    let mut expires_at_ms: Option<u64> = None;
+
+    loop {
+        let opcode = r.read_u8()?;
+        match opcode {
+            OPCODE_EOF => break,
+            OPCODE_AUX => {
+                let key = r.read_string()?;
+                let value = r.read_string()?;
+                contents.aux.push((key, value));
+            }
+            OPCODE_RESIZEDB => {
+                // only sizing hints
+                r.read_length()?;
+                r.read_length()?;
+            }
+            OPCODE_SELECTDB => {
+                db = r.read_length()? as usize;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expires_at_ms = Some(u64::from_le_bytes(r.read_array()?));
+            }
+            OPCODE_EXPIRETIME => {
+                expires_at_ms = Some(u32::from_le_bytes(r.read_array()?) as u64 * 1000);
+            }
+            OPCODE_FREQ => {
+                r.read_u8()?;
+            }
+            OPCODE_IDLE => {
+                r.read_length()?;
+            }
+            OPCODE_FUNCTION2 => {
+                // function libraries are not supported, the code is skipped
+                r.read_string()?;
+            }
+            OPCODE_MODULE_AUX => return Err(RdbError::UnsupportedOpcode(opcode)),
+            value_type => {
+                let key = r.read_string()?;
+                let value = read_value(&mut r, value_type)?;
+                contents.keys.push(RdbKey {
+                    db,
+                    key,
+                    value,
+                    expires_at_ms: expires_at_ms.take(),
+                });
+            }
+        }
+    }
+
+    // checksum was added in version 5
+    if version >= 5 {
+        let checksum_offset = r.pos;
+        let expected = u64::from_le_bytes(r.read_array()?);
+        if expected != 0 {
+            let actual = crc64(0, &data[..checksum_offset]);
+            if actual != expected {
+                return Err(RdbError::ChecksumMismatch { expected, actual });
+            }
+        }
+    }
+
+    contents.size = r.pos;
+    Ok(contents)
+}
+
+fn read_value(r: &mut Reader, value_type: u8) -> Result<types::StoredValue, RdbError> {
+    let value = match value_type {
+        TYPE_STRING => types::StoredValue::String(r.read_string()?),
+        TYPE_LIST => {
+            let len = r.read_count()?;
+            let mut list = VecDeque::with_capacity(len);
+            for _ in 0..len {
+                list.push_back(r.read_string()?);
+            }
+            types::StoredValue::List(list)
+        }
+        TYPE_SET => {
+            let len = r.read_count()?;
+            let mut set = types::Set::default();
+            for _ in 0..len {
+                set.insert(r.read_string()?);
+            }
+            types::StoredValue::Set(set)
+        }
+        TYPE_ZSET | TYPE_ZSET_2 => {
+            let len = r.read_count()?;
+            let mut zset = types::ZSet::new();
+            for _ in 0..len {
+                let member = r.read_string()?;
+                let score = if value_type == TYPE_ZSET_2 {
+                    r.read_binary_double()?
+                } else {
+                    r.read_string_double()?
+                };
+                zset.insert(member, score);
+            }
+            types::StoredValue::ZSet(zset)
+        }
+        TYPE_HASH => {
+            let len = r.read_count()?;
+            let mut hash = types::Hash::default();
+            for _ in 0..len {
+                let field = r.read_string()?;
+                let value = r.read_string()?;
+                hash.insert(field, value);
+            }
+            types::StoredValue::Hash(hash)
+        }
+        TYPE_HASH_ZIPMAP => types::StoredValue::Hash(parse_zipmap(&r.read_string()?)?),
+        TYPE_LIST_ZIPLIST => types::StoredValue::List(parse_ziplist(&r.read_string()?)?.into()),
+        TYPE_SET_INTSET => types::StoredValue::Set(
+            parse_intset(&r.read_string()?)?
+                .into_iter()
+                .map(|n| n.to_string().into_bytes())
+                .collect(),
+        ),
+        TYPE_SET_LISTPACK => {
+            types::StoredValue::Set(parse_listpack(&r.read_string()?)?.into_iter().collect())
+        }
+        TYPE_ZSET_ZIPLIST | TYPE_ZSET_LISTPACK => {
+            let blob = r.read_string()?;
+            let items = if value_type == TYPE_ZSET_ZIPLIST {
+                parse_ziplist(&blob)?
+            } else {
+                parse_listpack(&blob)?
+            };
+            types::StoredValue::ZSet(pairs_to_zset(items)?)
+        }
+        TYPE_HASH_ZIPLIST | TYPE_HASH_LISTPACK => {
+            let blob = r.read_string()?;
+            let items = if value_type == TYPE_HASH_ZIPLIST {
+                parse_ziplist(&blob)?
+            } else {
+                parse_listpack(&blob)?
+            };
+            types::StoredValue::Hash(pairs_to_hash(items)?)
+        }
+        TYPE_LIST_QUICKLIST => {
+            let nodes = r.read_count()?;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                list.extend(parse_ziplist(&r.read_string()?)?);
+            }
+            types::StoredValue::List(list)
+        }
+        TYPE_LIST_QUICKLIST_2 => {
+            let nodes = r.read_count()?;
+            let mut list = VecDeque::new();
+            for _ in 0..nodes {
+                let container = r.read_length()?;
+                let blob = r.read_string()?;
+                match container {
+                    QUICKLIST_NODE_PLAIN => list.push_back(blob),
+                    QUICKLIST_NODE_PACKED => list.extend(parse_listpack(&blob)?),
+                    _ => return Err(RdbError::CorruptEncoding("quicklist")),
+                }
+            }
+            types::StoredValue::List(list)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            types::StoredValue::Stream(read_stream(r, value_type)?)
+        }
+        other => return Err(RdbError::UnknownValueType(other)),
+    };
+    Ok(value)
+}
+
+fn pairs_to_zset(items: Vec<Vec<u8>>) -> Result<types::ZSet, RdbError> {
+    if !items.len().is_multiple_of(2) {
+        return Err(RdbError::CorruptEncoding("zset"));
+    }
+    let mut zset = types::ZSet::new();
+    for pair in items.chunks_exact(2) {
+        let score = std::str::from_utf8(&pair[1])
+            .ok()
+            .and_then(|s| s.parse::<f64>().ok())
+            .ok_or(RdbError::CorruptEncoding("zset"))?;
+        zset.insert(pair[0].clone(), score);
+    }
+    Ok(zset)
+}
+
+fn pairs_to_hash(items: Vec<Vec<u8>>) -> Result<types::Hash, RdbError> {
+    if !items.len().is_multiple_of(2) {
+        return Err(RdbError::CorruptEncoding("hash"));
+    }
+    let mut items = items.into_iter();
+    let mut hash = types::Hash::default();
+    while let (Some(field), Some(value)) = (items.next(), items.next()) {
+        hash.insert(field, value);
+    }
+    Ok(hash)
+}
+
+/// stream ids inside listpack node keys are two big endian u64s
+fn read_raw_stream_id(bytes: &[u8]) -> Result<(u64, u64), RdbError> {
+    if bytes.len() != 16 {
+        return Err(RdbError::CorruptEncoding("stream id"));
+    }
+    let ms = u64::from_be_bytes(bytes[..8].try_into().unwrap());
+    let seq = u64::from_be_bytes(bytes[8..].try_into().unwrap());
+    Ok((ms, seq))
+}
+
+fn read_stream(r: &mut Reader, value_type: u8) -> Result<Vec<types::Entry>, RdbError> {
+    let mut entries = Vec::new();
+
+    let nodes = r.read_count()?;
+    for _ in 0..nodes {
+        let (master_ms, master_seq) = read_raw_stream_id(&r.read_string()?)?;
+        let items = parse_listpack(&r.read_string()?)?;
+        read_stream_node(&items, master_ms, master_seq, &mut entries)?;
+    }
+
+    // length, last id
+    r.read_length()?;
+    r.read_length()?;
+    r.read_length()?;
+    if value_type >= TYPE_STREAM_LISTPACKS_2 {
+        // first id, max deleted id, entries added
+        for _ in 0..5 {
+            r.read_length()?;
+        }
+    }
+
+    // consumer groups are not supported, they are read and dropped
+    let groups = r.read_count()?;
+    for _ in 0..groups {
+        r.read_string()?;
+        r.read_length()?;
+        r.read_length()?;
+        if value_type >= TYPE_STREAM_LISTPACKS_2 {
+            r.read_length()?;
+        }
+
+        // global pending entries list: raw id, delivery time, delivery count
+        let pending = r.read_count()?;
+        for _ in 0..pending {
+            r.read_bytes(16)?;
+            r.read_bytes(8)?;
+            r.read_length()?;
+        }
+
+        let consumers = r.read_count()?;
+        for _ in 0..consumers {
+            r.read_string()?;
+            r.read_bytes(8)?;
+            if value_type >= TYPE_STREAM_LISTPACKS_3 {
+                r.read_bytes(8)?;
+            }
+            let pending = r.read_count()?;
+            r.read_bytes(pending * 16)?;
+        }
+    }
+
+    Ok(entries)
+}
+
+/// decodes one stream listpack node
+/// layout: count, deleted, master field count, master fields..., 0
+/// then per entry: flags, ms diff, seq diff, fields/values, lp-count
+fn read_stream_node(
+    items: &[Vec<u8>],
+    master_ms: u64,
+    master_seq: u64,
+    entries: &mut Vec<types::Entry>,
+) -> Result<(), RdbError> {
+    let corrupt = || RdbError::CorruptEncoding("stream");
+    let int_of = |item: Option<&Vec<u8>>| -> Result<i64, RdbError> {
+        item.and_then(|item| std::str::from_utf8(item).ok()?.parse::<i64>().ok())
+            .ok_or_else(corrupt)
+    };
+    let mut items = items.iter();
+
+    let count = int_of(items.next())?;
+    let deleted = int_of(items.next())?;
+    let master_field_count = int_of(items.next())?;
+
+    let mut master_fields = Vec::new();
+    for _ in 0..master_field_count {
+        master_fields.push(items.next().ok_or_else(corrupt)?.clone());
+    }
+    items.next().ok_or_else(corrupt)?; // master entry terminator
+
+    for _ in 0..(count + deleted) {
+        let flags = int_of(items.next())?;
+        let ms = master_ms.wrapping_add(int_of(items.next())? as u64);
+        let seq = master_seq.wrapping_add(int_of(items.next())? as u64);
+
+        let mut map = HashMap::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in &master_fields {
+                map.insert(field.clone(), items.next().ok_or_else(corrupt)?.clone());
+            }
+        } else {
+            let field_count = int_of(items.next())?;
+            for _ in 0..field_count {
+                let field = items.next().ok_or_else(corrupt)?.clone();
+                let value = items.next().ok_or_else(corrupt)?.clone();
+                map.insert(field, value);
+            }
+        }
+        items.next().ok_or_else(corrupt)?; // lp-count
+
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push(types::Entry {
+                id: format!("{ms}-{seq}"),
+                map,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// ziplist: zlbytes(u32) zltail(u32) zllen(u16) entries... 0xff
+/// every entry is prevlen, encoding, data
+fn parse_ziplist(blob: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let mut r = Reader::new(blob);
+    r.read_bytes(10)
+        .map_err(|_| RdbError::CorruptEncoding("ziplist"))?;
+
+    let mut items = Vec::new();
+    loop {
+        let first = r
+            .read_u8()
+            .map_err(|_| RdbError::CorruptEncoding("ziplist"))?;
+        if first == 0xff {
+            break;
+        }
+        // prevlen is 1 byte, or 0xfe followed by 4 bytes
+        if first == 0xfe {
+            r.read_bytes(4)?;
+        }
+
+        let enc = r.read_u8()?;
+        let item = match enc >> 6 {
+            0b00 => r.read_bytes((enc & 0x3f) as usize)?.to_vec(),
+            0b01 => {
+                let len = (((enc & 0x3f) as usize) << 8) | r.read_u8()? as usize;
+                r.read_bytes(len)?.to_vec()
+            }
+            0b10 => {
+                let len = u32::from_be_bytes(r.read_array()?) as usize;
+                r.read_bytes(len)?.to_vec()
+            }
+            _ => {
+                let n: i64 = match enc {
+                    0xc0 => i16::from_le_bytes(r.read_array()?) as i64,
+                    0xd0 => i32::from_le_bytes(r.read_array()?) as i64,
+                    0xe0 => i64::from_le_bytes(r.read_array()?),
+                    0xf0 => {
+                        let b: [u8; 3] = r.read_array()?;
+                        // sign extend 24 bits
+                        (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8) as i64
+                    }
+                    0xfe => r.read_u8()? as i8 as i64,
+                    0xf1..=0xfd => ((enc & 0x0f) - 1) as i64,
+                    _ => return Err(RdbError::CorruptEncoding("ziplist")),
+                };
+                n.to_string().into_bytes()
+            }
+        };
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// listpack: total bytes(u32) count(u16) entries... 0xff
+/// every entry is encoding, data, backlen
+fn parse_listpack(blob: &[u8]) -> Result<Vec<Vec<u8>>, RdbError> {
+    let mut r = Reader::new(blob);
+    r.read_bytes(6)
+        .map_err(|_| RdbError::CorruptEncoding("listpack"))?;
+
+    let mut items = Vec::new();
+    loop {
+        let start = r.pos;
+        let enc = r
+            .read_u8()
+            .map_err(|_| RdbError::CorruptEncoding("listpack"))?;
+        if enc == 0xff {
+            break;
+        }
+
+        let item = if enc & 0x80 == 0 {
+            // 7 bit unsigned int
+            (enc & 0x7f).to_string().into_bytes()
+        } else if enc & 0xc0 == 0x80 {
+            r.read_bytes((enc & 0x3f) as usize)?.to_vec()
+        } else if enc & 0xe0 == 0xc0 {
+            // 13 bit signed int
+            let raw = (((enc & 0x1f) as u16) << 8) | r.read_u8()? as u16;
+            (((raw << 3) as i16) >> 3).to_string().into_bytes()
+        } else if enc & 0xf0 == 0xe0 {
+            let len = (((enc & 0x0f) as usize) << 8) | r.read_u8()? as usize;
+            r.read_bytes(len)?.to_vec()
+        } else {
+            match enc {
+                0xf0 => {
+                    let len = u32::from_le_bytes(r.read_array()?) as usize;
+                    r.read_bytes(len)?.to_vec()
+                }
+                0xf1 => i16::from_le_bytes(r.read_array()?).to_string().into_bytes(),
+                0xf2 => {
+                    let b: [u8; 3] = r.read_array()?;
+                    (i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8)
+                        .to_string()
+                        .into_bytes()
+                }
+                0xf3 => i32::from_le_bytes(r.read_array()?).to_string().into_bytes(),
+                0xf4 => i64::from_le_bytes(r.read_array()?).to_string().into_bytes(),
+                _ => return Err(RdbError::CorruptEncoding("listpack")),
+            }
+        };
+
+        // backlen stores the size of encoding + data in 1 to 5 bytes
+        let entry_len = r.pos - start;
+        let backlen_size = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        r.read_bytes(backlen_size)?;
+
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// intset: encoding(u32, 2/4/8 bytes per int) length(u32) ints...
+fn parse_intset(blob: &[u8]) -> Result<Vec<i64>, RdbError> {
+    let mut r = Reader::new(blob);
+    let corrupt = |_| RdbError::CorruptEncoding("intset");
+    let width = u32::from_le_bytes(r.read_array().map_err(corrupt)?) as usize;
+    let len = u32::from_le_bytes(r.read_array().map_err(corrupt)?) as usize;
+
+    let mut ints = Vec::with_capacity(len.min(blob.len()));
+    for _ in 0..len {
+        let bytes = r.read_bytes(width).map_err(corrupt)?;
+        let n = match width {
+            2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            8 => i64::from_le_bytes(bytes.try_into().unwrap()),
+            _ => return Err(RdbError::CorruptEncoding("intset")),
+        };
+        ints.push(n);
+    }
+    Ok(ints)
+}
+
+/// zipmap: zmlen(u8) then len key len free value [free bytes]... 0xff
+/// lengths are 1 byte, or 0xfe followed by a 4 byte length
+fn parse_zipmap(blob: &[u8]) -> Result<types::Hash, RdbError> {
+    let mut r = Reader::new(blob);
+    let corrupt = |_| RdbError::CorruptEncoding("zipmap");
+
+    let read_len = |r: &mut Reader| -> Result<Option<usize>, RdbError> {
+        match r.read_u8()? {
+            0xff => Ok(None),
+            0xfe => Ok(Some(u32::from_le_bytes(r.read_array()?) as usize)),
+            len => Ok(Some(len as usize)),
+        }
+    };
+
+    r.read_u8().map_err(corrupt)?;
+    let mut hash = types::Hash::default();
+    while let Some(key_len) = read_len(&mut r).map_err(corrupt)? {
+        let key = r.read_bytes(key_len).map_err(corrupt)?.to_vec();
+        let value_len = read_len(&mut r)
+            .map_err(corrupt)?
+            .ok_or(RdbError::CorruptEncoding("zipmap"))?;
+        let free = r.read_u8().map_err(corrupt)? as usize;
+        let value = r.read_bytes(value_len).map_err(corrupt)?.to_vec();
+        r.read_bytes(free).map_err(corrupt)?;
+        hash.insert(key, value);
+    }
+    Ok(hash)
+}
+
+/// decompresses an lzf compressed string
+fn lzf_decompress(input: &[u8], expected_len: usize) -> Result<Vec<u8>, RdbError> {
+    let mut out: Vec<u8> = Vec::with_capacity(expected_len.min(input.len() * 64));
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+
+        if ctrl < 32 {
+            // literal run of ctrl + 1 bytes
+            let run = ctrl + 1;
+            let literal = input.get(i..i + run).ok_or(RdbError::CorruptLzf)?;
+            out.extend_from_slice(literal);
+            i += run;
+        } else {
+            // back reference
+            let mut len = ctrl >> 5;
+            if len == 7 {
+                len += *input.get(i).ok_or(RdbError::CorruptLzf)? as usize;
+                i += 1;
+            }
+            len += 2;
+
+            let low = *input.get(i).ok_or(RdbError::CorruptLzf)? as usize;
+            i += 1;
+            let back = ((ctrl & 0x1f) << 8) + low + 1;
+            let start = out.len().checked_sub(back).ok_or(RdbError::CorruptLzf)?;
+
+            // the reference can overlap with bytes being written
+            for k in 0..len {
+                let b = out[start + k];
+                out.push(b);
+            }
+        }
+
+        if out.len() > expected_len {
+            return Err(RdbError::CorruptLzf);
+        }
+    }
+
+    if out.len() != expected_len {
+        return Err(RdbError::CorruptLzf);
+    }
+    Ok(out)
+}
+
+/// crc64 with the jones polynomial, reflected, as used for rdb checksums
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+    static TABLE: std::sync::OnceLock<[u64; 256]> = std::sync::OnceLock::new();
+
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0u64; 256];
+        for (i, slot) in table.iter_mut().enumerate() {
+            let mut c = i as u64;
+            for _ in 0..8 {
+                c = if c & 1 == 1 { (c >> 1) ^ POLY } else { c >> 1 };
+            }
+            *slot = c;
+        }
+        table
+    });
+
+    for b in data {
+        crc = table[((crc ^ *b as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+//////////// WRITING ////////////
+
+const RDB_VERSION: u32 = 11;
+
+enum ListpackItem<'a> {
+    Int(i64),
+    Str(&'a [u8]),
+}
+
+fn write_length(buf: &mut Vec<u8>, len: u64) {
+    if len < 1 << 6 {
+        buf.push(len as u8);
+    } else if len < 1 << 14 {
+        buf.push(0x40 | (len >> 8) as u8);
+        buf.push(len as u8);
+    } else if len <= u32::MAX as u64 {
+        buf.push(0x80);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        buf.push(0x81);
+        buf.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &[u8]) {
+    write_length(buf, s.len() as u64);
+    buf.extend_from_slice(s);
+}
+
+fn write_listpack(items: &[ListpackItem]) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    for item in items {
+        let start = body.len();
+        match item {
+            ListpackItem::Int(n @ 0..=127) => body.push(*n as u8),
+            ListpackItem::Int(n @ -4096..=4095) => {
+                let raw = (*n as u16) & 0x1fff;
+                body.push(0xc0 | (raw >> 8) as u8);
+                body.push(raw as u8);
+            }
+            ListpackItem::Int(n) => {
+                body.push(0xf4);
+                body.extend_from_slice(&n.to_le_bytes());
+            }
+            ListpackItem::Str(s) if s.len() < 64 => {
+                body.push(0x80 | s.len() as u8);
+                body.extend_from_slice(s);
+            }
+            ListpackItem::Str(s) if s.len() < 4096 => {
+                body.push(0xe0 | (s.len() >> 8) as u8);
+                body.push(s.len() as u8);
+                body.extend_from_slice(s);
+            }
+            ListpackItem::Str(s) => {
+                body.push(0xf0);
+                body.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                body.extend_from_slice(s);
+            }
+        }
+
+        // backlen, most significant 7 bits first so it can be read backwards
+        let entry_len = (body.len() - start) as u64;
+        let backlen_size = match entry_len {
+            0..=127 => 1,
+            128..=16382 => 2,
+            16383..=2097150 => 3,
+            2097151..=268435454 => 4,
+            _ => 5,
+        };
+        for i in (0..backlen_size).rev() {
+            let bits = ((entry_len >> (7 * i)) & 0x7f) as u8;
+            body.push(if i == backlen_size - 1 {
+                bits
+            } else {
+                bits | 0x80
+            });
+        }
+    }
+    body.push(0xff);
+
+    let mut lp = Vec::with_capacity(body.len() + 6);
+    lp.extend_from_slice(&(body.len() as u32 + 6).to_le_bytes());
+    lp.extend_from_slice(&(items.len().min(u16::MAX as usize) as u16).to_le_bytes());
+    lp.extend_from_slice(&body);
+    lp
+}
+
+fn write_value(buf: &mut Vec<u8>, key: &[u8], value: &types::StoredValue) {
+    match value {
+        types::StoredValue::String(s) => {
+            buf.push(TYPE_STRING);
+            write_string(buf, key);
+            write_string(buf, s);
+        }
+        types::StoredValue::List(list) => {
+            buf.push(TYPE_LIST);
+            write_string(buf, key);
+            write_length(buf, list.len() as u64);
+            for item in list {
+                write_string(buf, item);
+            }
+        }
+        types::StoredValue::Set(set) => {
+            buf.push(TYPE_SET);
+            write_string(buf, key);
+            write_length(buf, set.len() as u64);
+            for member in set.iter() {
+                write_string(buf, member);
+            }
+        }
+        types::StoredValue::ZSet(zset) => {
+            buf.push(TYPE_ZSET_2);
+            write_string(buf, key);
+            write_length(buf, zset.ordered.len() as u64);
+            for (score, member) in &zset.ordered {
+                write_string(buf, member);
+                buf.extend_from_slice(&score.0.to_le_bytes());
+            }
+        }
+        types::StoredValue::Hash(hash) => {
+            buf.push(TYPE_HASH);
+            write_string(buf, key);
+            write_length(buf, hash.len() as u64);
+            for (field, value) in hash.iter() {
+                write_string(buf, field);
+                write_string(buf, value);
+            }
+        }
+        types::StoredValue::Stream(entries) => {
+            buf.push(TYPE_STREAM_LISTPACKS);
+            write_string(buf, key);
+            write_stream(buf, entries);
+        }
+    }
+}
+
+/// every entry goes into its own listpack node, with the entry id as master id
+/// and its fields as master fields, so each entry is stored with SAMEFIELDS
+fn write_stream(buf: &mut Vec<u8>, entries: &[types::Entry]) {
+    let parse_id = |id: &str| -> (u64, u64) {
+        let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+        (ms.parse().unwrap_or(0), seq.parse().unwrap_or(0))
+    };
+
+    write_length(buf, entries.len() as u64);
+    for entry in entries {
+        let (ms, seq) = parse_id(&entry.id);
+        let mut master_id = Vec::with_capacity(16);
+        master_id.extend_from_slice(&ms.to_be_bytes());
+        master_id.extend_from_slice(&seq.to_be_bytes());
+        write_string(buf, &master_id);
+
+        let field_count = entry.map.len() as i64;
+        let mut items = vec![
+            ListpackItem::Int(1), // count
+            ListpackItem::Int(0), // deleted
+            ListpackItem::Int(field_count),
+        ];
+        items.extend(entry.map.keys().map(|field| ListpackItem::Str(field)));
+        items.push(ListpackItem::Int(0)); // master entry terminator
+
+        items.push(ListpackItem::Int(STREAM_ITEM_FLAG_SAMEFIELDS));
+        items.push(ListpackItem::Int(0)); // ms diff
+        items.push(ListpackItem::Int(0)); // seq diff
+        items.extend(entry.map.values().map(|value| ListpackItem::Str(value)));
+        items.push(ListpackItem::Int(field_count + 3)); // lp-count
+
+        write_string(buf, &write_listpack(&items));
+    }
+
+    let (last_ms, last_seq) = entries.last().map_or((0, 0), |e| parse_id(&e.id));
+    write_length(buf, entries.len() as u64);
+    write_length(buf, last_ms);
+    write_length(buf, last_seq);
+
+    // no consumer groups
+    write_length(buf, 0);
+}
+
+/// serializes the given keys into a complete rdb file
+/// keys are expected to be grouped by db, aof_base marks the preamble of an append only file
+pub fn serialize(keys: &[RdbKey], aof_base: bool) -> Vec<u8> {
+    let mut buf = format!("REDIS{RDB_VERSION:04}").into_bytes();
+
+    let ctime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let aux: [(&[u8], Vec<u8>); 4] = [
+        (b"redis-ver", helper::REDIS_VERSION.as_bytes().to_vec()),
+        (b"redis-bits", b"64".to_vec()),
+        (b"ctime", ctime.to_string().into_bytes()),
+        (b"aof-base", if aof_base { b"1" } else { b"0" }.to_vec()),
+    ];
+    for (key, value) in aux {
+        buf.push(OPCODE_AUX);
+        write_string(&mut buf, key);
+        write_string(&mut buf, &value);
+    }
+
+    for db_keys in keys.chunk_by(|a, b| a.db == b.db) {
+        buf.push(OPCODE_SELECTDB);
+        write_length(&mut buf, db_keys[0].db as u64);
+
+        let expires = db_keys.iter().filter(|k| k.expires_at_ms.is_some()).count();
+        buf.push(OPCODE_RESIZEDB);
+        write_length(&mut buf, db_keys.len() as u64);
+        write_length(&mut buf, expires as u64);
+
+        for rdb_key in db_keys {
+            if let Some(unix_ms) = rdb_key.expires_at_ms {
+                buf.push(OPCODE_EXPIRETIME_MS);
+                buf.extend_from_slice(&unix_ms.to_le_bytes());
+            }
+            write_value(&mut buf, &rdb_key.key, &rdb_key.value);
+        }
+    }
+
+    buf.push(OPCODE_EOF);
+    let checksum = crc64(0, &buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    buf
+}
+
+/// writes a snapshot to a temp file in the same directory and renames it over
+/// the target, so a crash mid-write never leaves a truncated rdb file behind
+pub fn write_file(path: &std::path::Path, keys: &[RdbKey]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let data = serialize(keys, false);
+    let _data_memory = memory::NotCounted::new(data.capacity());
+    let tmp_path = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+
+    let result = (|| {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    })();
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// dump.rdb written by redis 7.0.15: 4 string keys in db 0, 2 of them with
+    /// a ttl in ms, and the aux fields redis always writes
+    const REDIS_7_0_DUMP: &[u8] = b"REDIS0010\xfa\x09redis-ver\x067.0.15\
+        \xfa\x0aredis-bits\xc0@\xfa\x05ctime\xc2\xc1\xeaBi\xfa\x08used-mem\xc2\x18\xda\x0e\x00\
+        \xfa\x08aof-base\xc0\x00\xfe\x00\xfb\x04\x02\
+        \xfc\xa8\x04\xca.\x9b\x01\x00\x00\x00\x03toy\x04goal\
+        \x00\x06bottle\x03sun\x00\x03foo\x03bar\
+        \xfc\x08\xecg-\x9b\x01\x00\x00\x00\x04pant\x05shirt\
+        \xff\x0e e\x09\x87\xe9\x81\x1e";
+
+    /// empty dump.rdb written by redis 7.2.0
+    const REDIS_7_2_EMPTY_DUMP: &[u8] = b"REDIS0011\xfa\x09redis-ver\x057.2.0\
+        \xfa\x0aredis-bits\xc0@\xfa\x05ctime\xc2m\x08\xbce\xfa\x08used-mem\xc2\xb0\xc4\x10\x00\
+        \xfa\x08aof-base\xc0\x00\xff\xf0n;\xfe\xc0\xffZ\xa2";
+
+    /// a complete rdb file around body, with the checksum redis would write
+    fn rdb_file(body: &[u8]) -> Vec<u8> {
+        let mut data = b"REDIS0011".to_vec();
+        data.extend_from_slice(body);
+        data.push(OPCODE_EOF);
+        let checksum = crc64(0, &data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+        data
+    }
+
+    /// a string with a 6 bit length
+    fn short_string(s: &[u8]) -> Vec<u8> {
+        assert!(s.len() < 64);
+        let mut encoded = vec![s.len() as u8];
+        encoded.extend_from_slice(s);
+        encoded
+    }
+
+    /// a single key of the given type in db 0
+    fn single_key(value_type: u8, encoded_value: &[u8]) -> types::StoredValue {
+        let mut body = vec![OPCODE_SELECTDB, 0, value_type];
+        body.extend(short_string(b"key"));
+        body.extend_from_slice(encoded_value);
+
+        let mut contents = parse(&rdb_file(&body)).unwrap();
+        assert_eq!(contents.keys.len(), 1);
+        contents.keys.pop().unwrap().value
+    }
+
+    fn string_value(value: &types::StoredValue) -> &[u8] {
+        match value {
+            types::StoredValue::String(s) => s,
+            other => panic!("expected a string, got {}", other.type_name()),
+        }
+    }
+
+    fn aux<'a>(contents: &'a RdbContents, key: &[u8]) -> &'a [u8] {
+        &contents.aux.iter().find(|(k, _)| k == key).unwrap().1
+    }
+
+    #[test]
+    fn parses_redis_7_0_dump() {
+        let contents = parse(REDIS_7_0_DUMP).unwrap();
+
+        assert_eq!(contents.version, 10);
+        assert_eq!(contents.size, REDIS_7_0_DUMP.len());
+        assert_eq!(aux(&contents, b"redis-ver"), b"7.0.15");
+        // integer encoded aux values
+        assert_eq!(aux(&contents, b"redis-bits"), b"64");
+        assert_eq!(aux(&contents, b"ctime"), b"1765993153");
+        assert_eq!(aux(&contents, b"used-mem"), b"973336");
+        assert_eq!(aux(&contents, b"aof-base"), b"0");
+
+        let keys: Vec<_> = contents
+            .keys
+            .iter()
+            .map(|k| {
+                (
+                    k.db,
+                    k.key.as_slice(),
+                    string_value(&k.value),
+                    k.expires_at_ms,
+                )
+            })
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                (0, &b"toy"[..], &b"goal"[..], Some(1766016550056)),
+                (0, b"bottle", b"sun", None),
+                (0, b"foo", b"bar", None),
+                (0, b"pant", b"shirt", Some(1765993344008)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_redis_7_2_empty_dump() {
+        let contents = parse(REDIS_7_2_EMPTY_DUMP).unwrap();
+
+        assert_eq!(contents.version, 11);
+        assert!(contents.keys.is_empty());
+        assert_eq!(aux(&contents, b"redis-ver"), b"7.2.0");
+        assert_eq!(aux(&contents, b"ctime"), b"1706821741");
+        assert_eq!(aux(&contents, b"used-mem"), b"1098928");
+    }
+
+    #[test]
+    fn ignores_what_follows_the_rdb() {
+        // an aof with an rdb preamble goes on with commands
+        let mut data = REDIS_7_2_EMPTY_DUMP.to_vec();
+        data.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+
+        let contents = parse(&data).unwrap();
+        assert_eq!(contents.size, REDIS_7_2_EMPTY_DUMP.len());
+    }
+
+    #[test]
+    fn rejects_a_corrupt_checksum() {
+        let mut data = REDIS_7_0_DUMP.to_vec();
+        // "bar" -> "baz"
+        let at = data.windows(3).position(|w| w == b"bar").unwrap();
+        data[at + 2] = b'z';
+
+        assert!(matches!(
+            parse(&data),
+            Err(RdbError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn accepts_a_disabled_checksum() {
+        let mut data = REDIS_7_0_DUMP.to_vec();
+        let len = data.len();
+        data[len - 8..].fill(0);
+
+        assert_eq!(parse(&data).unwrap().keys.len(), 4);
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        for len in 0..REDIS_7_0_DUMP.len() {
+            assert!(
+                parse(&REDIS_7_0_DUMP[..len]).is_err(),
+                "prefix of {len} bytes"
+            );
+        }
+        assert!(matches!(
+            parse(&REDIS_7_0_DUMP[..100]),
+            Err(RdbError::UnexpectedEof { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_headers_and_versions() {
+        assert!(matches!(
+            parse(b"RUBIS0011\xff"),
+            Err(RdbError::InvalidHeader)
+        ));
+        assert!(matches!(
+            parse(b"REDISabcd\xff"),
+            Err(RdbError::InvalidHeader)
+        ));
+        assert!(matches!(
+            parse(b"REDIS0099\xff"),
+            Err(RdbError::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_value_types() {
+        let mut body = vec![OPCODE_SELECTDB, 0, 0x63];
+        body.extend(short_string(b"key"));
+
+        assert!(matches!(
+            parse(&rdb_file(&body)),
+            Err(RdbError::UnknownValueType(0x63))
+        ));
+    }
+
+    #[test]
+    fn reads_every_length_encoding() {
+        // 14 bit length
+        let mut encoded = vec![0x40, 100];
+        encoded.extend(vec![b'a'; 100]);
+        assert_eq!(
+            string_value(&single_key(TYPE_STRING, &encoded)),
+            vec![b'a'; 100]
+        );
+
+        // 32 bit length
+        let mut encoded = vec![0x80];
+        encoded.extend_from_slice(&20_000u32.to_be_bytes());
+        encoded.extend(vec![b'b'; 20_000]);
+        assert_eq!(
+            string_value(&single_key(TYPE_STRING, &encoded)),
+            vec![b'b'; 20_000]
+        );
+
+        // 64 bit length
+        let mut encoded = vec![0x81];
+        encoded.extend_from_slice(&3u64.to_be_bytes());
+        encoded.extend_from_slice(b"xyz");
+        assert_eq!(string_value(&single_key(TYPE_STRING, &encoded)), b"xyz");
+    }
+
+    #[test]
+    fn reads_integer_encoded_strings() {
+        assert_eq!(string_value(&single_key(TYPE_STRING, &[0xc0, 0xfb])), b"-5");
+        assert_eq!(
+            string_value(&single_key(TYPE_STRING, &[0xc1, 0x39, 0x30])),
+            b"12345"
+        );
+        assert_eq!(
+            string_value(&single_key(TYPE_STRING, &[0xc2, 0x00, 0x5e, 0xd0, 0xb2])),
+            b"-1294967296"
+        );
+    }
+
+    #[test]
+    fn reads_lzf_compressed_strings() {
+        // a literal "a", then a back reference copying it 19 more times
+        let encoded = [0xc3, 5, 20, 0x00, b'a', 0xe0, 10, 0x00];
+        assert_eq!(
+            string_value(&single_key(TYPE_STRING, &encoded)),
+            vec![b'a'; 20]
+        );
+
+        // "abcabcabcX": a literal, a back reference of 6 and another literal
+        let encoded = [0xc3, 8, 10, 0x02, b'a', b'b', b'c', 0x80, 0x02, 0x00, b'X'];
+        assert_eq!(
+            string_value(&single_key(TYPE_STRING, &encoded)),
+            b"abcabcabcX"
+        );
+
+        // a reference before the start of the output
+        let encoded = [0xc3, 3, 4, 0x00, b'a', 0x40];
+        let mut body = vec![OPCODE_SELECTDB, 0, TYPE_STRING];
+        body.extend(short_string(b"key"));
+        body.extend_from_slice(&encoded);
+        assert!(parse(&rdb_file(&body)).is_err());
+
+        // more output than announced
+        let encoded = [0xc3, 5, 10, 0x00, b'a', 0xe0, 10, 0x00];
+        let mut body = vec![OPCODE_SELECTDB, 0, TYPE_STRING];
+        body.extend(short_string(b"key"));
+        body.extend_from_slice(&encoded);
+        assert!(matches!(parse(&rdb_file(&body)), Err(RdbError::CorruptLzf)));
+    }
+
+    #[test]
+    fn reads_expiries_and_dbs() {
+        let mut body = vec![OPCODE_SELECTDB, 0, OPCODE_RESIZEDB, 2, 1];
+        // seconds
+        body.push(OPCODE_EXPIRETIME);
+        body.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        body.push(TYPE_STRING);
+        body.extend(short_string(b"a"));
+        body.extend(short_string(b"1"));
+        body.push(TYPE_STRING);
+        body.extend(short_string(b"b"));
+        body.extend(short_string(b"2"));
+        // milliseconds, in another db, with the lru and lfu hints before the key
+        body.extend_from_slice(&[OPCODE_SELECTDB, 3]);
+        body.push(OPCODE_EXPIRETIME_MS);
+        body.extend_from_slice(&1_700_000_000_123u64.to_le_bytes());
+        body.extend_from_slice(&[OPCODE_IDLE, 10, OPCODE_FREQ, 5]);
+        body.push(TYPE_STRING);
+        body.extend(short_string(b"c"));
+        body.extend(short_string(b"3"));
+
+        let contents = parse(&rdb_file(&body)).unwrap();
+        let keys: Vec<_> = contents
+            .keys
+            .iter()
+            .map(|k| (k.db, k.key.as_slice(), k.expires_at_ms))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                (0, &b"a"[..], Some(1_700_000_000_000)),
+                (0, b"b", None),
+                (3, b"c", Some(1_700_000_000_123)),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_intsets() {
+        // 16 bit integers: 1, -2, 300
+        let mut blob = Vec::new();
+        blob.extend_from_slice(&2u32.to_le_bytes());
+        blob.extend_from_slice(&3u32.to_le_bytes());
+        for n in [1i16, -2, 300] {
+            blob.extend_from_slice(&n.to_le_bytes());
+        }
+        let mut encoded = vec![blob.len() as u8];
+        encoded.extend(blob);
+
+        let types::StoredValue::Set(set) = single_key(TYPE_SET_INTSET, &encoded) else {
+            panic!("expected a set");
+        };
+        let mut members: Vec<_> = set.iter().cloned().collect();
+        members.sort();
+        assert_eq!(members, [b"-2".to_vec(), b"1".to_vec(), b"300".to_vec()]);
+    }
+
+    #[test]
+    fn reads_ziplists() {
+        let entries: &[u8] = &[
+            0x00, 0x05, b'h', b'e', b'l', b'l', b'o', // 6 bit string
+            0x07, 0xfe, 0xfd, // int8 -3
+            0x03, 0xf5, // immediate 4
+            0x02, 0xc0, 0xe8, 0x03, // int16 1000
+            0x04, 0xf0, 0x00, 0x00, 0x80, // int24 -8388608
+            0x05, 0xd0, 0x40, 0x42, 0x0f, 0x00, // int32 1000000
+        ];
+        let mut blob = Vec::new();
+        blob.extend_from_slice(&(10 + entries.len() as u32 + 1).to_le_bytes());
+        blob.extend_from_slice(&(10 + entries.len() as u32 - 6).to_le_bytes());
+        blob.extend_from_slice(&6u16.to_le_bytes());
+        blob.extend_from_slice(entries);
+        blob.push(0xff);
+        let mut encoded = vec![blob.len() as u8];
+        encoded.extend(blob);
+
+        let types::StoredValue::List(list) = single_key(TYPE_LIST_ZIPLIST, &encoded) else {
+            panic!("expected a list");
+        };
+        let expected: [&[u8]; 6] = [b"hello", b"-3", b"4", b"1000", b"-8388608", b"1000000"];
+        assert_eq!(list, expected.map(|item| item.to_vec()));
+    }
+
+    /// listpack with the given raw entries, each followed by its backlen
+    fn listpack(entries: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for entry in entries {
+            body.extend_from_slice(entry);
+            body.push(entry.len() as u8);
+        }
+        body.push(0xff);
+
+        let mut blob = Vec::new();
+        blob.extend_from_slice(&(6 + body.len() as u32).to_le_bytes());
+        blob.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        blob.extend(body);
+
+        let mut encoded = vec![blob.len() as u8];
+        encoded.extend(blob);
+        encoded
+    }
+
+    #[test]
+    fn reads_listpack_hashes() {
+        let encoded = listpack(&[
+            b"\x84name",
+            b"\x83bob",
+            b"\x83age",
+            b"\x2a", // 7 bit uint 42
+            b"\x85score",
+            b"\xdf\x9c", // 13 bit int -100
+            b"\x83big",
+            b"\xf3\x00\x00\x00\x80", // int32 -2147483648
+        ]);
+
+        let types::StoredValue::Hash(hash) = single_key(TYPE_HASH_LISTPACK, &encoded) else {
+            panic!("expected a hash");
+        };
+        assert_eq!(hash.len(), 4);
+        assert_eq!(hash[&b"name"[..]], b"bob");
+        assert_eq!(hash[&b"age"[..]], b"42");
+        assert_eq!(hash[&b"score"[..]], b"-100");
+        assert_eq!(hash[&b"big"[..]], b"-2147483648");
+    }
+
+    #[test]
+    fn reads_listpack_zsets() {
+        let encoded = listpack(&[
+            b"\x81a",
+            b"\x01",
+            b"\x81b",
+            b"\x832.5",
+            b"\x81c",
+            b"\xf1\x18\xfc",
+        ]);
+
+        let types::StoredValue::ZSet(zset) = single_key(TYPE_ZSET_LISTPACK, &encoded) else {
+            panic!("expected a zset");
+        };
+        let ordered: Vec<_> = zset
+            .ordered
+            .iter()
+            .map(|(score, member)| (member.as_slice(), score.0))
+            .collect();
+        assert_eq!(ordered, [(&b"c"[..], -1000.0), (b"a", 1.0), (b"b", 2.5)]);
+    }
+
+    #[test]
+    fn reads_quicklists() {
+        let mut encoded = vec![2];
+        encoded.push(QUICKLIST_NODE_PACKED as u8);
+        encoded.extend(listpack(&[b"\x81a", b"\x81b"]));
+        encoded.push(QUICKLIST_NODE_PLAIN as u8);
+        encoded.extend(short_string(b"plain"));
+
+        let types::StoredValue::List(list) = single_key(TYPE_LIST_QUICKLIST_2, &encoded) else {
+            panic!("expected a list");
+        };
+        assert_eq!(list, [b"a".to_vec(), b"b".to_vec(), b"plain".to_vec()]);
+    }
+
+    #[test]
+    fn reads_zipmaps() {
+        // zmlen, then len key len free value, the second value has 1 free byte
+        let blob: &[u8] = b"\x02\x03foo\x03\x00bar\x01x\x02\x01yz?\xff";
+        let mut encoded = vec![blob.len() as u8];
+        encoded.extend_from_slice(blob);
+
+        let types::StoredValue::Hash(hash) = single_key(TYPE_HASH_ZIPMAP, &encoded) else {
+            panic!("expected a hash");
+        };
+        assert_eq!(hash.len(), 2);
+        assert_eq!(hash[&b"foo"[..]], b"bar");
+        assert_eq!(hash[&b"x"[..]], b"yz");
+    }
+
+    #[test]
+    fn corrupt_encodings_are_errors() {
+        // listpack shorter than its header
+        assert!(
+            parse(&{
+                let mut body = vec![OPCODE_SELECTDB, 0, TYPE_HASH_LISTPACK];
+                body.extend(short_string(b"key"));
+                body.extend(short_string(b"\x01\x00"));
+                rdb_file(&body)
+            })
+            .is_err()
+        );
+
+        // hash listpack with an odd number of items
+        let mut body = vec![OPCODE_SELECTDB, 0, TYPE_HASH_LISTPACK];
+        body.extend(short_string(b"key"));
+        body.extend(listpack(&[b"\x81a"]));
+        assert!(matches!(
+            parse(&rdb_file(&body)),
+            Err(RdbError::CorruptEncoding("hash"))
+        ));
+
+        // intset with an impossible integer width
+        let mut blob = 3u32.to_le_bytes().to_vec();
+        blob.extend_from_slice(&1u32.to_le_bytes());
+        blob.extend_from_slice(&[0, 0, 0]);
+        let mut body = vec![OPCODE_SELECTDB, 0, TYPE_SET_INTSET];
+        body.extend(short_string(b"key"));
+        body.extend(short_string(&blob));
+        assert!(matches!(
+            parse(&rdb_file(&body)),
+            Err(RdbError::CorruptEncoding("intset"))
+        ));
+    }
+
+    fn same_value(a: &types::StoredValue, b: &types::StoredValue) -> bool {
+        use types::StoredValue::*;
+        match (a, b) {
+            (String(a), String(b)) => a == b,
+            (List(a), List(b)) => a == b,
+            (Set(a), Set(b)) => **a == **b,
+            (ZSet(a), ZSet(b)) => a.scores == b.scores && a.ordered == b.ordered,
+            (Hash(a), Hash(b)) => **a == **b,
+            (Stream(a), Stream(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.id == b.id && a.map == b.map)
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn serialized_keys_parse_back() {
+        let mut zset = types::ZSet::new();
+        zset.insert(b"low".to_vec(), -1.5);
+        zset.insert(b"high".to_vec(), 1e300);
+        zset.insert(b"inf".to_vec(), f64::INFINITY);
+        zset.insert(b"zero".to_vec(), 0.0);
+
+        let entry = |id: &str, fields: &[(&[u8], &[u8])]| types::Entry {
+            id: id.to_string(),
+            map: fields
+                .iter()
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect(),
+        };
+
+        let values = vec![
+            (
+                0,
+                &b"empty"[..],
+                types::StoredValue::String(Vec::new()),
+                None,
+            ),
+            (
+                0,
+                b"number",
+                types::StoredValue::String(b"-12345".to_vec()),
+                Some(1),
+            ),
+            (
+                0,
+                b"binary",
+                types::StoredValue::String((0..=255).collect()),
+                None,
+            ),
+            (
+                0,
+                b"long",
+                types::StoredValue::String(vec![b'x'; 70_000]),
+                Some(4_102_444_800_000),
+            ),
+            (
+                1,
+                b"list",
+                types::StoredValue::List((0..1000).map(|n| n.to_string().into_bytes()).collect()),
+                Some(1_700_000_000_123),
+            ),
+            (
+                1,
+                b"set",
+                types::StoredValue::Set(
+                    [b"a".to_vec(), b"1".to_vec(), Vec::new()]
+                        .into_iter()
+                        .collect(),
+                ),
+                None,
+            ),
+            (2, b"zset", types::StoredValue::ZSet(zset), None),
+            (
+                2,
+                b"hash",
+                types::StoredValue::Hash(
+                    [
+                        (b"f".to_vec(), b"v".to_vec()),
+                        (b"n".to_vec(), b"42".to_vec()),
+                    ]
+                    .into_iter()
+                    .collect(),
+                ),
+                Some(1_700_000_000_000),
+            ),
+            (
+                15,
+                b"stream",
+                types::StoredValue::Stream(vec![
+                    entry("1-0", &[(b"temp", b"20")]),
+                    entry("1-1", &[(b"temp", b"21"), (b"hum", b"50")]),
+                    entry("1700000000000-7", &[]),
+                ]),
+                None,
+            ),
+        ];
+        let keys: Vec<_> = values
+            .into_iter()
+            .map(|(db, key, value, expires_at_ms)| RdbKey {
+                db,
+                key: key.to_vec(),
+                value,
+                expires_at_ms,
+            })
+            .collect();
+
+        let data = serialize(&keys, true);
+        let contents = parse(&data).unwrap();
+
+        assert_eq!(contents.version, RDB_VERSION);
+        assert_eq!(contents.size, data.len());
+        assert_eq!(aux(&contents, b"aof-base"), b"1");
+        assert_eq!(contents.keys.len(), keys.len());
+        for (written, read) in keys.iter().zip(&contents.keys) {
+            let name = String::from_utf8_lossy(&written.key);
+            assert_eq!(read.db, written.db, "{name}");
+            assert_eq!(read.key, written.key, "{name}");
+            assert_eq!(read.expires_at_ms, written.expires_at_ms, "{name}");
+            assert!(same_value(&read.value, &written.value), "{name}");
+        }
+    }
+
+    #[test]
+    fn written_files_parse_back() {
+        let dir = std::env::temp_dir().join(format!("rdb-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.rdb");
+
+        let keys = [RdbKey {
+            db: 0,
+            key: b"foo".to_vec(),
+            value: types::StoredValue::String(b"bar".to_vec()),
+            expires_at_ms: Some(1_700_000_000_000),
+        }];
+        write_file(&path, &keys).unwrap();
+        let contents = parse(&std::fs::read(&path).unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(contents.keys.len(), 1);
+        assert_eq!(contents.keys[0].key, b"foo");
+        assert_eq!(string_value(&contents.keys[0].value), b"bar");
+        assert_eq!(contents.keys[0].expires_at_ms, Some(1_700_000_000_000));
+    }
+}
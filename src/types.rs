@@ -1,15 +1,74 @@
 use clap::Parser;
 use ordered_float::OrderedFloat;
-use std::collections::{BTreeSet, HashMap};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::net::{SocketAddr, TcpStream};
 use std::sync::{Arc, Condvar, Mutex};
 
-#[derive(Debug)]
+use crate::aof;
+use crate::helper;
+use crate::memory;
+use crate::rdb;
+
+/// elements grouped by their scan hash in hash order, so a SCAN continues from
+/// its cursor with a range lookup rather than a pass over every element
+#[derive(Debug, Clone)]
+pub struct ScanIndex<K = Vec<u8>>(BTreeMap<u64, Vec<K>>);
+
+impl<K> Default for ScanIndex<K> {
+    fn default() -> Self {
+        Self(BTreeMap::new())
+    }
+}
+
+impl<K: Borrow<[u8]>> ScanIndex<K> {
+    pub fn insert(&mut self, element: K) {
+        let bucket = self
+            .0
+            .entry(helper::scan_hash(element.borrow()))
+            .or_default();
+        if !bucket.iter().any(|e| e.borrow() == element.borrow()) {
+            bucket.push(element);
+        }
+    }
+
+    pub fn remove(&mut self, element: &[u8]) {
+        let hash = helper::scan_hash(element);
+        let Some(bucket) = self.0.get_mut(&hash) else {
+            return;
+        };
+        bucket.retain(|e| e.borrow() != element);
+        if bucket.is_empty() {
+            self.0.remove(&hash);
+        }
+    }
+
+    /// one page of a SCAN style iteration: the first count elements at or after
+    /// the cursor (a few more if hashes collide at the end of the page), and the
+    /// cursor to continue from, 0 once everything was returned
+    /// elements present for the whole iteration are returned exactly once
+    pub fn page(&self, cursor: u64, count: usize) -> (u64, Vec<&K>) {
+        let mut page = Vec::new();
+        for (hash, bucket) in self.0.range(cursor..) {
+            if page.len() >= count {
+                return (*hash, page);
+            }
+            page.extend(bucket);
+        }
+        (0, page)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct ZSet {
     // stores actual value
-    pub scores: HashMap<String, f64>,
+    pub scores: HashMap<Vec<u8>, f64>,
 
     // store for ordering purposes
-    pub ordered: BTreeSet<(OrderedFloat<f64>, String)>,
+    pub ordered: BTreeSet<(OrderedFloat<f64>, Vec<u8>)>,
+
+    // members in the order ZSCAN returns them
+    pub scan_order: ScanIndex,
 }
 
 impl ZSet {
@@ -17,7 +76,109 @@ impl ZSet {
         Self {
             scores: HashMap::new(),
             ordered: BTreeSet::new(),
+            scan_order: ScanIndex::default(),
+        }
+    }
+
+    /// adds or updates a member, returns true if it was newly added
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        match self.scores.insert(member.clone(), score) {
+            Some(old_score) => {
+                self.ordered
+                    .remove(&(OrderedFloat(old_score), member.clone()));
+                self.ordered.insert((OrderedFloat(score), member));
+                false
+            }
+            None => {
+                self.scan_order.insert(member.clone());
+                self.ordered.insert((OrderedFloat(score), member));
+                true
+            }
+        }
+    }
+
+    /// removes a member, returns true if it was there
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        let Some(score) = self.scores.remove(member) else {
+            return false;
+        };
+        self.ordered.remove(&(OrderedFloat(score), member.to_vec()));
+        self.scan_order.remove(member);
+        true
+    }
+}
+
+/// fields of a hash value, reads go straight to the map
+#[derive(Debug, Clone, Default)]
+pub struct Hash {
+    fields: HashMap<Vec<u8>, Vec<u8>>,
+    scan_order: ScanIndex, // fields in the order HSCAN returns them
+}
+
+impl std::ops::Deref for Hash {
+    type Target = HashMap<Vec<u8>, Vec<u8>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.fields
+    }
+}
+
+impl FromIterator<(Vec<u8>, Vec<u8>)> for Hash {
+    fn from_iter<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(iter: I) -> Self {
+        let mut hash = Hash::default();
+        for (field, value) in iter {
+            hash.insert(field, value);
         }
+        hash
+    }
+}
+
+impl Hash {
+    /// sets a field, returns its previous value
+    pub fn insert(&mut self, field: Vec<u8>, value: Vec<u8>) -> Option<Vec<u8>> {
+        self.scan_order.insert(field.clone());
+        self.fields.insert(field, value)
+    }
+
+    pub fn scan_order(&self) -> &ScanIndex {
+        &self.scan_order
+    }
+}
+
+/// members of a set value, reads go straight to the set
+#[derive(Debug, Clone, Default)]
+pub struct Set {
+    members: HashSet<Vec<u8>>,
+    scan_order: ScanIndex, // members in the order SSCAN returns them
+}
+
+impl std::ops::Deref for Set {
+    type Target = HashSet<Vec<u8>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.members
+    }
+}
+
+impl FromIterator<Vec<u8>> for Set {
+    fn from_iter<I: IntoIterator<Item = Vec<u8>>>(iter: I) -> Self {
+        let mut set = Set::default();
+        for member in iter {
+            set.insert(member);
+        }
+        set
+    }
+}
+
+impl Set {
+    /// adds a member, returns true if it wasn't there yet
+    pub fn insert(&mut self, member: Vec<u8>) -> bool {
+        self.scan_order.insert(member.clone());
+        self.members.insert(member)
+    }
+
+    pub fn scan_order(&self) -> &ScanIndex {
+        &self.scan_order
     }
 }
 
@@ -26,6 +187,18 @@ pub struct UserInfo {
     pub is_authenticated: bool,
 }
 
+impl Default for UserInfo {
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            is_authenticated: false,
+        }
+    }
+}
+
+// username -> sha256 of each of its passwords
+pub type SharedUsers = Arc<Mutex<HashMap<String, Vec<[u8; 32]>>>>;
+
 #[derive(Parser, Debug)]
 pub struct Args {
     #[arg(short, long)]
@@ -36,40 +209,740 @@ pub struct Args {
     pub dir: Option<String>,
     #[arg(long)]
     pub dbfilename: Option<String>,
+    #[arg(long)]
+    pub save: Option<String>, // "<seconds> <changes> ..." or "" to disable
+    #[arg(long)]
+    pub appendonly: Option<String>, // yes or no
+    #[arg(long)]
+    pub appendfilename: Option<String>,
+    #[arg(long)]
+    pub appendfsync: Option<String>, // always, everysec or no
+    #[arg(long)]
+    pub repl_backlog_size: Option<String>, // bytes, or with a kb/mb/gb unit
+    #[arg(long)]
+    pub auto_aof_rewrite_percentage: Option<u64>,
+    #[arg(long)]
+    pub auto_aof_rewrite_min_size: Option<String>, // bytes, or with a kb/mb/gb unit
+    #[arg(long)]
+    pub maxmemory: Option<String>, // bytes, or with a kb/mb/gb unit, 0 for no limit
+    #[arg(long)]
+    pub maxmemory_policy: Option<String>,
+    #[arg(long)]
+    pub maxmemory_samples: Option<usize>,
+    #[arg(long)]
+    pub databases: Option<usize>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum StoredValue {
-    String(String),
+    String(Vec<u8>),
+    List(VecDeque<Vec<u8>>),
+    Set(Set),
+    ZSet(ZSet),
+    Hash(Hash),
     Stream(Vec<Entry>),
 }
 
-#[derive(Debug)]
+impl StoredValue {
+    /// name reported by the TYPE command
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            StoredValue::String(_) => "string",
+            StoredValue::List(_) => "list",
+            StoredValue::Set(_) => "set",
+            StoredValue::ZSet(_) => "zset",
+            StoredValue::Hash(_) => "hash",
+            StoredValue::Stream(_) => "stream",
+        }
+    }
+
+    /// number of elements held, 1 for a string
+    pub fn element_count(&self) -> usize {
+        match self {
+            StoredValue::String(_) => 1,
+            StoredValue::List(list) => list.len(),
+            StoredValue::Set(set) => set.len(),
+            StoredValue::ZSet(zset) => zset.scores.len(),
+            StoredValue::Hash(hash) => hash.len(),
+            StoredValue::Stream(entries) => entries.len(),
+        }
+    }
+
+    /// rough number of bytes held by the value, for INFO memory
+    pub fn approx_size(&self) -> usize {
+        // per element bookkeeping of the underlying collection
+        const OVERHEAD: usize = 16;
+
+        match self {
+            StoredValue::String(s) => s.len(),
+            StoredValue::List(list) => list.iter().map(|e| e.len() + OVERHEAD).sum(),
+            StoredValue::Set(set) => set.iter().map(|e| e.len() + OVERHEAD).sum(),
+            StoredValue::ZSet(zset) => zset
+                .scores
+                .keys()
+                .map(|member| 2 * (member.len() + 8 + OVERHEAD))
+                .sum(),
+            StoredValue::Hash(hash) => hash
+                .iter()
+                .map(|(field, value)| field.len() + value.len() + OVERHEAD)
+                .sum(),
+            StoredValue::Stream(entries) => entries
+                .iter()
+                .map(|entry| {
+                    entry.id.len()
+                        + entry
+                            .map
+                            .iter()
+                            .map(|(field, value)| field.len() + value.len() + OVERHEAD)
+                            .sum::<usize>()
+                })
+                .sum(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Entry {
     pub id: String,
-    pub map: HashMap<String, String>,
+    pub map: HashMap<Vec<u8>, Vec<u8>>,
 }
 
 #[derive(Debug)]
 pub struct ValueEntry {
     pub value: StoredValue,
-    pub expires_at: Option<std::time::Instant>, // None = no expiry
+    pub expires_at: Option<u64>, // absolute unix time in ms, None = no expiry
+
+    // what lru and lfu eviction go by
+    pub last_access: u64,    // unix time in ms
+    pub lfu_counter: u8,     // grows logarithmically with the accesses
+    pub lfu_decayed_at: u64, // unix time in minutes the counter was last decayed
 }
 
+/// counter of a new key, so it isn't evicted before it had a chance to be used
+const LFU_INIT_VAL: u8 = 5;
+/// the higher, the more accesses it takes to grow the lfu counter
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// minutes after which the lfu counter loses one
+const LFU_DECAY_TIME: u64 = 1;
+
+impl ValueEntry {
+    pub fn new(value: StoredValue) -> Self {
+        Self::with_expiry(value, None)
+    }
+
+    pub fn with_expiry(value: StoredValue, expires_at: Option<u64>) -> Self {
+        let now = helper::now_unix_ms();
+        Self {
+            value,
+            expires_at,
+            last_access: now,
+            lfu_counter: LFU_INIT_VAL,
+            lfu_decayed_at: now / 60_000,
+        }
+    }
+
+    /// records an access for the eviction policies
+    pub fn touch(&mut self) {
+        let now = helper::now_unix_ms();
+        self.last_access = now;
+
+        // the same logarithmic counter as redis: the higher it is, the less likely
+        // an access increments it
+        let counter = self.lfu_count(now);
+        let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+        let random = helper::random_u64() as f64 / u64::MAX as f64;
+        self.lfu_counter = if random < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+            counter.saturating_add(1)
+        } else {
+            counter
+        };
+        self.lfu_decayed_at = now / 60_000;
+    }
+
+    /// lfu counter once decayed for the time since it was last touched
+    pub fn lfu_count(&self, now: u64) -> u8 {
+        let periods = (now / 60_000).saturating_sub(self.lfu_decayed_at) / LFU_DECAY_TIME;
+        self.lfu_counter
+            .saturating_sub(periods.min(u8::MAX as u64) as u8)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expire_time| helper::now_unix_ms() >= expire_time)
+    }
+}
+
+/// keys in a vec, so a random one is picked in O(1)
+#[derive(Debug, Default)]
+struct KeyIndex {
+    keys: Vec<Arc<[u8]>>,
+    positions: HashMap<Arc<[u8]>, usize>,
+}
+
+impl KeyIndex {
+    fn insert(&mut self, key: &Arc<[u8]>) {
+        if !self.positions.contains_key(key) {
+            self.positions.insert(Arc::clone(key), self.keys.len());
+            self.keys.push(Arc::clone(key));
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        let Some(position) = self.positions.remove(key) else {
+            return;
+        };
+        // the last key takes its place
+        self.keys.swap_remove(position);
+        if let Some(moved) = self.keys.get(position) {
+            *self.positions.get_mut(moved).unwrap() = position;
+        }
+    }
+
+    /// up to count distinct keys picked at random, all of them when there
+    /// aren't more than count
+    fn sample(&self, count: usize) -> Vec<Vec<u8>> {
+        if self.keys.len() <= count {
+            return self.keys.iter().map(|key| key.to_vec()).collect();
+        }
+
+        let mut positions: Vec<usize> = (0..count)
+            .map(|_| helper::random_u64() as usize % self.keys.len())
+            .collect();
+        positions.sort_unstable();
+        positions.dedup();
+        positions
+            .into_iter()
+            .map(|i| self.keys[i].to_vec())
+            .collect()
+    }
+}
+
+/// the keys of a db, reads go straight to the map
+/// writes go through here so the key indexes stay in sync, an expiry is changed
+/// with set_expiry rather than through get_mut
+/// a key is allocated once, the map and the indexes share it
+#[derive(Debug, Default)]
+pub struct Keyspace {
+    entries: HashMap<Arc<[u8]>, ValueEntry>,
+    keys: KeyIndex,     // every key, what eviction samples
+    volatile: KeyIndex, // keys with a ttl, what active expiry and volatile eviction sample
+    scan_order: ScanIndex<Arc<[u8]>>,
+}
+
+impl std::ops::Deref for Keyspace {
+    type Target = HashMap<Arc<[u8]>, ValueEntry>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl Keyspace {
+    pub fn get(&self, key: &[u8]) -> Option<&ValueEntry> {
+        self.entries.get(key)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, entry: ValueEntry) -> Option<ValueEntry> {
+        let key = match self.entries.get_key_value(key.as_slice()) {
+            Some((key, _)) => Arc::clone(key),
+            None => {
+                let key: Arc<[u8]> = key.into();
+                self.keys.insert(&key);
+                self.scan_order.insert(Arc::clone(&key));
+                key
+            }
+        };
+        if entry.expires_at.is_some() {
+            self.volatile.insert(&key);
+        } else {
+            self.volatile.remove(&key);
+        }
+        self.entries.insert(key, entry)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<ValueEntry> {
+        let entry = self.entries.remove(key)?;
+        self.keys.remove(key);
+        self.scan_order.remove(key);
+        if entry.expires_at.is_some() {
+            self.volatile.remove(key);
+        }
+        Some(entry)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut ValueEntry> {
+        self.entries.get_mut(key)
+    }
+
+    /// sets or clears the ttl of key, returns the previous one
+    pub fn set_expiry(&mut self, key: &[u8], expires_at: Option<u64>) -> Option<u64> {
+        let key = Arc::clone(self.entries.get_key_value(key)?.0);
+        match expires_at {
+            Some(_) => self.volatile.insert(&key),
+            None => self.volatile.remove(&key),
+        }
+        let entry = self.entries.get_mut(&key).unwrap();
+        std::mem::replace(&mut entry.expires_at, expires_at)
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    /// keys in the order SCAN returns them
+    pub fn scan_order(&self) -> &ScanIndex<Arc<[u8]>> {
+        &self.scan_order
+    }
+
+    /// up to count distinct keys picked at random
+    pub fn sample_keys(&self, count: usize) -> Vec<Vec<u8>> {
+        self.keys.sample(count)
+    }
+
+    /// up to count distinct keys with a ttl picked at random
+    pub fn sample_volatile(&self, count: usize) -> Vec<Vec<u8>> {
+        self.volatile.sample(count)
+    }
+}
+
+pub type SharedStore = Arc<(Mutex<Keyspace>, Condvar)>;
+
+/// live keys copied out of the dbs to be written somewhere, the copy doesn't
+/// count against maxmemory while it's alive
 #[derive(Debug)]
-pub struct List {
-    pub name: String,
-    pub vec: Vec<String>,
+pub struct Snapshot {
+    pub keys: Vec<rdb::RdbKey>,
+    _memory: memory::NotCounted,
 }
 
-impl List {
-    pub fn new(name: &str) -> Self {
+impl Snapshot {
+    pub fn new(keys: Vec<rdb::RdbKey>, bytes: usize) -> Self {
         Self {
-            name: name.to_string(),
-            vec: Vec::new(),
+            keys,
+            _memory: memory::NotCounted::new(bytes),
         }
     }
 }
 
-pub type SharedStore = Arc<(Mutex<HashMap<String, ValueEntry>>, Condvar)>;
-pub type SharedMainList = Arc<(Mutex<Vec<List>>, Condvar)>;
+/// the logical databases, each connection works on the one it selected
+/// code that locks more than one does it in index order
+pub type SharedDatabases = Arc<Vec<SharedStore>>;
+
+pub type KeyspaceGuard<'a> = std::sync::MutexGuard<'a, Keyspace>;
+
+/// arguments of SCAN, HSCAN, SSCAN and ZSCAN after the key
+#[derive(Debug)]
+pub struct ScanArgs {
+    pub cursor: u64,
+    pub pattern: Option<Vec<u8>>, // MATCH
+    pub count: usize,
+    pub type_name: Option<String>, // TYPE, only for SCAN
+    pub novalues: bool,            // NOVALUES, only for HSCAN
+}
+
+/// options of a SET after the value
+#[derive(Debug, Default)]
+pub struct SetArgs {
+    pub nx: bool,
+    pub xx: bool,
+    pub get: bool, // reply with the old value
+    pub keep_ttl: bool,
+    pub expires_at: Option<u64>, // EX, PX, EXAT or PXAT as absolute unix ms
+}
+
+/// what GETEX does to the ttl of the key
+#[derive(Debug, PartialEq)]
+pub enum GetExTtl {
+    Keep,
+    Persist,
+    ExpireAt(u64), // absolute unix ms
+}
+
+/// state of rdb snapshotting shared between connections
+#[derive(Debug)]
+pub struct SaveState {
+    pub dirty: u64,     // changes since the last successful save
+    pub last_save: u64, // unix time in seconds
+    pub last_bgsave_try: u64,
+    pub last_bgsave_ok: bool,
+    pub bgsave_in_progress: bool,
+    pub save_points: Vec<(u64, u64)>, // (seconds, changes)
+}
+
+impl SaveState {
+    pub fn new(save_points: Vec<(u64, u64)>) -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        Self {
+            dirty: 0,
+            last_save: now,
+            last_bgsave_try: 0,
+            last_bgsave_ok: true,
+            bgsave_in_progress: false,
+            save_points,
+        }
+    }
+}
+
+pub type SharedSaveState = Arc<Mutex<SaveState>>;
+
+/// replication stream this server produces as a master, or follows as a replica
+/// the backlog keeps its most recent bytes so a reconnecting replica can continue
+#[derive(Debug)]
+pub struct ReplicationState {
+    pub replid: String,
+    pub master_repl_offset: usize,
+    pub backlog: VecDeque<u8>,
+    pub backlog_size: usize,
+
+    // id of the stream this one continues after a promotion, valid up to the offset
+    pub replid2: Option<(String, usize)>,
+
+    pub listening_port: String,
+    pub stream_db: usize, // db the master's stream last selected, its writes go there
+
+    pub master_addr: Option<String>, // None when this server is a master
+    pub master_link: Option<TcpStream>, // current connection with the master, if any
+    pub link_id: u64,                // bumped on every role change, stale replication threads stop
+}
+
+impl ReplicationState {
+    pub fn new(replid: String, backlog_size: usize, listening_port: String) -> Self {
+        Self {
+            replid,
+            master_repl_offset: 0,
+            backlog: VecDeque::new(),
+            backlog_size,
+            replid2: None,
+            stream_db: 0,
+            listening_port,
+            master_addr: None,
+            master_link: None,
+            link_id: 0,
+        }
+    }
+
+    pub fn role(&self) -> &'static str {
+        if self.master_addr.is_some() {
+            "role:slave"
+        } else {
+            "role:master"
+        }
+    }
+
+    /// adds bytes sent to (or received from) the replication stream
+    pub fn feed(&mut self, payload: &[u8]) {
+        self.master_repl_offset += payload.len();
+
+        let payload = &payload[payload.len().saturating_sub(self.backlog_size)..];
+        let overflow = (self.backlog.len() + payload.len()).saturating_sub(self.backlog_size);
+        self.backlog.drain(..overflow);
+        self.backlog.extend(payload);
+    }
+
+    /// everything after the given offset, None if part of it already left the backlog
+    pub fn backlog_since(&self, offset: usize) -> Option<Vec<u8>> {
+        let backlog_start = self.master_repl_offset - self.backlog.len();
+        if offset < backlog_start || offset > self.master_repl_offset {
+            return None;
+        }
+
+        Some(
+            self.backlog
+                .range(offset - backlog_start..)
+                .copied()
+                .collect(),
+        )
+    }
+}
+
+pub type SharedReplicationState = Arc<Mutex<ReplicationState>>;
+
+/// a replica connected to this master, registered when it sends PSYNC
+#[derive(Debug)]
+pub struct Replica {
+    pub stream: TcpStream,
+    pub addr: SocketAddr, // of the replication connection, identifies its ACKs
+    pub listening_port: Option<u16>, // from REPLCONF listening-port
+    pub ack_offset: usize, // last offset the replica acknowledged
+    pub last_ack: std::time::Instant,
+}
+
+pub type SharedReplicas = Arc<Mutex<Vec<Replica>>>;
+
+#[derive(Debug, Default)]
+pub struct CommandStats {
+    pub calls: u64,
+    pub usec: u64,
+    pub rejected_calls: u64, // refused before running, like a write on a replica
+    pub failed_calls: u64,   // ran and replied with an error
+}
+
+/// counters reported by INFO
+#[derive(Debug)]
+pub struct ServerStats {
+    pub started_at: std::time::Instant,
+    pub run_id: String,
+    pub connected_clients: usize,
+    pub total_connections_received: u64,
+    pub total_commands_processed: u64,
+    pub total_error_replies: u64,
+    pub expired_keys: u64,
+    pub evicted_keys: u64,
+    pub command_stats: HashMap<String, CommandStats>,
+    pub error_stats: HashMap<String, u64>, // error prefix (ERR, WRONGTYPE, ...) -> count
+}
+
+impl ServerStats {
+    pub fn new(run_id: String) -> Self {
+        Self {
+            started_at: std::time::Instant::now(),
+            run_id,
+            connected_clients: 0,
+            total_connections_received: 0,
+            total_commands_processed: 0,
+            total_error_replies: 0,
+            expired_keys: 0,
+            evicted_keys: 0,
+            command_stats: HashMap::new(),
+            error_stats: HashMap::new(),
+        }
+    }
+
+    /// counts an executed command, name is None for commands the server doesn't know
+    pub fn record_call(&mut self, name: Option<String>, usec: u64, resp: &[u8]) {
+        let failed = self.record_error(resp);
+
+        if let Some(name) = name {
+            let stats = self.command_stats.entry(name).or_default();
+            stats.calls += 1;
+            stats.usec += usec;
+            if failed {
+                stats.failed_calls += 1;
+            }
+        }
+    }
+
+    /// counts a command refused before it ran
+    pub fn record_rejected(&mut self, name: Option<String>, resp: &[u8]) {
+        self.record_error(resp);
+
+        if let Some(name) = name {
+            self.command_stats.entry(name).or_default().rejected_calls += 1;
+        }
+    }
+
+    fn record_error(&mut self, resp: &[u8]) -> bool {
+        let Some(error) = resp.strip_prefix(b"-") else {
+            return false;
+        };
+
+        let prefix = error
+            .split(|b| *b == b' ' || *b == b'\r')
+            .next()
+            .unwrap_or_default();
+        *self
+            .error_stats
+            .entry(String::from_utf8_lossy(prefix).to_string())
+            .or_default() += 1;
+        self.total_error_replies += 1;
+        true
+    }
+}
+
+pub type SharedServerStats = Arc<Mutex<ServerStats>>;
+
+/// state shared by every connection, each one works on a clone of it
+#[derive(Clone)]
+pub struct ServerContext {
+    pub dbs: SharedDatabases,
+    pub dir: Arc<Mutex<Option<String>>>,
+    pub dbfilename: Arc<Mutex<Option<String>>>,
+    pub save_state: SharedSaveState,
+    pub aof: aof::SharedAof,
+    pub replicas: SharedReplicas,
+    pub repl_state: SharedReplicationState,
+    pub subs_htable: Arc<Mutex<HashMap<Vec<u8>, Vec<TcpStream>>>>,
+    pub users: SharedUsers,
+    pub stats: SharedServerStats,
+    pub maxmemory: memory::SharedMaxMemory,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &[u8]) -> StoredValue {
+        StoredValue::String(value.to_vec())
+    }
+
+    fn sorted_volatile(keyspace: &Keyspace) -> Vec<Vec<u8>> {
+        let mut keys = keyspace.sample_volatile(usize::MAX);
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn keyspace_shares_one_copy_of_each_key() {
+        let mut keyspace = Keyspace::default();
+        keyspace.insert(b"k".to_vec(), ValueEntry::new(string(b"1")));
+        keyspace.set_expiry(b"k", Some(5));
+        keyspace.insert(
+            b"k".to_vec(),
+            ValueEntry::with_expiry(string(b"2"), Some(5)),
+        );
+
+        // the map, the vec and positions of both key indexes and the scan order
+        let (key, _) = keyspace.get_key_value(b"k".as_slice()).unwrap();
+        assert_eq!(Arc::strong_count(key), 6);
+
+        keyspace.set_expiry(b"k", None);
+        let (key, _) = keyspace.get_key_value(b"k".as_slice()).unwrap();
+        assert_eq!(Arc::strong_count(key), 4);
+    }
+
+    #[test]
+    fn keyspace_indexes_the_keys_with_a_ttl() {
+        let mut keyspace = Keyspace::default();
+        keyspace.insert(
+            b"a".to_vec(),
+            ValueEntry::with_expiry(string(b"1"), Some(1)),
+        );
+        keyspace.insert(b"b".to_vec(), ValueEntry::new(string(b"2")));
+        keyspace.insert(
+            b"c".to_vec(),
+            ValueEntry::with_expiry(string(b"3"), Some(3)),
+        );
+        assert_eq!(sorted_volatile(&keyspace), [b"a".to_vec(), b"c".to_vec()]);
+
+        keyspace.set_expiry(b"b", Some(2));
+        assert_eq!(keyspace.set_expiry(b"a", None), Some(1));
+        assert_eq!(sorted_volatile(&keyspace), [b"b".to_vec(), b"c".to_vec()]);
+
+        // overwritten without a ttl
+        keyspace.insert(b"c".to_vec(), ValueEntry::new(string(b"4")));
+        assert_eq!(sorted_volatile(&keyspace), [b"b".to_vec()]);
+
+        keyspace.remove(b"b");
+        assert!(sorted_volatile(&keyspace).is_empty());
+        assert_eq!(keyspace.len(), 2);
+
+        // a missing key gets no ttl
+        assert_eq!(keyspace.set_expiry(b"missing", Some(5)), None);
+        assert!(sorted_volatile(&keyspace).is_empty());
+    }
+
+    #[test]
+    fn keyspace_samples_distinct_live_keys() {
+        let mut keyspace = Keyspace::default();
+        for n in 0..100u64 {
+            let key = n.to_string().into_bytes();
+            keyspace.insert(key, ValueEntry::with_expiry(string(b"v"), Some(n)));
+        }
+        for n in (0..100u64).step_by(2) {
+            keyspace.remove(n.to_string().as_bytes());
+        }
+
+        let mut sample = keyspace.sample_volatile(20);
+        let len = sample.len();
+        assert!((1..=20).contains(&len));
+        sample.sort();
+        sample.dedup();
+        assert_eq!(sample.len(), len);
+        assert!(sample.iter().all(|key| keyspace.contains_key(key)));
+
+        let mut all = keyspace.sample_keys(50);
+        all.sort();
+        let mut expected: Vec<_> = keyspace.keys().map(|key| key.to_vec()).collect();
+        expected.sort();
+        assert_eq!(all, expected);
+    }
+
+    /// every element of a full SCAN style iteration, page by page
+    fn scan_all(index: &ScanIndex, count: usize) -> Vec<Vec<u8>> {
+        let mut elements = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (next, page) = index.page(cursor, count);
+            assert!(next == 0 || page.len() >= count);
+            elements.extend(page.into_iter().cloned());
+            if next == 0 {
+                return elements;
+            }
+            cursor = next;
+        }
+    }
+
+    #[test]
+    fn scan_index_pages_through_every_element_once() {
+        let mut index = ScanIndex::default();
+        for n in 0..1000 {
+            index.insert(n.to_string().into_bytes());
+        }
+
+        for count in [1, 7, 10, 1000, 5000] {
+            let mut elements = scan_all(&index, count);
+            assert_eq!(elements.len(), 1000);
+            elements.sort();
+            elements.dedup();
+            assert_eq!(elements.len(), 1000);
+        }
+        assert_eq!(index.page(0, 10).1.len(), 10);
+    }
+
+    #[test]
+    fn scan_index_survives_changes_between_pages() {
+        let mut index = ScanIndex::default();
+        for n in 0..100 {
+            index.insert(format!("old{n}").into_bytes());
+        }
+
+        // half the elements go away and new ones come while the iteration runs
+        let mut seen = Vec::new();
+        let mut cursor = 0;
+        let mut round = 0;
+        loop {
+            let (next, page) = index.page(cursor, 10);
+            seen.extend(page.into_iter().cloned());
+            for n in (round * 10..round * 10 + 10).filter(|n| n % 2 == 1) {
+                index.remove(format!("old{n}").as_bytes());
+                index.insert(format!("new{n}").into_bytes());
+            }
+            round += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+
+        // the elements there from start to end were all returned, once
+        for n in (0..100).filter(|n| n % 2 == 0) {
+            let element = format!("old{n}").into_bytes();
+            assert_eq!(seen.iter().filter(|e| **e == element).count(), 1);
+        }
+        let mut unique = seen.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), seen.len());
+    }
+
+    #[test]
+    fn zset_keeps_its_scan_order_in_sync() {
+        let mut zset = ZSet::new();
+        assert!(zset.insert(b"a".to_vec(), 1.0));
+        assert!(!zset.insert(b"a".to_vec(), 2.0));
+        assert!(zset.insert(b"b".to_vec(), 3.0));
+        assert!(zset.remove(b"b"));
+        assert!(!zset.remove(b"b"));
+
+        assert_eq!(scan_all(&zset.scan_order, 10), [b"a".to_vec()]);
+        assert_eq!(zset.ordered.len(), 1);
+    }
+}